@@ -1,6 +1,7 @@
 use anyhow::Result;
 use crabby_merge::jenkins;
 use std::env;
+use url::Url;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
@@ -8,9 +9,15 @@ async fn main() -> Result<()> {
     let username = env::var("JENKINS_USERNAME").expect("JENKINS_USERNAME not set");
     let password = env::var("JENKINS_PASSWORD").expect("JENKINS_PASSWORD not set");
     let auth = jenkins::Auth::new(username, password);
-    let client = reqwest::Client::new();
+    // Only send the credentials to the server the job is on
+    let mut servers = jenkins::Servers::default();
+    servers.add(
+        &Url::parse(&url)?.origin().ascii_serialization(),
+        auth.clone(),
+    )?;
+    let client = jenkins::Client::new();
     let job = jenkins::Job::new(&url, auth)?;
-    job.rebuild(&client).await?;
+    job.rebuild(&client, &servers, false).await?;
     println!("Rebuilt ✅");
     Ok(())
 }
@@ -1,40 +1,374 @@
-#![cfg(feature = "jenkins")]
+#![cfg(feature = "ci")]
 
+use crate::bitbucket::BuildStatus;
 use crate::History;
+
+use anyhow::{anyhow, Result};
 use log::*;
-use time::Duration;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use time::{Duration, OffsetDateTime};
 
-fn backoff_time(n_retries: u32) -> Duration {
-    if n_retries == 0 {
-        Duration::ZERO
-    } else {
-        Duration::minutes(5)
+const DEFAULT_DELAY_SECS: u64 = 300;
+/// Longest delay between retries, whatever the schedule, so that the time of the next retry can
+/// always be represented
+const MAX_DELAY: Duration = Duration::days(365);
+
+/// How the delay between retries of a build grows
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum BackoffStrategy {
+    /// The same delay before every retry
+    Fixed {
+        #[serde(default = "default_delay_secs")]
+        delay_secs: u64,
+    },
+    /// A delay that grows by `step_secs` with every retry
+    Linear {
+        #[serde(default = "default_delay_secs")]
+        delay_secs: u64,
+        #[serde(default = "default_delay_secs")]
+        step_secs: u64,
+    },
+    /// A delay that is multiplied by `factor` with every retry, up to `max_delay_secs`
+    Exponential {
+        #[serde(default = "default_delay_secs")]
+        delay_secs: u64,
+        #[serde(default = "default_factor")]
+        factor: f64,
+        max_delay_secs: Option<u64>,
+    },
+}
+
+fn default_delay_secs() -> u64 {
+    DEFAULT_DELAY_SECS
+}
+
+fn default_factor() -> f64 {
+    2.0
+}
+
+/// How long to wait between retries of a build. The first retry is never delayed.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct BackoffSchedule {
+    #[serde(flatten)]
+    pub strategy: BackoffStrategy,
+    /// Fraction of each delay to randomly add or remove, so that builds that failed together aren't
+    /// all retried together
+    #[serde(default)]
+    pub jitter: f64,
+}
+
+impl Default for BackoffSchedule {
+    fn default() -> Self {
+        Self {
+            strategy: BackoffStrategy::Fixed {
+                delay_secs: DEFAULT_DELAY_SECS,
+            },
+            jitter: 0.0,
+        }
     }
 }
 
-pub fn should_retry_now(hash: &str, max_retries: u32) -> bool {
-    match History::load(hash) {
-        Err(_) => {
-            History::delete(hash).ok();
-            false
+impl BackoffSchedule {
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(anyhow!("Backoff jitter must be between 0 and 1"));
         }
-        Ok(None) => {
-            if let Err(e) = History::save(hash, 0) {
-                error!("Error saving Jenkins history file for {}: {}", hash, e);
+        if let BackoffStrategy::Exponential { factor, .. } = self.strategy {
+            if !factor.is_finite() || factor < 1.0 {
+                return Err(anyhow!("Backoff factor must be at least 1"));
             }
-            max_retries > 0 && backoff_time(0) == Duration::ZERO
-        }
-        Ok(Some(history)) => {
-            if history.n_retries() < max_retries
-                && history.age() >= backoff_time(history.n_retries())
-            {
-                if let Err(e) = History::save(hash, history.n_retries() + 1) {
-                    error!("Error saving Jenkins history file for {}: {}", hash, e);
-                }
-                true
-            } else {
-                false
+        }
+        Ok(())
+    }
+
+    /// Returns the delay before retrying a build that was already retried `n_retries` times, up to
+    /// `MAX_DELAY`. `seed` picks the jitter, so that the delay stays the same from one run to the
+    /// next.
+    fn delay(&self, n_retries: u32, seed: &str) -> Duration {
+        if n_retries == 0 {
+            return Duration::ZERO;
+        }
+        let step = n_retries - 1;
+        let secs = match self.strategy {
+            BackoffStrategy::Fixed { delay_secs } => delay_secs as f64,
+            BackoffStrategy::Linear {
+                delay_secs,
+                step_secs,
+            } => delay_secs as f64 + step_secs as f64 * f64::from(step),
+            BackoffStrategy::Exponential {
+                delay_secs,
+                factor,
+                max_delay_secs,
+            } => {
+                let secs = delay_secs as f64 * factor.powi(step.try_into().unwrap_or(i32::MAX));
+                max_delay_secs.map_or(secs, |max| secs.min(max as f64))
             }
+        };
+        // Clamped before adding jitter too, so that full jitter can't turn an infinite delay into
+        // NaN
+        let secs = secs.min(MAX_DELAY.as_seconds_f64());
+        let mut hasher = DefaultHasher::new();
+        (seed, n_retries).hash(&mut hasher);
+        // Between -1 and 1
+        let offset = hasher.finish() as f64 / u64::MAX as f64 * 2.0 - 1.0;
+        Duration::saturating_seconds_f64(secs * (1.0 + self.jitter * offset)).min(MAX_DELAY)
+    }
+}
+
+/// Failed builds to retry whose names match `regex`, and how long to wait between their retries
+#[derive(Debug, Clone)]
+pub struct RetryRule {
+    pub regex: Regex,
+    pub backoff: BackoffSchedule,
+}
+
+/// When a failed build can be retried next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NextRetry {
+    Now,
+    /// The build has to wait for its backoff to end
+    At(OffsetDateTime),
+    /// The build was retried as many times as allowed
+    Never,
+}
+
+/// Returns when a failed build of a commit can be retried next. Each build of a commit has its own
+/// retry limit and backoff.
+pub fn next_retry(
+    hash: &str,
+    build: &BuildStatus,
+    schedule: &BackoffSchedule,
+    max_retries: u32,
+) -> Result<NextRetry> {
+    let history = History::load(hash)?.and_then(|history| history.build(build));
+    let n_retries = history.as_ref().map_or(0, |history| history.n_retries());
+    if n_retries >= max_retries {
+        return Ok(NextRetry::Never);
+    }
+    let Some(history) = history else {
+        return Ok(NextRetry::Now);
+    };
+    let seed = format!("{}/{}", hash, build.key);
+    let wait = schedule
+        .delay(n_retries, &seed)
+        .saturating_sub(history.age());
+    if wait <= Duration::ZERO {
+        return Ok(NextRetry::Now);
+    }
+    OffsetDateTime::now_utc()
+        .checked_add(wait)
+        .map(NextRetry::At)
+        .ok_or_else(|| anyhow!("Next retry of {} is out of range", build.name))
+}
+
+/// Returns when a failed build of a commit can be retried next, counting a retry if it can be
+/// retried now
+pub fn should_retry_now(
+    hash: &str,
+    build: &BuildStatus,
+    schedule: &BackoffSchedule,
+    max_retries: u32,
+) -> NextRetry {
+    match next_retry(hash, build, schedule, max_retries) {
+        Err(_) => {
+            History::delete(hash).ok();
+            NextRetry::Never
         }
+        Ok(NextRetry::Now) => {
+            count_retry(hash, build);
+            NextRetry::Now
+        }
+        Ok(next) => next,
+    }
+}
+
+/// Counts a retry of a failed build of a commit
+pub fn count_retry(hash: &str, build: &BuildStatus) {
+    let n_retries = History::load(hash)
+        .ok()
+        .flatten()
+        .and_then(|history| history.build(build))
+        .map_or(0, |history| history.n_retries());
+    if let Err(e) = History::save(hash, build, n_retries + 1) {
+        error!("Error saving retry history file for {}: {}", hash, e);
+    }
+}
+
+/// Formats a delay for logging, e.g. "1h 5m" or "12m"
+pub fn format_delay(delay: Duration) -> String {
+    let minutes = delay.whole_minutes();
+    if minutes >= 60 {
+        format!("{}h {}m", minutes / 60, minutes % 60)
+    } else if minutes > 0 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}s", delay.whole_seconds().max(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitbucket::BuildState;
+
+    fn build(key: &str) -> BuildStatus {
+        BuildStatus {
+            state: BuildState::Failed,
+            key: key.to_owned(),
+            name: key.to_owned(),
+            url: format!("https://jenkins/job/{}/1/", key),
+        }
+    }
+
+    fn delays(schedule: BackoffSchedule) -> Vec<i64> {
+        (0..5)
+            .map(|n_retries| schedule.delay(n_retries, "seed").whole_seconds())
+            .collect()
+    }
+
+    #[test]
+    fn builds_have_their_own_retries() {
+        let (unit, integration) = (build("unit"), build("integration"));
+        let schedule = BackoffSchedule::default();
+        // The first retry of each build isn't delayed, and isn't held back by the other build's
+        assert_eq!(
+            should_retry_now("giraffes", &unit, &schedule, 3),
+            NextRetry::Now
+        );
+        assert!(matches!(
+            should_retry_now("giraffes", &unit, &schedule, 3),
+            NextRetry::At(_)
+        ));
+        assert_eq!(
+            should_retry_now("giraffes", &integration, &schedule, 3),
+            NextRetry::Now
+        );
+        assert_eq!(
+            should_retry_now("giraffes", &build("lint"), &schedule, 0),
+            NextRetry::Never
+        );
+        History::delete("giraffes").unwrap();
+    }
+
+    #[test]
+    fn retries_are_limited() {
+        let unit = build("unit");
+        let schedule = BackoffSchedule {
+            strategy: BackoffStrategy::Fixed { delay_secs: 0 },
+            jitter: 0.0,
+        };
+        assert_eq!(
+            should_retry_now("zebras", &unit, &schedule, 2),
+            NextRetry::Now
+        );
+        assert_eq!(
+            should_retry_now("zebras", &unit, &schedule, 2),
+            NextRetry::Now
+        );
+        assert_eq!(
+            should_retry_now("zebras", &unit, &schedule, 2),
+            NextRetry::Never
+        );
+        History::delete("zebras").unwrap();
+    }
+
+    #[test]
+    fn next_retry_is_after_the_delay() {
+        let unit = build("unit");
+        let schedule = BackoffSchedule {
+            strategy: BackoffStrategy::Fixed { delay_secs: 720 },
+            jitter: 0.0,
+        };
+        History::save("okapis", &unit, 1).unwrap();
+        let NextRetry::At(at) = next_retry("okapis", &unit, &schedule, 3).unwrap() else {
+            panic!("Retry isn't delayed");
+        };
+        let wait = at - OffsetDateTime::now_utc();
+        assert!(wait > Duration::minutes(11) && wait <= Duration::minutes(12));
+        History::delete("okapis").unwrap();
+    }
+
+    #[test]
+    fn schedules() {
+        let schedule = |strategy| BackoffSchedule {
+            strategy,
+            jitter: 0.0,
+        };
+        assert_eq!(delays(BackoffSchedule::default()), [0, 300, 300, 300, 300]);
+        assert_eq!(
+            delays(schedule(BackoffStrategy::Linear {
+                delay_secs: 60,
+                step_secs: 30,
+            })),
+            [0, 60, 90, 120, 150]
+        );
+        assert_eq!(
+            delays(schedule(BackoffStrategy::Exponential {
+                delay_secs: 60,
+                factor: 2.0,
+                max_delay_secs: Some(300),
+            })),
+            [0, 60, 120, 240, 300]
+        );
+    }
+
+    #[test]
+    fn long_delays_are_clamped() {
+        let unit = build("unit");
+        for strategy in [
+            BackoffStrategy::Fixed {
+                delay_secs: u64::MAX,
+            },
+            BackoffStrategy::Exponential {
+                delay_secs: 60,
+                factor: 10.0,
+                max_delay_secs: None,
+            },
+        ] {
+            let schedule = BackoffSchedule {
+                strategy,
+                jitter: 1.0,
+            };
+            assert!((1..1000).all(|n_retries| schedule.delay(n_retries, "seed") <= MAX_DELAY));
+            History::save("tapirs", &unit, 999).unwrap();
+            let NextRetry::At(at) = next_retry("tapirs", &unit, &schedule, u32::MAX).unwrap()
+            else {
+                panic!("Retry isn't delayed");
+            };
+            assert!(at <= OffsetDateTime::now_utc() + MAX_DELAY);
+        }
+        History::delete("tapirs").unwrap();
+    }
+
+    #[test]
+    fn jitter() {
+        let schedule = BackoffSchedule {
+            strategy: BackoffStrategy::Fixed { delay_secs: 1000 },
+            jitter: 0.5,
+        };
+        let delays: Vec<i64> = (0..20)
+            .map(|seed| schedule.delay(1, &seed.to_string()).whole_seconds())
+            .collect();
+        assert!(delays.iter().all(|delay| (500..=1500).contains(delay)));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+        // The same build waits as long in every run
+        assert_eq!(schedule.delay(1, "seed"), schedule.delay(1, "seed"));
+        assert!(BackoffSchedule {
+            jitter: 1.5,
+            ..schedule
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn formats_delays() {
+        assert_eq!(format_delay(Duration::seconds(30)), "30s");
+        assert_eq!(format_delay(Duration::seconds(12 * 60 + 5)), "12m");
+        assert_eq!(format_delay(Duration::minutes(65)), "1h 5m");
     }
 }
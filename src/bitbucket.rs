@@ -1,12 +1,23 @@
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::rate_limit::RateLimiter;
+
 use anyhow::{anyhow, Context, Result};
+use cfg_if::cfg_if;
+use futures::future;
+use futures::stream::{self, Stream, TryStreamExt};
+use log::*;
 use reqwest::{
     header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE},
-    Response,
+    RequestBuilder, Response, StatusCode,
 };
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use std::mem;
 use std::time::Duration;
+#[cfg(feature = "metrics")]
+use std::time::Instant;
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 #[serde(rename_all = "UPPERCASE")]
@@ -24,11 +35,78 @@ pub struct BuildStatus {
     pub url: String,
 }
 
-#[derive(Debug, Deserialize)]
+/// Review status of a pull request participant
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ParticipantStatus {
+    Approved,
+    NeedsWork,
+    Unapproved,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct User {
+    pub name: String,
+}
+
+/// A reviewer or participant of a pull request
+#[derive(Debug, Deserialize, Clone)]
+pub struct Participant {
+    pub user: User,
+    pub status: ParticipantStatus,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum CommentSeverity {
+    #[default]
+    Normal,
+    Blocker,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum CommentState {
+    #[default]
+    Open,
+    Pending,
+    Resolved,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PullRequestState {
+    #[default]
+    Open,
+    Merged,
+    Declined,
+}
+
+/// A pull request comment
+///
+/// Blocker comments, also known as tasks, have a `severity` of `Blocker`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Comment {
+    pub id: u64,
+    pub author: User,
+    pub text: String,
+    #[serde(default)]
+    pub severity: CommentSeverity,
+    #[serde(default)]
+    pub state: CommentState,
+    #[serde(rename = "comments", default)]
+    pub replies: Vec<Comment>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct PullRequest {
     id: u32,
+    #[serde(default)]
+    pub title: String,
     pub description: Option<String>,
+    #[serde(default)]
+    pub state: PullRequestState,
     from_ref: serde_json::Value,
     to_ref: serde_json::Value,
     /// `links["self"][0]["href"]` contains the PR URL
@@ -36,6 +114,15 @@ pub struct PullRequest {
     version: i32,
     /// `author["user"]["name"]` contains the author's username
     author: serde_json::Value,
+    #[serde(default)]
+    pub reviewers: Vec<Participant>,
+    #[serde(default)]
+    pub participants: Vec<Participant>,
+    #[serde(default)]
+    pub draft: bool,
+    /// `properties["openTaskCount"]` contains the number of unresolved tasks
+    #[serde(default)]
+    properties: serde_json::Value,
 }
 
 impl PullRequest {
@@ -52,6 +139,99 @@ impl PullRequest {
             .and_then(|u| u.get("name").and_then(serde_json::Value::as_str))
     }
 
+    /// Returns the email address of the author, if Bitbucket provides it
+    pub fn author_email(&self) -> Option<&str> {
+        self.author
+            .get("user")
+            .and_then(|u| u.get("emailAddress").and_then(serde_json::Value::as_str))
+    }
+
+    /// Returns the display name of the target branch, e.g. `main`
+    pub fn target_branch(&self) -> Option<&str> {
+        self.to_ref
+            .get("displayId")
+            .and_then(serde_json::Value::as_str)
+    }
+
+    /// Returns the usernames of all reviewers and participants with the given status
+    pub fn users_with_status(&self, status: ParticipantStatus) -> BTreeSet<&str> {
+        self.reviewers
+            .iter()
+            .chain(self.participants.iter())
+            .filter(|participant| participant.status == status)
+            .map(|participant| participant.user.name.as_str())
+            .collect()
+    }
+
+    pub fn open_task_count(&self) -> u64 {
+        self.properties
+            .get("openTaskCount")
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or(0)
+    }
+
+    /// Returns the key of the project containing the target repository
+    pub fn project_key(&self) -> Option<&str> {
+        self.to_ref["repository"]["project"]["key"].as_str()
+    }
+
+    /// Returns the slug of the target repository
+    pub fn repo_slug(&self) -> Option<&str> {
+        self.to_ref["repository"]["slug"].as_str()
+    }
+
+    /// Returns the project key and slug of the target repository, if Bitbucket provided both
+    fn repo(&self) -> Option<(&str, &str)> {
+        Some((self.project_key()?, self.repo_slug()?))
+    }
+
+    /// Returns the REST API path of the target repository
+    fn repo_api_path(&self) -> Result<String> {
+        let (project_key, repo_slug) = self
+            .repo()
+            .ok_or_else(|| anyhow!("Pull request {} has no target repository", self.id))?;
+        Ok(format!(
+            "/rest/api/1.0/projects/{}/repos/{}",
+            project_key, repo_slug
+        ))
+    }
+
+    /// Returns the REST API path of the pull request
+    fn api_path(&self) -> Result<String> {
+        Ok(format!(
+            "{}/pull-requests/{}",
+            self.repo_api_path()?,
+            self.id
+        ))
+    }
+
+    /// Returns the id of the pull request, which is unique within its target repository
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns a key that identifies the pull request on the server, e.g. `PROJ-repo-123`, if
+    /// Bitbucket described its target repository
+    pub fn key(&self) -> Option<String> {
+        let (project_key, repo_slug) = self.repo()?;
+        Some(format!("{}-{}-{}", project_key, repo_slug, self.id))
+    }
+
+    /// Returns true if both pull requests target the same repository
+    pub fn same_repo(&self, other: &PullRequest) -> bool {
+        self.repo().is_some() && self.repo() == other.repo()
+    }
+
+    /// Returns the fully-qualified name of the source branch e.g. `refs/heads/feature`
+    pub fn source_ref(&self) -> Option<&str> {
+        self.from_ref.get("id").and_then(serde_json::Value::as_str)
+    }
+
+    /// Returns the fully-qualified name of the target branch e.g. `refs/heads/main`
+    pub fn target_ref(&self) -> Option<&str> {
+        self.to_ref.get("id").and_then(serde_json::Value::as_str)
+    }
+
     pub fn hash(&self) -> Option<&str> {
         self.from_ref
             .get("latestCommit")
@@ -59,11 +239,65 @@ impl PullRequest {
     }
 }
 
+/// The Bitbucket server rejected the API token
+#[derive(Debug, Clone, Copy)]
+pub struct AuthError;
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Bitbucket authentication failed. Check bitbucket_api_token."
+        )
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Returns an `AuthError` if the server rejected the request's credentials
+fn check_auth(response: Response) -> Result<Response> {
+    if response.status() == StatusCode::UNAUTHORIZED {
+        Err(AuthError.into())
+    } else {
+        Ok(response)
+    }
+}
+
+/// The Bitbucket server won't merge a pull request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeVeto {
+    /// Whether the pull request conflicts with its target branch
+    pub conflicted: bool,
+    /// Reasons given by the merge check endpoint
+    pub reasons: Vec<String>,
+}
+
+impl fmt::Display for MergeVeto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reasons.join("; "))
+    }
+}
+
+impl std::error::Error for MergeVeto {}
+
+/// Options for fetching paged API endpoints
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PageOptions {
+    /// Number of values to request per page. If `None`, the server default is used.
+    pub limit: Option<u32>,
+    /// Maximum number of pages of pull requests or repositories to fetch for a single search. If
+    /// `None`, all pages are fetched. Endpoints that merge checks depend on are always fetched in
+    /// full.
+    pub max_pages: Option<u32>,
+}
+
 #[derive(Debug)]
 /// A Bitbucket API client
 pub struct Client {
     base_url: String,
     http_client: reqwest::Client,
+    page_options: PageOptions,
+    rate_limiter: Option<RateLimiter>,
 }
 
 impl Client {
@@ -92,9 +326,65 @@ impl Client {
                 .timeout(Duration::from_secs(10))
                 .build()
                 .unwrap(),
+            page_options: PageOptions::default(),
+            rate_limiter: None,
+        }
+    }
+
+    /// Limits the client to the given number of requests per second
+    pub fn with_rate_limit(mut self, requests_per_second: f64) -> Self {
+        self.rate_limiter = Some(RateLimiter::new(requests_per_second));
+        self
+    }
+
+    /// Waits until the rate limit allows another request
+    async fn throttle(&self) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
         }
     }
 
+    /// Sets the options used when fetching paged API endpoints
+    pub fn with_page_options(mut self, page_options: PageOptions) -> Self {
+        self.page_options = page_options;
+        self
+    }
+
+    /// Sends a request, applying the rate limit and checking for authentication failures
+    async fn send(&self, endpoint: &str, request: RequestBuilder) -> Result<Response> {
+        self.send_allowing(endpoint, request, None).await
+    }
+
+    /// Sends a request like [`Client::send`], but doesn't count a response with `allowed_status`
+    /// as a failed request
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    async fn send_allowing(
+        &self,
+        endpoint: &str,
+        request: RequestBuilder,
+        allowed_status: Option<StatusCode>,
+    ) -> Result<Response> {
+        let request = request.build()?;
+        self.throttle().await;
+        cfg_if! {
+            if #[cfg(feature = "metrics")] {
+                let method = request.method().clone();
+                let start = Instant::now();
+                let response = self.http_client.execute(request).await;
+                metrics::observe_bitbucket_request(
+                    &method,
+                    endpoint,
+                    start.elapsed(),
+                    &response,
+                    allowed_status,
+                );
+            } else {
+                let response = self.http_client.execute(request).await;
+            }
+        }
+        check_auth(response?)
+    }
+
     /// Performs a POST request
     async fn post<T>(
         &self,
@@ -106,13 +396,19 @@ impl Client {
         T: Into<reqwest::Body> + std::default::Default + Send,
     {
         let url = self.base_url.clone() + endpoint;
-        Ok(self
+        let request = self
             .http_client
             .post(&url)
             .query(&params)
-            .body(body.unwrap_or_default())
-            .send()
-            .await?)
+            .body(body.unwrap_or_default());
+        self.send(endpoint, request).await
+    }
+
+    /// Performs a PUT request with a JSON body
+    async fn put(&self, endpoint: &str, body: &serde_json::Value) -> Result<Response> {
+        let url = self.base_url.clone() + endpoint;
+        self.send(endpoint, self.http_client.put(&url).json(body))
+            .await
     }
 
     /// Performs a GET request
@@ -122,38 +418,89 @@ impl Client {
         params: Option<&HashMap<&str, String>>,
     ) -> Result<Response> {
         let url = self.base_url.clone() + endpoint;
-        Ok(self.http_client.get(&url).query(&params).send().await?)
+        self.send(endpoint, self.http_client.get(&url).query(&params))
+            .await
     }
 
-    /// Returns the values returned by a paged GET endpoint
-    async fn get_paged_api(
-        &self,
-        endpoint: &str,
-        params: Option<HashMap<&str, String>>,
-    ) -> Result<serde_json::Value> {
+    /// Returns a stream of the values returned by a paged GET endpoint
+    ///
+    /// Pages are only fetched as the stream is consumed, so callers can stop early by dropping the
+    /// stream. At most `max_pages` pages are fetched, if given.
+    fn paged<'a, T>(
+        &'a self,
+        endpoint: String,
+        params: Option<HashMap<&'a str, String>>,
+        max_pages: Option<u32>,
+    ) -> impl Stream<Item = Result<T>> + 'a
+    where
+        T: DeserializeOwned + 'a,
+    {
         /// The response to a single GET request
         #[derive(Deserialize)]
         #[serde(rename_all = "camelCase")]
-        struct Page {
+        struct Page<T> {
             next_page_start: Option<u32>,
-            values: Vec<serde_json::Value>,
+            values: Vec<T>,
         }
 
-        let mut values = Vec::new();
-        let mut params = params.unwrap_or_else(|| HashMap::with_capacity(1));
-        params.insert("start", "0".to_owned());
-        loop {
-            let page: Page =
-                serde_json::from_str(&self.get(endpoint, Some(&params)).await?.text().await?)?;
-            values.extend_from_slice(&page.values);
-            if let Some(start) = page.next_page_start {
-                let start_str = start.to_string();
-                params.insert("start", start_str);
-                continue;
-            }
-            break;
+        let mut params = params.unwrap_or_else(|| HashMap::with_capacity(2));
+        if let Some(limit) = self.page_options.limit {
+            params.insert("limit", limit.to_string());
         }
-        Ok(values.into())
+        let initial_state = (Some(0), 0, endpoint, params);
+        stream::try_unfold(
+            initial_state,
+            move |(start, n_pages, endpoint, mut params)| async move {
+                let start = match start {
+                    Some(start) => start,
+                    None => return anyhow::Ok(None),
+                };
+                if max_pages.is_some_and(|max_pages| n_pages >= max_pages) {
+                    warn!("Stopped fetching {} after {} pages", endpoint, n_pages);
+                    return Ok(None);
+                }
+                params.insert("start", start.to_string());
+                let page: Page<T> =
+                    serde_json::from_str(&self.get(&endpoint, Some(&params)).await?.text().await?)?;
+                let values = stream::iter(page.values.into_iter().map(Ok));
+                Ok(Some((
+                    values,
+                    (page.next_page_start, n_pages + 1, endpoint, params),
+                )))
+            },
+        )
+        .try_flatten()
+    }
+
+    /// Returns all values returned by a paged GET endpoint
+    async fn get_paged_api<T>(
+        &self,
+        endpoint: &str,
+        params: Option<HashMap<&str, String>>,
+    ) -> Result<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        self.paged(endpoint.to_owned(), params, None)
+            .try_collect()
+            .await
+    }
+
+    /// Returns the values returned by a paged GET endpoint that is searched for pull requests, up
+    /// to the configured maximum number of pages
+    ///
+    /// Only use this where missing values can't let a pull request be merged.
+    async fn search_paged_api<T>(
+        &self,
+        endpoint: &str,
+        params: Option<HashMap<&str, String>>,
+    ) -> Result<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        self.paged(endpoint.to_owned(), params, self.page_options.max_pages)
+            .try_collect()
+            .await
     }
 
     /// Returns the username of the authenticated user
@@ -165,31 +512,9 @@ impl Client {
             .await?)
     }
 
-    /// Returns the text of all comments made on a given PR
-    ///
-    /// # Arguments
-    ///
-    /// * `pr` - Pull request to search
-    /// * `username` - If not `None`, only comments written by the provided user will be
-    /// included
-    pub async fn get_pr_comments(
-        &self,
-        pr: &PullRequest,
-        username: Option<&str>,
-    ) -> Result<Vec<String>> {
-        #[derive(Deserialize)]
-        struct CommentAuthor {
-            name: String,
-        }
-
-        #[derive(Deserialize)]
-        struct Comment {
-            author: CommentAuthor,
-            text: String,
-            #[serde(rename = "comments")]
-            replies: Vec<Comment>,
-        }
-
+    /// Returns a stream of all comments and comment replies made on a given PR, with replies
+    /// flattened out. Comments are returned newest first.
+    pub fn pr_comments(&self, pr: &PullRequest) -> impl Stream<Item = Result<Comment>> + '_ {
         #[derive(Deserialize)]
         struct Activity {
             action: String,
@@ -197,54 +522,57 @@ impl Client {
         }
 
         /// Helper function to recurse through comment replies
-        fn recurse_nested_comments(
-            comments: &mut Vec<Comment>,
-            comment_text: &mut Vec<String>,
-            username: Option<&str>,
-        ) {
-            for comment in comments {
-                if username.is_none() || username == Some(&comment.author.name) {
-                    let new_comment = mem::take(&mut comment.text);
-                    comment_text.push(new_comment);
-                }
-                recurse_nested_comments(&mut comment.replies, comment_text, username);
+        fn flatten_comments(mut comment: Comment, comments: &mut Vec<Comment>) {
+            let replies = mem::take(&mut comment.replies);
+            comments.push(comment);
+            for reply in replies {
+                flatten_comments(reply, comments);
             }
         }
 
         // Using the pull request activities API to fetch comments, as it's more ergonomic than the
         // comments API
-        let endpoint = format!(
-            "/rest/api/1.0/projects/{project_key}/repos/{repo_slug}/pull-requests/{id}/activities",
-            project_key = pr.to_ref["repository"]["project"]["key"].as_str().unwrap(),
-            repo_slug = pr.to_ref["repository"]["slug"].as_str().unwrap(),
-            id = pr.id,
-        );
-        let activities: Vec<Activity> =
-            serde_json::from_value(self.get_paged_api(&endpoint, None).await?)?;
-        Ok(activities
-            .into_iter()
-            // Assemble a vector containing all top-level comments and comment replies, filtering
-            // out comments written by other users if a username was provided
-            .flat_map(|activity| {
-                // The activities API can return other events besides comments. Filter out anything that
-                // is not a comment.
-                if activity.action != "COMMENTED" || activity.comment.is_none() {
-                    return Vec::new();
-                }
-                let mut top_level_comment = activity.comment.unwrap();
-                let mut comment_text = Vec::new();
-                if username.is_none() || username == Some(&top_level_comment.author.name) {
-                    let new_comment = mem::take(&mut top_level_comment.text);
-                    comment_text.push(new_comment);
+        stream::once(future::ready(pr.api_path()))
+            .map_ok(move |path| self.paged(format!("{}/activities", path), None, None))
+            .try_flatten()
+            .map_ok(|activity: Activity| {
+                let mut comments = Vec::new();
+                // The activities API can return other events besides comments. Filter out
+                // anything that is not a comment.
+                if activity.action == "COMMENTED" {
+                    if let Some(comment) = activity.comment {
+                        flatten_comments(comment, &mut comments);
+                    }
                 }
-                recurse_nested_comments(
-                    &mut top_level_comment.replies,
-                    &mut comment_text,
-                    username,
-                );
-                comment_text
+                stream::iter(comments.into_iter().map(Ok))
             })
-            .collect())
+            .try_flatten()
+    }
+
+    /// Returns all comments and comment replies made on a given PR, with replies flattened out
+    pub async fn get_pr_comment_list(&self, pr: &PullRequest) -> Result<Vec<Comment>> {
+        self.pr_comments(pr).try_collect().await
+    }
+
+    /// Returns the text of all comments made on a given PR
+    ///
+    /// # Arguments
+    ///
+    /// * `pr` - Pull request to search
+    /// * `username` - If not `None`, only comments written by the provided user will be
+    ///   included
+    pub async fn get_pr_comments(
+        &self,
+        pr: &PullRequest,
+        username: Option<&str>,
+    ) -> Result<Vec<String>> {
+        self.pr_comments(pr)
+            .try_filter(|comment| {
+                future::ready(username.is_none() || username == Some(&comment.author.name))
+            })
+            .map_ok(|comment| comment.text)
+            .try_collect()
+            .await
     }
 
     /// Returns a list of pull requests affiliated with the authenticated user
@@ -252,62 +580,79 @@ impl Client {
     /// # Arguments
     ///
     /// * `params` - A list of parameters to pass to the Bitbucket
-    /// `/rest/api/1.0/dashboard/pull-requests` endpoint. See Bitbucket API documentation for
-    /// available options.
+    ///   `/rest/api/1.0/dashboard/pull-requests` endpoint. See Bitbucket API documentation for
+    ///   available options.
     pub async fn get_prs(&self, params: Option<HashMap<&str, String>>) -> Result<Vec<PullRequest>> {
-        let raw_result = self
-            .get_paged_api("/rest/api/1.0/dashboard/pull-requests", params)
-            .await?;
-        Ok(serde_json::from_value(raw_result)?)
+        self.search_paged_api("/rest/api/1.0/dashboard/pull-requests", params)
+            .await
     }
 
-    /// Check if a pull request is able to be merged without actually merging it
-    pub async fn can_merge(&self, pr: &PullRequest) -> Result<()> {
+    /// Returns a list of pull requests in a repository
+    ///
+    /// # Arguments
+    ///
+    /// * `project_key` - Key of the project containing the repository
+    /// * `repo_slug` - Slug of the repository
+    /// * `params` - A list of parameters to pass to the Bitbucket
+    ///   `/rest/api/1.0/projects/{projectKey}/repos/{repositorySlug}/pull-requests` endpoint. See
+    ///   Bitbucket API documentation for available options.
+    pub async fn get_repo_prs(
+        &self,
+        project_key: &str,
+        repo_slug: &str,
+        params: Option<HashMap<&str, String>>,
+    ) -> Result<Vec<PullRequest>> {
         let endpoint = format!(
-            "/rest/api/1.0/projects/{project_key}/repos/{repo_slug}/pull-requests/{id}/merge",
-            project_key = pr.to_ref["repository"]["project"]["key"].as_str().unwrap(),
-            repo_slug = pr.to_ref["repository"]["slug"].as_str().unwrap(),
-            id = pr.id,
+            "/rest/api/1.0/projects/{}/repos/{}/pull-requests",
+            project_key, repo_slug
         );
+        self.search_paged_api(&endpoint, params).await
+    }
+
+    /// Returns the slugs of all repositories in a project
+    pub async fn get_repo_slugs(&self, project_key: &str) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct Repository {
+            slug: String,
+        }
+
+        let endpoint = format!("/rest/api/1.0/projects/{}/repos", project_key);
+        let repos: Vec<Repository> = self.search_paged_api(&endpoint, None).await?;
+        Ok(repos.into_iter().map(|repo| repo.slug).collect())
+    }
+
+    /// Check if a pull request is able to be merged without actually merging it
+    pub async fn can_merge(&self, pr: &PullRequest) -> Result<()> {
+        let endpoint = format!("{}/merge", pr.api_path()?);
         let response_text = self.get(&endpoint, None).await?.text().await?;
         let response_json: serde_json::Value = serde_json::from_str(&response_text)?;
-        response_json
-            .as_object()
-            .and_then(|response| {
-                if response.get("canMerge") == Some(&serde_json::Value::Bool(true)) {
-                    Some(())
-                } else {
-                    None
-                }
-            })
-            .ok_or_else(|| anyhow!(response_text))
+        if response_json.get("canMerge") == Some(&serde_json::Value::Bool(true)) {
+            return Ok(());
+        }
+        match merge_veto_reasons(&response_json) {
+            reasons if reasons.is_empty() => Err(anyhow!(response_text)),
+            reasons => Err(MergeVeto {
+                conflicted: response_json.get("conflicted") == Some(&serde_json::Value::Bool(true)),
+                reasons,
+            }
+            .into()),
+        }
     }
 
     /// Merge the given pull request
     pub async fn merge_pr(&self, pr: &PullRequest) -> Result<()> {
         // Check if the PR is blocked from merging e.g. because there's a build in progress
         // TODO: maybe just skip this check and use the POST error response instead
-        self.can_merge(pr)
-            .await
-            .with_context(|| format!("PR not ready to merge: {}", pr.url().unwrap()))?;
+        self.can_merge(pr).await.context("PR not ready to merge")?;
 
-        let endpoint = format!(
-            "/rest/api/1.0/projects/{project_key}/repos/{repo_slug}/pull-requests/{id}/merge",
-            project_key = pr.to_ref["repository"]["project"]["key"].as_str().unwrap(),
-            repo_slug = pr.to_ref["repository"]["slug"].as_str().unwrap(),
-            id = pr.id,
-        );
+        let endpoint = format!("{}/merge", pr.api_path()?);
         // Create json body by hand. It's just one "version" field that contains the PR version id
         let post_body = String::from(r#"{"version":"#) + &pr.version.to_string() + "}";
         let response = self.post(&endpoint, None, Some(post_body)).await?;
         if response.status().as_u16() == 200 {
             Ok(())
         } else {
-            Err(anyhow!(
-                "PR merge failed for {}\n{}",
-                pr.url().unwrap(),
-                response.text().await?
-            ))
+            Err(anyhow!("PR merge failed: {}", response.text().await?))
         }
     }
 
@@ -317,7 +662,297 @@ impl Client {
     // TODO: use git hash type
     pub async fn get_build_status(&self, hash: &str) -> Result<Vec<BuildStatus>> {
         let endpoint = format!("/rest/build-status/1.0/commits/{}", hash);
-        let response = self.get_paged_api(&endpoint, None).await?;
-        Ok(serde_json::from_value(response)?)
+        self.get_paged_api(&endpoint, None).await
+    }
+
+    /// Returns the contents of a file on the target branch of a pull request, or `None` if the file
+    /// doesn't exist
+    pub async fn get_target_file(&self, pr: &PullRequest, path: &str) -> Result<Option<String>> {
+        let endpoint = format!("{}/raw/{}", pr.repo_api_path()?, path);
+        let mut params = HashMap::with_capacity(1);
+        if let Some(target_ref) = pr.target_ref() {
+            params.insert("at", target_ref.to_owned());
+        }
+        let url = self.base_url.clone() + &endpoint;
+        let request = self.http_client.get(&url).query(&params);
+        // A missing file is an answer, not a failed request
+        let response = self
+            .send_allowing(&endpoint, request, Some(StatusCode::NOT_FOUND))
+            .await?;
+        match response.status().as_u16() {
+            200 => Ok(Some(response.text().await?)),
+            404 => Ok(None),
+            status => Err(anyhow!("Fetching {} returned {}", path, status)),
+        }
+    }
+
+    /// Returns the paths of all files changed by a pull request
+    pub async fn get_pr_changed_paths(&self, pr: &PullRequest) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Path {
+            to_string: String,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Change {
+            path: Path,
+            src_path: Option<Path>,
+        }
+
+        let endpoint = format!("{}/changes", pr.api_path()?);
+        let changes: Vec<Change> = self.get_paged_api(&endpoint, None).await?;
+        Ok(changes
+            .into_iter()
+            // Moving a file out of a path is considered a change to that path as well
+            .flat_map(|change| std::iter::once(change.path).chain(change.src_path))
+            .map(|path| path.to_string)
+            .collect())
+    }
+
+    /// Returns a pull request in the same repository as `pr`
+    pub async fn get_pr_in_repo(&self, pr: &PullRequest, id: u32) -> Result<PullRequest> {
+        let endpoint = format!("{}/pull-requests/{}", pr.repo_api_path()?, id);
+        let response = self.get(&endpoint, None).await?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "Fetching pull request {} returned {}",
+                id,
+                response.status()
+            ));
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Returns the open pull requests in the same repository as `pr` whose source is `source_ref`
+    pub async fn get_open_prs_from_ref(
+        &self,
+        pr: &PullRequest,
+        source_ref: &str,
+    ) -> Result<Vec<PullRequest>> {
+        let endpoint = format!("{}/pull-requests", pr.repo_api_path()?);
+        let mut params: HashMap<&str, String> = HashMap::with_capacity(3);
+        params.insert("state", "OPEN".to_owned());
+        params.insert("direction", "OUTGOING".to_owned());
+        params.insert("at", source_ref.to_owned());
+        self.get_paged_api(&endpoint, Some(params)).await
+    }
+
+    /// Adds a comment to a pull request
+    pub async fn add_pr_comment(&self, pr: &PullRequest, text: &str) -> Result<()> {
+        let endpoint = format!("{}/comments", pr.api_path()?);
+        let url = self.base_url.clone() + &endpoint;
+        let body = serde_json::json!({ "text": text });
+        let response = self
+            .send(&endpoint, self.http_client.post(&url).json(&body))
+            .await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(anyhow!("Commenting failed: {}", response.text().await?))
+        }
+    }
+
+    /// Changes the target branch of a pull request and returns the updated pull request
+    pub async fn retarget_pr(&self, pr: &PullRequest, target_ref: &str) -> Result<PullRequest> {
+        let reviewers: Vec<serde_json::Value> = pr
+            .reviewers
+            .iter()
+            .map(|reviewer| serde_json::json!({"user": {"name": reviewer.user.name}}))
+            .collect();
+        // Include the fields that aren't being changed so that they aren't cleared
+        let body = serde_json::json!({
+            "version": pr.version,
+            "title": pr.title,
+            "description": pr.description,
+            "reviewers": reviewers,
+            "toRef": {
+                "id": target_ref,
+                "repository": pr.to_ref["repository"],
+            },
+        });
+        let response = self.put(&pr.api_path()?, &body).await?;
+        if response.status().is_success() {
+            Ok(response.json().await?)
+        } else {
+            Err(anyhow!(
+                "Retargeting {} failed\n{}",
+                pr.url().unwrap(),
+                response.text().await?
+            ))
+        }
+    }
+}
+
+/// Returns the reasons given by the merge check endpoint for why a pull request can't be merged
+fn merge_veto_reasons(response: &serde_json::Value) -> Vec<String> {
+    let mut reasons = Vec::new();
+    if response.get("conflicted") == Some(&serde_json::Value::Bool(true)) {
+        reasons.push(String::from("conflicts"));
+    }
+    let vetoes = response.get("vetoes").and_then(serde_json::Value::as_array);
+    reasons.extend(
+        vetoes
+            .into_iter()
+            .flatten()
+            .filter_map(|veto| veto.get("summaryMessage")?.as_str())
+            .map(String::from),
+    );
+    reasons
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+    use crate::test_server::{self, Request, Response};
+    use serde_json::json;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    /// Serves 3 pages of 2 numbers each from any endpoint
+    async fn serve_pages() -> (String, UnboundedReceiver<Request>) {
+        test_server::serve(|request| {
+            let start: u32 = query(request, "start").map_or(0, |start| start.parse().unwrap());
+            let is_last_page = start >= 4;
+            Response::json(json!({
+                "values": [start, start + 1],
+                "isLastPage": is_last_page,
+                "nextPageStart": (!is_last_page).then_some(start + 2),
+            }))
+        })
+        .await
+    }
+
+    fn query(request: &Request, name: &str) -> Option<String> {
+        let url = url::Url::parse(&format!("http://localhost{}", request.path)).unwrap();
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    /// Returns the `start` and `limit` parameters of the requests received so far
+    fn page_params(requests: &mut UnboundedReceiver<Request>) -> Vec<(String, Option<String>)> {
+        let mut params = Vec::new();
+        while let Ok(request) = requests.try_recv() {
+            params.push((query(&request, "start").unwrap(), query(&request, "limit")));
+        }
+        params
+    }
+
+    #[test]
+    fn veto_reasons() {
+        assert_eq!(
+            merge_veto_reasons(&json!({"canMerge": false, "conflicted": true, "vetoes": []})),
+            vec!["conflicts"]
+        );
+        assert_eq!(
+            merge_veto_reasons(&json!({
+                "canMerge": false,
+                "conflicted": false,
+                "vetoes": [
+                    {"summaryMessage": "Not all required builds are successful yet", "detailedMessage": "..."},
+                    {"summaryMessage": "Requires approvals", "detailedMessage": "..."},
+                ],
+            })),
+            vec![
+                "Not all required builds are successful yet",
+                "Requires approvals"
+            ]
+        );
+        assert!(merge_veto_reasons(&json!({"canMerge": false})).is_empty());
+    }
+
+    #[tokio::test]
+    async fn pages_are_chained() {
+        let (base_url, mut requests) = serve_pages().await;
+        let api = Client::new(base_url, "token");
+        let values: Vec<u32> = api.get_paged_api("/values", None).await.unwrap();
+        assert_eq!(values, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(
+            page_params(&mut requests),
+            [
+                (String::from("0"), None),
+                (String::from("2"), None),
+                (String::from("4"), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn page_limit() {
+        let (base_url, mut requests) = serve_pages().await;
+        let api = Client::new(base_url, "token").with_page_options(PageOptions {
+            limit: Some(2),
+            max_pages: None,
+        });
+        let mut params = HashMap::new();
+        params.insert("state", String::from("open"));
+        let values: Vec<u32> = api.get_paged_api("/values", Some(params)).await.unwrap();
+        assert_eq!(values.len(), 6);
+        let request = requests.recv().await.unwrap();
+        assert_eq!(query(&request, "limit").as_deref(), Some("2"));
+        assert_eq!(query(&request, "state").as_deref(), Some("open"));
+    }
+
+    #[tokio::test]
+    async fn max_pages_only_limits_searches() {
+        let (base_url, mut requests) = serve_pages().await;
+        let api = Client::new(base_url, "token").with_page_options(PageOptions {
+            limit: None,
+            max_pages: Some(2),
+        });
+        let values: Vec<u32> = api.search_paged_api("/values", None).await.unwrap();
+        assert_eq!(values, [0, 1, 2, 3]);
+        assert_eq!(page_params(&mut requests).len(), 2);
+        // Endpoints that merge checks depend on are fetched in full
+        let values: Vec<u32> = api.get_paged_api("/values", None).await.unwrap();
+        assert_eq!(values.len(), 6);
+        assert_eq!(page_params(&mut requests).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn merge_vetoes() {
+        let (base_url, _requests) = test_server::serve(|_| {
+            Response::json(json!({
+                "canMerge": false,
+                "conflicted": true,
+                "vetoes": [{"summaryMessage": "Requires approvals"}],
+            }))
+        })
+        .await;
+        let api = Client::new(base_url, "token");
+        let pr = test_fixtures::pr(1, json!({}));
+        let e = api.merge_pr(&pr).await.unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<MergeVeto>(),
+            Some(&MergeVeto {
+                conflicted: true,
+                reasons: vec![
+                    String::from("conflicts"),
+                    String::from("Requires approvals")
+                ],
+            })
+        );
+        assert_eq!(
+            format!("{:#}", e),
+            "PR not ready to merge: conflicts; Requires approvals"
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_target_repository() {
+        let (base_url, mut requests) = test_server::serve(|_| Response::status(500)).await;
+        let api = Client::new(base_url, "token");
+        let pr = test_fixtures::pr(1, json!({"toRef": {"id": "refs/heads/main"}}));
+        assert_eq!(pr.project_key(), None);
+        assert_eq!(pr.key(), None);
+        assert!(!pr.same_repo(&pr));
+        assert_eq!(
+            format!("{:#}", api.merge_pr(&pr).await.unwrap_err()),
+            "PR not ready to merge: Pull request 1 has no target repository"
+        );
+        assert!(api.get_pr_comment_list(&pr).await.is_err());
+        assert!(requests.try_recv().is_err());
     }
 }
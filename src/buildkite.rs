@@ -0,0 +1,213 @@
+#![cfg(feature = "buildkite")]
+//! Rebuilding failed Buildkite builds
+
+use crate::ci::{self, CiBackend, QueuedBuild, RebuildState, HTTP_CLIENT};
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::{RequestBuilder, StatusCode};
+use serde::Deserialize;
+
+/// Buildkite credentials
+#[derive(Debug, Clone, Deserialize)]
+pub struct BuildkiteConfig {
+    /// API access token with the `read_builds` and `write_builds` scopes
+    pub token: String,
+    /// Web URL of Buildkite. Defaults to `https://buildkite.com`.
+    #[serde(default = "default_url")]
+    pub url: String,
+    /// URL of the REST API. Defaults to `https://api.buildkite.com`.
+    #[serde(default = "default_api_url")]
+    pub api_url: String,
+}
+
+fn default_url() -> String {
+    String::from("https://buildkite.com")
+}
+
+fn default_api_url() -> String {
+    String::from("https://api.buildkite.com")
+}
+
+/// A build, identified by its URL
+#[derive(Debug, PartialEq, Eq)]
+struct BuildUrl {
+    organization: String,
+    pipeline: String,
+    number: u64,
+}
+
+/// A build, as returned by the API
+#[derive(Debug, Deserialize)]
+struct Build {
+    state: String,
+    web_url: String,
+}
+
+/// Buildkite as a CI server to rebuild builds on
+#[derive(Debug)]
+pub struct Buildkite {
+    url: String,
+    api_url: String,
+    token: String,
+}
+
+impl Buildkite {
+    pub fn new(config: BuildkiteConfig) -> Self {
+        Self {
+            url: config.url.trim_end_matches('/').to_owned(),
+            api_url: config.api_url.trim_end_matches('/').to_owned(),
+            token: config.token,
+        }
+    }
+
+    /// Parses the URL of a build, or of one of its jobs
+    fn parse_url(&self, build_url: &str) -> Option<BuildUrl> {
+        static PATH_REGEX: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"^/([^/]+)/([^/]+)/builds/(\d+)(?:[/?#].*)?$").unwrap());
+
+        let path = build_url.strip_prefix(&self.url)?;
+        let captures = PATH_REGEX.captures(path)?;
+        Some(BuildUrl {
+            organization: captures[1].to_owned(),
+            pipeline: captures[2].to_owned(),
+            number: captures[3].parse().ok()?,
+        })
+    }
+
+    fn build_api_url(&self, build: &BuildUrl) -> String {
+        format!(
+            "{}/v2/organizations/{}/pipelines/{}/builds/{}",
+            self.api_url, build.organization, build.pipeline, build.number
+        )
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        request.bearer_auth(&self.token)
+    }
+}
+
+impl CiBackend for Buildkite {
+    fn name(&self) -> &'static str {
+        "Buildkite"
+    }
+
+    fn recognizes(&self, build_url: &str) -> bool {
+        self.parse_url(build_url).is_some()
+    }
+
+    fn rebuild_state<'a>(
+        &'a self,
+        rebuild: &'a mut QueuedBuild,
+    ) -> BoxFuture<'a, Result<Option<RebuildState>>> {
+        Box::pin(async move {
+            let Some(build) = rebuild.url.as_deref().and_then(|url| self.parse_url(url)) else {
+                return Ok(None);
+            };
+            let response = self
+                .authorize(HTTP_CLIENT.get(self.build_api_url(&build)))
+                .send()
+                .await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let build: Build = ci::json_response(response, "Fetching build").await?;
+            Ok(match build.state.as_str() {
+                "creating" | "scheduled" => Some(RebuildState::Queued),
+                "running" | "failing" | "canceling" => Some(RebuildState::Running),
+                // passed, failed, blocked, canceled, skipped or not_run
+                _ => None,
+            })
+        })
+    }
+
+    fn rebuild<'a>(&'a self, build_url: &'a str) -> BoxFuture<'a, Result<QueuedBuild>> {
+        Box::pin(async move {
+            let build = self
+                .parse_url(build_url)
+                .ok_or_else(|| anyhow!("Invalid URL: {}", build_url))?;
+            // Rebuilding creates a new build of the same commit with the same environment
+            let url = format!("{}/rebuild", self.build_api_url(&build));
+            let response = self.authorize(HTTP_CLIENT.put(url)).send().await?;
+            let rebuilt: Build = ci::json_response(response, "Rebuild").await?;
+            Ok(QueuedBuild {
+                queue_url: None,
+                url: Some(rebuilt.web_url),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Response};
+    use serde_json::json;
+
+    fn backend(url: &str) -> Buildkite {
+        Buildkite::new(BuildkiteConfig {
+            token: String::from("secret"),
+            url: String::from(url),
+            api_url: String::from(url),
+        })
+    }
+
+    #[test]
+    fn parses_urls() {
+        let buildkite = backend("https://buildkite.com");
+        let build = BuildUrl {
+            organization: String::from("acme"),
+            pipeline: String::from("app"),
+            number: 7,
+        };
+        for url in [
+            "https://buildkite.com/acme/app/builds/7",
+            "https://buildkite.com/acme/app/builds/7#0190-job",
+            "https://buildkite.com/acme/app/builds/7/steps",
+        ] {
+            assert_eq!(buildkite.parse_url(url).as_ref(), Some(&build), "{}", url);
+        }
+        assert!(!buildkite.recognizes("https://buildkite.com/acme/app"));
+        assert!(!buildkite.recognizes("https://example.com/acme/app/builds/7"));
+    }
+
+    #[tokio::test]
+    async fn rebuilds() {
+        let (base_url, _requests) = test_server::serve(|request| {
+            let host = request.header("host").unwrap_or_default();
+            if request.header("authorization") != Some("Bearer secret") {
+                return Response::status(401);
+            }
+            match (request.method.as_str(), request.path.as_str()) {
+                ("PUT", "/v2/organizations/acme/pipelines/app/builds/7/rebuild") => {
+                    Response::json(json!({
+                        "state": "scheduled",
+                        "web_url": format!("http://{}/acme/app/builds/8", host),
+                    }))
+                }
+                ("GET", "/v2/organizations/acme/pipelines/app/builds/8") => Response::json(json!({
+                    "state": "failing",
+                    "web_url": format!("http://{}/acme/app/builds/8", host),
+                })),
+                _ => Response::status(404),
+            }
+        })
+        .await;
+        let buildkite = backend(&base_url);
+        let mut rebuild = buildkite
+            .rebuild(&format!("{}/acme/app/builds/7", base_url))
+            .await
+            .unwrap();
+        assert_eq!(rebuild.url, Some(format!("{}/acme/app/builds/8", base_url)));
+        assert_eq!(
+            buildkite.rebuild_state(&mut rebuild).await.unwrap(),
+            Some(RebuildState::Running)
+        );
+        assert!(buildkite
+            .rebuild(&format!("{}/acme/app/builds/9", base_url))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,241 @@
+//! Rebuilding failed builds on CI servers
+//!
+//! Each supported CI server implements [`CiBackend`]. A failed build reported to Bitbucket is
+//! rebuilt by the first configured backend that recognizes the build's URL. Only
+//! [`RebuildState`] is available without the `ci` feature, as results always have room for
+//! rebuilds.
+
+#[cfg(feature = "buildkite")]
+use crate::buildkite::Buildkite;
+#[cfg(feature = "github")]
+use crate::github_actions::GithubActions;
+#[cfg(feature = "gitlab")]
+use crate::gitlab::Gitlab;
+#[cfg(feature = "jenkins")]
+use crate::jenkins::JenkinsBackend;
+#[cfg(feature = "teamcity")]
+use crate::teamcity::Teamcity;
+#[cfg(feature = "ci")]
+use crate::Config;
+
+#[cfg(feature = "ci")]
+use anyhow::Result;
+#[cfg(feature = "ci")]
+use futures::future::BoxFuture;
+#[cfg(feature = "ci")]
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+
+/// HTTP client shared by the backends that don't need one of their own
+#[cfg(any(
+    feature = "buildkite",
+    feature = "github",
+    feature = "gitlab",
+    feature = "teamcity"
+))]
+pub(crate) static HTTP_CLIENT: once_cell::sync::Lazy<reqwest::Client> =
+    once_cell::sync::Lazy::new(|| {
+        reqwest::Client::builder()
+            // Required by GitHub
+            .user_agent(concat!("crabby-merge/", env!("CARGO_PKG_VERSION")))
+            // Builds are retried while checking pull requests, so a CI server that hangs mustn't
+            // hold them up
+            .timeout(std::time::Duration::from_secs(30))
+            .build()
+            .expect("Could not build HTTP client")
+    });
+
+/// What a rebuild triggered by crabby-merge is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RebuildState {
+    /// The build was rebuilt in this run
+    Triggered,
+    /// The build was rebuilt in an earlier run and the new build is waiting in the queue
+    Queued,
+    /// The build was rebuilt in an earlier run and the new build is running
+    Running,
+    /// The build will be rebuilt once its backoff ends
+    Waiting,
+}
+
+impl fmt::Display for RebuildState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Triggered => "triggered",
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Waiting => "waiting",
+        })
+    }
+}
+
+/// A rebuild that was triggered
+#[cfg(feature = "ci")]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedBuild {
+    /// URL of the queue item, for CI servers that queue builds before they get a URL
+    pub queue_url: Option<String>,
+    /// URL of the new build, once it has left the queue
+    pub url: Option<String>,
+}
+
+/// A CI server that failed builds can be rebuilt on
+#[cfg(feature = "ci")]
+pub trait CiBackend: Send + Sync {
+    /// Name of the CI server, for logging
+    fn name(&self) -> &'static str;
+
+    /// Returns whether a build URL reported to Bitbucket belongs to this CI server
+    fn recognizes(&self, build_url: &str) -> bool;
+
+    /// Returns whether a rebuild is still queued or running, or `None` if it has finished or can't
+    /// be followed any more. Fills in the URL of the new build once it is known.
+    fn rebuild_state<'a>(
+        &'a self,
+        rebuild: &'a mut QueuedBuild,
+    ) -> BoxFuture<'a, Result<Option<RebuildState>>>;
+
+    /// Triggers a rebuild of a failed build
+    fn rebuild<'a>(&'a self, build_url: &'a str) -> BoxFuture<'a, Result<QueuedBuild>>;
+
+    /// Returns whether a failed build is worth retrying because it failed for a known flaky
+    /// reason. Backends that can't tell retry every failure.
+    fn failed_flakily<'a>(&'a self, _build_url: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async { Ok(true) })
+    }
+}
+
+/// Returns the CI servers configured in `config`, in the order they should be tried
+#[cfg(feature = "ci")]
+#[cfg_attr(
+    not(any(
+        feature = "buildkite",
+        feature = "github",
+        feature = "gitlab",
+        feature = "jenkins",
+        feature = "teamcity"
+    )),
+    allow(unused_mut, unused_variables)
+)]
+pub fn backends(config: &Config) -> Vec<Box<dyn CiBackend>> {
+    let mut backends: Vec<Box<dyn CiBackend>> = Vec::new();
+    #[cfg(feature = "github")]
+    if let Some(github) = &config.github {
+        backends.push(Box::new(GithubActions::new(github.clone())));
+    }
+    #[cfg(feature = "gitlab")]
+    if let Some(gitlab) = &config.gitlab {
+        backends.push(Box::new(Gitlab::new(gitlab.clone())));
+    }
+    #[cfg(feature = "teamcity")]
+    if let Some(teamcity) = &config.teamcity {
+        backends.push(Box::new(Teamcity::new(teamcity.clone())));
+    }
+    #[cfg(feature = "buildkite")]
+    if let Some(buildkite) = &config.buildkite {
+        backends.push(Box::new(Buildkite::new(buildkite.clone())));
+    }
+    // Jenkins recognizes any URL that looks like a build, so it goes last
+    #[cfg(feature = "jenkins")]
+    if !config.jenkins_servers.is_empty() {
+        backends.push(Box::new(JenkinsBackend::new(
+            config.jenkins_servers.clone(),
+            config.jenkins_restart_failed_stage,
+            config.jenkins_flaky_failures.clone(),
+        )));
+    }
+    backends
+}
+
+/// Returns the first backend that recognizes a build URL
+#[cfg(feature = "ci")]
+pub fn backend_for<'a>(
+    backends: &'a [Box<dyn CiBackend>],
+    build_url: &str,
+) -> Option<&'a dyn CiBackend> {
+    backends
+        .iter()
+        .find(|backend| backend.recognizes(build_url))
+        .map(Box::as_ref)
+}
+
+/// Returns the JSON body of a successful response, or an error describing what failed
+#[cfg(any(
+    feature = "buildkite",
+    feature = "github",
+    feature = "gitlab",
+    feature = "teamcity"
+))]
+pub(crate) async fn json_response<T: serde::de::DeserializeOwned>(
+    response: reqwest::Response,
+    action: &str,
+) -> Result<T> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(response.json().await?)
+    } else {
+        Err(anyhow::anyhow!(
+            "{} returned {}: {}",
+            action,
+            status,
+            response.text().await.unwrap_or_default()
+        ))
+    }
+}
+
+#[cfg(all(test, feature = "ci"))]
+mod tests {
+    use super::*;
+
+    /// A backend that recognizes URLs with a given prefix
+    struct Mock(&'static str);
+
+    impl CiBackend for Mock {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn recognizes(&self, build_url: &str) -> bool {
+            build_url.starts_with(self.0)
+        }
+
+        fn rebuild_state<'a>(
+            &'a self,
+            _rebuild: &'a mut QueuedBuild,
+        ) -> BoxFuture<'a, Result<Option<RebuildState>>> {
+            Box::pin(async { Ok(None) })
+        }
+
+        fn rebuild<'a>(&'a self, build_url: &'a str) -> BoxFuture<'a, Result<QueuedBuild>> {
+            Box::pin(async move {
+                Ok(QueuedBuild {
+                    queue_url: None,
+                    url: Some(build_url.to_owned()),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatches_on_url() {
+        let backends: Vec<Box<dyn CiBackend>> = vec![
+            Box::new(Mock("https://ci.example.com/")),
+            Box::new(Mock("https://")),
+        ];
+        let backend = |url| backend_for(&backends, url).map(CiBackend::name);
+        assert_eq!(
+            backend("https://ci.example.com/build/1"),
+            Some("https://ci.example.com/")
+        );
+        assert_eq!(backend("https://other.example.com/1"), Some("https://"));
+        assert_eq!(backend("ftp://example.com/1"), None);
+
+        let backend = backend_for(&backends, "https://ci.example.com/build/1").unwrap();
+        assert!(backend
+            .failed_flakily("https://ci.example.com/build/1")
+            .await
+            .unwrap());
+    }
+}
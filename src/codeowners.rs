@@ -0,0 +1,322 @@
+//! Support for CODEOWNERS files
+//!
+//! Each non-empty, non-comment line of a CODEOWNERS file consists of a gitignore-style path pattern
+//! followed by one or more owners. Users are written as `@username` and groups as `@@group`, where
+//! groups are defined by the `reviewer_groups` configuration table. As in GitHub, the last pattern
+//! matching a path determines its owners.
+
+use crate::bitbucket::{self, ParticipantStatus, PullRequest};
+use crate::merge_checks::Blocker;
+
+use anyhow::{anyhow, Result};
+use guard::guard;
+use log::*;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+/// An owner of a set of paths
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Owner {
+    User(String),
+    Group(String),
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Owner::User(name) => write!(f, "@{}", name),
+            Owner::Group(name) => write!(f, "@@{}", name),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Regex,
+    owners: Vec<Owner>,
+}
+
+/// A parsed CODEOWNERS file
+#[derive(Debug)]
+pub struct CodeOwners {
+    rules: Vec<Rule>,
+}
+
+/// Converts a gitignore-style pattern into a regex matching the full path of a file
+fn pattern_to_regex(pattern: &str) -> Result<Regex> {
+    // Patterns with a slash anywhere but the end are relative to the repository root. Others can
+    // match at any depth.
+    let trimmed = pattern.trim_end_matches('/');
+    let anchored = trimmed.contains('/');
+    let trimmed = trimmed.trim_start_matches('/');
+    if trimmed.is_empty() {
+        return Err(anyhow!("Invalid pattern: {}", pattern));
+    }
+
+    let mut regex = String::from(if anchored { "^" } else { "^(?:.*/)?" });
+    let mut chars = trimmed.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    // "**/" matches zero or more directories
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    // A pattern matching a directory matches everything inside of it
+    regex.push_str("(?:/.*)?$");
+    Ok(Regex::new(&regex)?)
+}
+
+impl CodeOwners {
+    /// Parses the contents of a CODEOWNERS file
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut tokens = line.split_whitespace();
+            let pattern = tokens.next().unwrap();
+            let owners = tokens
+                .take_while(|token| !token.starts_with('#'))
+                .map(|token| {
+                    if let Some(group) = token.strip_prefix("@@") {
+                        Owner::Group(group.to_string())
+                    } else {
+                        Owner::User(token.trim_start_matches('@').to_string())
+                    }
+                })
+                .collect();
+            rules.push(Rule {
+                pattern: pattern_to_regex(pattern)?,
+                owners,
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    /// Returns the owners of the given path. A path without owners can be approved by anyone.
+    pub fn owners(&self, path: &str) -> &[Owner] {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.pattern.is_match(path))
+            .map(|rule| rule.owners.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Returns blockers for all changed paths that don't have an approval from one of their owners
+    ///
+    /// # Arguments
+    ///
+    /// * `paths` - Changed paths
+    /// * `approvers` - Usernames of users that have approved the changes
+    /// * `groups` - Members of each group that can be referenced in the CODEOWNERS file
+    pub fn blockers<'a>(
+        &self,
+        paths: impl IntoIterator<Item = &'a str>,
+        approvers: &BTreeSet<&str>,
+        groups: &HashMap<String, Vec<String>>,
+    ) -> Vec<Blocker> {
+        let is_approved_by = |owner: &Owner| match owner {
+            Owner::User(name) => approvers.contains(name.as_str()),
+            Owner::Group(group) => match groups.get(group) {
+                Some(members) => members
+                    .iter()
+                    .any(|member| approvers.contains(member.as_str())),
+                None => {
+                    warn!("CODEOWNERS group {} is not in reviewer_groups", group);
+                    false
+                }
+            },
+        };
+
+        // Group unapproved paths by their owners to keep the list of blockers short
+        let mut unapproved: BTreeMap<&[Owner], Vec<String>> = BTreeMap::new();
+        for path in paths {
+            let owners = self.owners(path);
+            if !owners.is_empty() && !owners.iter().any(is_approved_by) {
+                unapproved.entry(owners).or_default().push(path.to_string());
+            }
+        }
+        unapproved
+            .into_iter()
+            .map(|(owners, paths)| Blocker::MissingCodeOwnerApproval {
+                owners: owners.iter().map(ToString::to_string).collect(),
+                paths,
+            })
+            .collect()
+    }
+}
+
+/// Fetches the CODEOWNERS file from the target branch of a pull request and returns blockers for
+/// all changed paths that aren't approved by one of their owners
+pub async fn blockers(
+    api: &bitbucket::Client,
+    pr: &PullRequest,
+    codeowners_path: &str,
+    groups: &HashMap<String, Vec<String>>,
+) -> Result<Vec<Blocker>> {
+    guard!(
+        let Some(contents) = api.get_target_file(pr, codeowners_path).await?
+        else {
+            debug!(
+                "No {} found for {}",
+                codeowners_path,
+                pr.url().unwrap_or_default()
+            );
+            return Ok(Vec::new());
+        }
+    );
+    let codeowners = CodeOwners::parse(&contents)?;
+    let paths: BTreeSet<String> = api.get_pr_changed_paths(pr).await?.into_iter().collect();
+    let approvers = pr.users_with_status(ParticipantStatus::Approved);
+    Ok(codeowners.blockers(paths.iter().map(String::as_str), &approvers, groups))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+    use crate::test_server::{self, Response};
+    use serde_json::json;
+
+    const CODEOWNERS: &str = "\
+# Default owners
+*           @alice
+*.rs        @bob @@rustaceans  # inline comment
+/docs/      @carol
+build/**/out @dave
+";
+
+    #[test]
+    fn patterns() {
+        let codeowners = CodeOwners::parse(CODEOWNERS).unwrap();
+        let user = |name: &str| Owner::User(name.to_string());
+        assert_eq!(codeowners.owners("README.md"), [user("alice")]);
+        assert_eq!(
+            codeowners.owners("src/main.rs"),
+            [user("bob"), Owner::Group(String::from("rustaceans"))]
+        );
+        assert_eq!(codeowners.owners("docs/guide/index.md"), [user("carol")]);
+        assert_eq!(codeowners.owners("src/docs/index.md"), [user("alice")]);
+        assert_eq!(codeowners.owners("build/out/a"), [user("dave")]);
+        assert_eq!(codeowners.owners("build/x/y/out"), [user("dave")]);
+    }
+
+    #[test]
+    fn unowned_paths() {
+        let codeowners = CodeOwners::parse("/src/ @alice").unwrap();
+        assert!(codeowners.owners("README.md").is_empty());
+        assert!(codeowners.owners("lib/src/a.rs").is_empty());
+    }
+
+    #[test]
+    fn approvals() {
+        let codeowners = CodeOwners::parse(CODEOWNERS).unwrap();
+        let mut groups = HashMap::new();
+        groups.insert(String::from("rustaceans"), vec![String::from("erin")]);
+        let paths = ["src/main.rs", "src/lib.rs", "docs/index.md"];
+
+        let approvers = BTreeSet::from(["erin", "carol"]);
+        assert!(codeowners.blockers(paths, &approvers, &groups).is_empty());
+
+        let approvers = BTreeSet::from(["alice"]);
+        assert_eq!(
+            codeowners.blockers(paths, &approvers, &groups),
+            vec![
+                Blocker::MissingCodeOwnerApproval {
+                    owners: vec![String::from("@bob"), String::from("@@rustaceans")],
+                    paths: vec![String::from("src/main.rs"), String::from("src/lib.rs")],
+                },
+                Blocker::MissingCodeOwnerApproval {
+                    owners: vec![String::from("@carol")],
+                    paths: vec![String::from("docs/index.md")],
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fetches_codeowners_and_changes() {
+        let (base_url, mut requests) = test_server::serve(|request| {
+            let path = request.path.split('?').next().unwrap();
+            match path {
+                "/rest/api/1.0/projects/PROJ/repos/app/raw/.github/CODEOWNERS" => {
+                    Response::text("*  @alice\n*.rs  @@rustaceans\n/docs/  @carol\n")
+                }
+                "/rest/api/1.0/projects/PROJ/repos/app/pull-requests/1/changes" => {
+                    Response::json(json!({
+                        "isLastPage": true,
+                        "values": [
+                            {"path": {"toString": "src/main.rs"}},
+                            {"path": {"toString": "docs/guide.md"}, "srcPath": {"toString": "README.md"}},
+                        ],
+                    }))
+                }
+                _ => Response::status(404),
+            }
+        })
+        .await;
+        let api = bitbucket::Client::new(base_url, "token");
+        let mut groups = HashMap::new();
+        groups.insert(String::from("rustaceans"), vec![String::from("erin")]);
+        let reviewers = json!([
+            {"user": {"name": "carol"}, "status": "APPROVED"},
+            {"user": {"name": "erin"}, "status": "UNAPPROVED"},
+        ]);
+
+        let pr = test_fixtures::pr(1, json!({"reviewers": reviewers}));
+        assert_eq!(
+            blockers(&api, &pr, ".github/CODEOWNERS", &groups)
+                .await
+                .unwrap(),
+            vec![
+                Blocker::MissingCodeOwnerApproval {
+                    owners: vec![String::from("@alice")],
+                    // Moving a file away counts as a change to its old path
+                    paths: vec![String::from("README.md")],
+                },
+                Blocker::MissingCodeOwnerApproval {
+                    owners: vec![String::from("@@rustaceans")],
+                    paths: vec![String::from("src/main.rs")],
+                },
+            ]
+        );
+        let request = requests.recv().await.unwrap();
+        assert!(
+            request.path.contains("at=refs%2Fheads%2Fmain"),
+            "{}",
+            request.path
+        );
+        let request = requests.recv().await.unwrap();
+        assert!(request.path.contains("/changes"), "{}", request.path);
+
+        // Without a CODEOWNERS file, the changes aren't even fetched
+        let pr = test_fixtures::pr(
+            2,
+            json!({"toRef": test_fixtures::to_ref("lib", "refs/heads/main")}),
+        );
+        assert!(blockers(&api, &pr, ".github/CODEOWNERS", &groups)
+            .await
+            .unwrap()
+            .is_empty());
+        let request = requests.recv().await.unwrap();
+        assert!(request
+            .path
+            .starts_with("/rest/api/1.0/projects/PROJ/repos/lib/raw/"));
+        assert!(requests.try_recv().is_err());
+    }
+}
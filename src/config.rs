@@ -1,34 +1,116 @@
+#[cfg(feature = "ci")]
+use crate::backoff::{BackoffSchedule, RetryRule};
+use crate::bitbucket::PageOptions;
+#[cfg(feature = "buildkite")]
+use crate::buildkite::BuildkiteConfig;
+#[cfg(feature = "email")]
+use crate::email::{EmailConfig, EmailNotifier};
+#[cfg(feature = "github")]
+use crate::github_actions::GithubConfig;
+#[cfg(feature = "gitlab")]
+use crate::gitlab::GitlabConfig;
 #[cfg(feature = "jenkins")]
 use crate::jenkins;
+use crate::logging::LogFormat;
+use crate::merge_checks::ReviewerRules;
+use crate::notifier::SinkConfig;
+use crate::rate_limit;
+use crate::search::RepositoryPattern;
+#[cfg(feature = "teamcity")]
+use crate::teamcity::TeamcityConfig;
 
 use anyhow::{anyhow, Context, Result};
 use cfg_if::cfg_if;
 use config::{Environment, File, FileFormat};
+use log::LevelFilter;
 use regex::Regex;
 use regex::RegexBuilder;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::Path;
+#[cfg(feature = "email")]
+use std::sync::Arc;
+use url::Url;
 
-#[cfg(feature = "jenkins")]
-// Need to use an unsigned type because of limitation of config crate
-// https://github.com/mehcode/config-rs/issues/352
-const DEFAULT_JENKINS_RETRY_LIMIT: i32 = 10;
+#[cfg(feature = "ci")]
+const DEFAULT_CI_RETRY_LIMIT: u32 = 10;
+
+const DEFAULT_MAX_CONCURRENT_PRS: i32 = 8;
+
+/// Flavor of the async runtime that PR's are checked on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeFlavor {
+    /// Run all tasks on the main thread
+    CurrentThread,
+    /// Run tasks on a pool of worker threads
+    MultiThread,
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub bitbucket_url: String,
     pub bitbucket_api_token: String,
+    /// Failed builds to retry. The first rule whose regex matches a build's name applies.
+    #[cfg(feature = "ci")]
+    pub retry_rules: Vec<RetryRule>,
+    #[cfg(feature = "ci")]
+    pub retry_limit: u32,
+    /// Jenkins servers that failed builds can be rebuilt on, with their credentials
     #[cfg(feature = "jenkins")]
-    pub jenkins_auth: Option<jenkins::Auth>,
+    pub jenkins_servers: jenkins::Servers,
+    /// Restart only the failed stage of declarative pipelines instead of rebuilding them
     #[cfg(feature = "jenkins")]
-    pub jenkins_retry_regex: Option<Regex>,
+    pub jenkins_restart_failed_stage: bool,
+    /// Only retry builds that failed because of these. If empty, all failures are retried.
     #[cfg(feature = "jenkins")]
-    pub jenkins_retry_limit: u32,
+    pub jenkins_flaky_failures: jenkins::FlakyFailures,
+    #[cfg(feature = "github")]
+    pub github: Option<GithubConfig>,
+    #[cfg(feature = "gitlab")]
+    pub gitlab: Option<GitlabConfig>,
+    #[cfg(feature = "teamcity")]
+    pub teamcity: Option<TeamcityConfig>,
+    #[cfg(feature = "buildkite")]
+    pub buildkite: Option<BuildkiteConfig>,
     pub check_description: bool,
     pub check_comments: bool,
     pub check_own_prs: bool,
     pub check_approved_prs: bool,
+    pub check_reviewer_prs: bool,
+    pub check_participant_prs: bool,
+    pub check_mentioned_prs: bool,
     pub merge_regex: Regex,
+    pub reviewer_rules: ReviewerRules,
+    pub block_on_draft: bool,
+    pub block_on_open_tasks: bool,
+    pub block_on_blocker_comments: bool,
+    pub reviewer_groups: HashMap<String, Vec<String>>,
+    pub check_codeowners: bool,
+    pub codeowners_path: String,
+    pub check_depends_on: bool,
+    pub check_stacked_branches: bool,
+    pub repositories: Vec<RepositoryPattern>,
+    pub page_options: PageOptions,
+    pub max_concurrent_prs: usize,
+    pub requests_per_second: Option<f64>,
+    pub runtime: RuntimeFlavor,
+    pub worker_threads: Option<usize>,
+    pub summary_json: bool,
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    pub notifiers: Vec<SinkConfig>,
+    /// Built when the configuration is loaded, so that a bad SMTP setup fails the load instead of
+    /// every run
+    #[cfg(feature = "email")]
+    pub email: Option<Arc<EmailNotifier>>,
+    #[cfg(feature = "notify")]
+    pub desktop_notifications: bool,
+    /// Seconds to wait between runs. If unset, crabby-merge runs once and exits.
+    pub poll_interval_secs: Option<u64>,
+    /// Port to serve Prometheus metrics on
+    #[cfg(feature = "metrics")]
+    pub metrics_port: Option<u16>,
 }
 
 impl Config {
@@ -37,19 +119,101 @@ impl Config {
         struct Options {
             bitbucket_url: String,
             bitbucket_api_token: String,
+            // Named after Jenkins because it was the first CI server supported
+            #[cfg(feature = "ci")]
+            #[serde(alias = "jenkins_retry_trigger")]
+            ci_retry_trigger: Option<String>,
+            #[cfg(feature = "ci")]
+            #[serde(alias = "jenkins_retry_limit")]
+            ci_retry_limit: Option<u32>,
+            #[cfg(feature = "ci")]
+            #[serde(default)]
+            ci_backoff: BackoffSchedule,
+            #[cfg(feature = "ci")]
+            #[serde(default)]
+            ci_retry_rules: Vec<RetryRuleOptions>,
             #[cfg(feature = "jenkins")]
             jenkins_username: Option<String>,
             #[cfg(feature = "jenkins")]
             jenkins_password: Option<String>,
             #[cfg(feature = "jenkins")]
-            jenkins_retry_trigger: Option<String>,
+            jenkins_url: Option<String>,
+            #[cfg(feature = "jenkins")]
+            #[serde(default)]
+            jenkins_servers: HashMap<String, JenkinsServerOptions>,
+            #[cfg(feature = "jenkins")]
+            jenkins_restart_failed_stage: bool,
+            #[cfg(feature = "jenkins")]
+            #[serde(default)]
+            jenkins_flaky_tests: Vec<String>,
             #[cfg(feature = "jenkins")]
-            jenkins_retry_limit: u32,
+            #[serde(default)]
+            jenkins_flaky_log_patterns: Vec<String>,
+            #[cfg(feature = "github")]
+            github: Option<GithubConfig>,
+            #[cfg(feature = "gitlab")]
+            gitlab: Option<GitlabConfig>,
+            #[cfg(feature = "teamcity")]
+            teamcity: Option<TeamcityConfig>,
+            #[cfg(feature = "buildkite")]
+            buildkite: Option<BuildkiteConfig>,
             merge_trigger: String,
             check_description: bool,
             check_comments: bool,
             check_own_prs: bool,
             check_approved_prs: bool,
+            check_reviewer_prs: bool,
+            check_participant_prs: bool,
+            check_mentioned_prs: bool,
+            min_approvals: u32,
+            block_on_needs_work: bool,
+            #[serde(default)]
+            required_reviewers: Vec<String>,
+            #[serde(default)]
+            reviewer_groups: HashMap<String, Vec<String>>,
+            #[serde(default)]
+            required_groups: Vec<String>,
+            block_on_draft: bool,
+            block_on_open_tasks: bool,
+            block_on_blocker_comments: bool,
+            check_codeowners: bool,
+            codeowners_path: String,
+            check_depends_on: bool,
+            check_stacked_branches: bool,
+            #[serde(default)]
+            repositories: Vec<String>,
+            page_limit: Option<u32>,
+            max_pages: Option<u32>,
+            max_concurrent_prs: usize,
+            requests_per_second: Option<f64>,
+            runtime: RuntimeFlavor,
+            worker_threads: Option<usize>,
+            summary_json: bool,
+            log_level: String,
+            log_format: LogFormat,
+            #[serde(default)]
+            notifiers: Vec<SinkConfig>,
+            #[cfg(feature = "email")]
+            email: Option<EmailConfig>,
+            #[cfg(feature = "notify")]
+            desktop_notifications: bool,
+            poll_interval_secs: Option<u64>,
+            #[cfg(feature = "metrics")]
+            metrics_port: Option<u16>,
+        }
+
+        #[cfg(feature = "jenkins")]
+        #[derive(Debug, Deserialize)]
+        struct JenkinsServerOptions {
+            username: String,
+            password: String,
+        }
+
+        #[cfg(feature = "ci")]
+        #[derive(Debug, Deserialize)]
+        struct RetryRuleOptions {
+            trigger: String,
+            backoff: Option<BackoffSchedule>,
         }
 
         let mut config_path =
@@ -67,13 +231,32 @@ impl Config {
             .set_default("check_description", true)?
             .set_default("check_comments", false)?
             .set_default("check_own_prs", true)?
-            .set_default("check_approved_prs", false)?;
+            .set_default("check_approved_prs", false)?
+            .set_default("check_reviewer_prs", false)?
+            .set_default("check_participant_prs", false)?
+            .set_default("check_mentioned_prs", false)?
+            .set_default("min_approvals", 0)?
+            .set_default("block_on_needs_work", false)?
+            .set_default("block_on_draft", true)?
+            .set_default("block_on_open_tasks", true)?
+            .set_default("block_on_blocker_comments", false)?
+            .set_default("check_codeowners", false)?
+            .set_default("codeowners_path", "CODEOWNERS")?
+            .set_default("check_depends_on", true)?
+            .set_default("check_stacked_branches", false)?
+            .set_default("max_concurrent_prs", DEFAULT_MAX_CONCURRENT_PRS)?
+            .set_default("runtime", "current_thread")?
+            .set_default("summary_json", false)?
+            .set_default("log_level", "info")?
+            .set_default("log_format", "text")?;
         cfg_if! {
             if #[cfg(feature = "jenkins")] {
                 let config_builder =
-                    config_builder.set_default("jenkins_retry_limit", DEFAULT_JENKINS_RETRY_LIMIT)?;
+                    config_builder.set_default("jenkins_restart_failed_stage", false)?;
             }
         }
+        #[cfg(feature = "notify")]
+        let config_builder = config_builder.set_default("desktop_notifications", true)?;
 
         let config: Options = config_builder
             .build()
@@ -89,35 +272,178 @@ impl Config {
                     .try_deserialize()
                     .map_err(|_| anyhow!("failed to load config"))
             })?;
+        cfg_if! {
+            if #[cfg(feature = "ci")] {
+                config.ci_backoff.validate()?;
+                let mut retry_rules = config
+                    .ci_retry_rules
+                    .iter()
+                    .map(|rule| {
+                        let backoff = rule.backoff.unwrap_or(config.ci_backoff);
+                        backoff.validate()?;
+                        Ok(RetryRule {
+                            regex: Regex::new(&rule.trigger)
+                                .with_context(|| format!("Bad regex: {}", rule.trigger))?,
+                            backoff,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                if let Some(trigger) = &config.ci_retry_trigger {
+                    retry_rules.push(RetryRule {
+                        regex: Regex::new(trigger)?,
+                        backoff: config.ci_backoff,
+                    });
+                }
+            }
+        }
         cfg_if! {
             if #[cfg(feature = "jenkins")] {
-                let retry_regex = match &config.jenkins_retry_trigger {
-                    Some(trigger) => Some(Regex::new(trigger)?),
-                    None => None,
+                let regexes = |patterns: &[String]| {
+                    patterns
+                        .iter()
+                        .map(|pattern| {
+                            Regex::new(pattern).with_context(|| format!("Bad regex: {}", pattern))
+                        })
+                        .collect::<Result<Vec<_>>>()
+                };
+                let flaky_failures = jenkins::FlakyFailures {
+                    tests: regexes(&config.jenkins_flaky_tests)?,
+                    log_patterns: regexes(&config.jenkins_flaky_log_patterns)?,
                 };
+                let mut jenkins_servers = jenkins::Servers::default();
+                for (pattern, server) in &config.jenkins_servers {
+                    let credentials =
+                        jenkins::Auth::new(server.username.clone(), server.password.clone());
+                    jenkins_servers.add(pattern, credentials)?;
+                }
+                if let (Some(username), Some(password)) =
+                    (&config.jenkins_username, &config.jenkins_password)
+                {
+                    // The credentials would otherwise be sent to any URL a build status points at
+                    let url = config.jenkins_url.as_ref().ok_or_else(|| {
+                        anyhow!("jenkins_username and jenkins_password require jenkins_url")
+                    })?;
+                    let credentials = jenkins::Auth::new(username.clone(), password.clone());
+                    jenkins_servers.add(url, credentials)?;
+                }
             }
         }
         let merge_regex = RegexBuilder::new(&config.merge_trigger)
             .multi_line(true)
             .build()
             .with_context(|| format!("Bad regex: {}", config.merge_trigger))?;
+        let required_groups = config
+            .required_groups
+            .into_iter()
+            .map(|group| {
+                let members =
+                    config.reviewer_groups.get(&group).cloned().ok_or_else(|| {
+                        anyhow!("Required group {} is not in reviewer_groups", group)
+                    })?;
+                Ok((group, members))
+            })
+            .collect::<Result<_>>()?;
+        if config.max_concurrent_prs == 0 {
+            return Err(anyhow!("max_concurrent_prs must be at least 1"));
+        }
+        if config
+            .requests_per_second
+            .is_some_and(|rps| !rps.is_finite() || rps < rate_limit::MIN_REQUESTS_PER_SECOND)
+        {
+            return Err(anyhow!(
+                "requests_per_second must be at least {}",
+                rate_limit::MIN_REQUESTS_PER_SECOND
+            ));
+        }
+        if config.worker_threads == Some(0) {
+            return Err(anyhow!("worker_threads must be at least 1"));
+        }
+        let log_level: LevelFilter = config
+            .log_level
+            .parse()
+            .map_err(|_| anyhow!("Invalid log_level: {}", config.log_level))?;
+        for notifier in &config.notifiers {
+            Url::parse(&notifier.url)
+                .with_context(|| format!("Invalid notifier URL: {}", notifier.url))?;
+        }
+        #[cfg(feature = "email")]
+        let email = config
+            .email
+            .as_ref()
+            .map(|email| EmailNotifier::new(email).map(Arc::new))
+            .transpose()
+            .context("Invalid email configuration")?;
+        if config.poll_interval_secs == Some(0) {
+            return Err(anyhow!("poll_interval_secs must be at least 1"));
+        }
+        let repositories = config
+            .repositories
+            .iter()
+            .map(|repo| repo.parse())
+            .collect::<Result<_>>()?;
         Ok(Self {
             bitbucket_url: config.bitbucket_url,
             bitbucket_api_token: config.bitbucket_api_token,
+            #[cfg(feature = "ci")]
+            retry_rules,
+            #[cfg(feature = "ci")]
+            retry_limit: config.ci_retry_limit.unwrap_or(DEFAULT_CI_RETRY_LIMIT),
             #[cfg(feature = "jenkins")]
-            jenkins_auth: match (config.jenkins_username, config.jenkins_password) {
-                (Some(username), Some(password)) => Some(jenkins::Auth::new(username, password)),
-                _ => None,
-            },
+            jenkins_servers,
             #[cfg(feature = "jenkins")]
-            jenkins_retry_regex: retry_regex,
+            jenkins_restart_failed_stage: config.jenkins_restart_failed_stage,
             #[cfg(feature = "jenkins")]
-            jenkins_retry_limit: config.jenkins_retry_limit,
+            jenkins_flaky_failures: flaky_failures,
+            #[cfg(feature = "github")]
+            github: config.github,
+            #[cfg(feature = "gitlab")]
+            gitlab: config.gitlab,
+            #[cfg(feature = "teamcity")]
+            teamcity: config.teamcity,
+            #[cfg(feature = "buildkite")]
+            buildkite: config.buildkite,
             check_comments: config.check_comments,
             check_description: config.check_description,
             check_own_prs: config.check_own_prs,
             check_approved_prs: config.check_approved_prs,
+            check_reviewer_prs: config.check_reviewer_prs,
+            check_participant_prs: config.check_participant_prs,
+            check_mentioned_prs: config.check_mentioned_prs,
             merge_regex,
+            reviewer_rules: ReviewerRules {
+                min_approvals: config.min_approvals,
+                block_on_needs_work: config.block_on_needs_work,
+                required_reviewers: config.required_reviewers,
+                required_groups,
+            },
+            block_on_draft: config.block_on_draft,
+            block_on_open_tasks: config.block_on_open_tasks,
+            block_on_blocker_comments: config.block_on_blocker_comments,
+            reviewer_groups: config.reviewer_groups,
+            check_codeowners: config.check_codeowners,
+            codeowners_path: config.codeowners_path,
+            check_depends_on: config.check_depends_on,
+            check_stacked_branches: config.check_stacked_branches,
+            repositories,
+            page_options: PageOptions {
+                limit: config.page_limit,
+                max_pages: config.max_pages,
+            },
+            max_concurrent_prs: config.max_concurrent_prs,
+            requests_per_second: config.requests_per_second,
+            runtime: config.runtime,
+            worker_threads: config.worker_threads,
+            summary_json: config.summary_json,
+            log_level,
+            log_format: config.log_format,
+            notifiers: config.notifiers,
+            #[cfg(feature = "email")]
+            email,
+            #[cfg(feature = "notify")]
+            desktop_notifications: config.desktop_notifications,
+            poll_interval_secs: config.poll_interval_secs,
+            #[cfg(feature = "metrics")]
+            metrics_port: config.metrics_port,
         })
     }
 }
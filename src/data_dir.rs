@@ -0,0 +1,61 @@
+//! Location of the state that crabby-merge keeps between runs
+
+#[cfg(not(test))]
+use directories::ProjectDirs;
+use once_cell::sync::Lazy;
+use std::path::PathBuf;
+
+static CRATE_NAME: &str = "crabby-merge";
+#[cfg(not(test))]
+pub static DATA_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    let dir = ProjectDirs::from("", "", CRATE_NAME)
+        .expect("Could not get project directory")
+        .data_dir()
+        .to_path_buf();
+    std::fs::create_dir_all(&dir).ok();
+    dir
+});
+#[cfg(test)]
+pub static DATA_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    // Create a directory in the temp directory
+    // TODO: figure out how to remove the directory afterward
+    let temp_dir = Box::leak(Box::new(
+        tempdir::TempDir::new(CRATE_NAME).expect("Could not get project directory"),
+    ));
+    temp_dir.path().to_path_buf()
+});
+
+/// Returns the path of a state file in a subdirectory of the data directory, replacing characters
+/// that aren't safe in filenames
+pub fn state_file(subdir: &str, name: &str) -> PathBuf {
+    let dir = DATA_DIR.join(subdir);
+    std::fs::create_dir_all(&dir).ok();
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.@~".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    dir.join(name)
+}
+
+/// Removes files in a subdirectory of the data directory that haven't been modified for `max_age`
+pub fn remove_stale_files(subdir: &str, max_age: time::Duration) -> anyhow::Result<()> {
+    let now = std::time::SystemTime::now();
+    for entry in std::fs::read_dir(DATA_DIR.join(subdir))?.flatten() {
+        let stale = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .is_some_and(|age| age >= max_age);
+        if stale {
+            std::fs::remove_file(entry.path()).ok();
+        }
+    }
+    Ok(())
+}
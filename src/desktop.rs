@@ -0,0 +1,165 @@
+#![cfg(feature = "notify")]
+//! Desktop notifications about the user's own pull requests
+//!
+//! A notification is shown when one of the user's pull requests is merged, or is blocked in a way
+//! that won't resolve itself. The last block notified about for each pull request is stored in the
+//! data directory so that the same notification isn't shown on every run.
+
+use crate::bitbucket::PullRequest;
+use crate::data_dir;
+use crate::search::{MergeFailure, Outcome};
+
+use anyhow::Result;
+use log::*;
+use notify_rust::Notification;
+use time::Duration;
+
+const STATE_DIR: &str = "desktop";
+/// State files that haven't been updated for this long belong to pull requests that are gone
+const STALENESS_THRESHOLD: Duration = Duration::days(5);
+
+/// Returns why a pull request can't be merged, if it won't be merged without someone fixing it
+fn permanent_block_reason(outcome: &Outcome) -> Option<String> {
+    let permanent = match outcome {
+        Outcome::Blocked(_) => true,
+        Outcome::MergeFailed {
+            reason: MergeFailure::Conflicts,
+            rebuilds,
+            ..
+        } if rebuilds.is_empty() => true,
+        // Builds that won't be retried again need someone to look at them
+        Outcome::MergeFailed {
+            exhausted_builds, ..
+        } => !exhausted_builds.is_empty(),
+        // Anything else, like a build that is still running or was just rebuilt, may resolve itself
+        _ => false,
+    };
+    permanent.then(|| outcome.block_reason()).flatten()
+}
+
+/// Returns the summary and body of the notification to show for the outcome of checking a pull
+/// request, if any
+fn notification(pr: &PullRequest, outcome: &Outcome) -> Option<(String, String)> {
+    let url = pr.url().unwrap_or_default();
+    let link = format!("<a href=\"{}\">{}</a>", url, url);
+    match outcome {
+        Outcome::Merged => Some((format!("Merged: {}", pr.title), link)),
+        _ => permanent_block_reason(outcome).map(|reason| {
+            (
+                format!("Merge blocked: {}", pr.title),
+                format!("{}\n{}", reason, link),
+            )
+        }),
+    }
+}
+
+/// Shows desktop notifications for pull requests authored by a user
+#[derive(Debug)]
+pub struct DesktopNotifier {
+    username: String,
+}
+
+impl DesktopNotifier {
+    pub fn new(username: &str) -> Self {
+        Self {
+            username: username.to_owned(),
+        }
+    }
+
+    /// Shows a notification for the outcome of checking a pull request, if it is one of the user's
+    /// and it wasn't already shown
+    pub async fn notify_outcome(&self, pr: &PullRequest, outcome: &Outcome) {
+        if pr.author() != Some(self.username.as_str()) {
+            return;
+        }
+        let Some(key) = pr.key() else {
+            return;
+        };
+        let state_path = data_dir::state_file(STATE_DIR, &key);
+        if matches!(outcome, Outcome::Merged | Outcome::NoTrigger) {
+            std::fs::remove_file(&state_path).ok();
+        }
+        let Some((summary, body)) = notification(pr, outcome) else {
+            return;
+        };
+        if !matches!(outcome, Outcome::Merged) {
+            let shown = std::fs::read_to_string(&state_path).is_ok_and(|shown| shown == body);
+            // Saving on every run also keeps the file from being cleaned up as stale
+            std::fs::write(&state_path, &body).ok();
+            if shown {
+                return;
+            }
+        }
+        let result = Notification::new()
+            .appname("crabby-merge")
+            .summary(&summary)
+            .body(&body)
+            .show_async()
+            .await;
+        if let Err(e) = result {
+            warn!("Could not show desktop notification: {}", e);
+        }
+    }
+}
+
+/// Clean out notification state that hasn't been updated for `STALENESS_THRESHOLD`
+pub fn decruft() -> Result<()> {
+    data_dir::remove_stale_files(STATE_DIR, STALENESS_THRESHOLD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge_checks::Blocker;
+    use crate::test_fixtures;
+    use serde_json::json;
+
+    #[test]
+    fn notifications() {
+        let pr = test_fixtures::pr(1, json!({"title": "Fix the thing"}));
+        let link = "<a href=\"https://bitbucket/pr/1\">https://bitbucket/pr/1</a>";
+        assert_eq!(
+            notification(&pr, &Outcome::Merged),
+            Some((String::from("Merged: Fix the thing"), String::from(link)))
+        );
+        assert_eq!(
+            notification(&pr, &Outcome::Blocked(vec![Blocker::Draft])),
+            Some((
+                String::from("Merge blocked: Fix the thing"),
+                format!("pull request is a draft\n{}", link)
+            ))
+        );
+        let conflicts = Outcome::MergeFailed {
+            error: String::from("PR not ready to merge: conflicts"),
+            reason: MergeFailure::Conflicts,
+            rebuilds: Vec::new(),
+            exhausted_builds: Vec::new(),
+        };
+        assert!(notification(&pr, &conflicts).is_some());
+        let building = Outcome::MergeFailed {
+            error: String::from("PR not ready to merge: builds in progress"),
+            reason: MergeFailure::Vetoed,
+            rebuilds: Vec::new(),
+            exhausted_builds: Vec::new(),
+        };
+        assert_eq!(notification(&pr, &building), None);
+        let exhausted = Outcome::MergeFailed {
+            error: String::from("PR not ready to merge: build failed"),
+            reason: MergeFailure::Vetoed,
+            rebuilds: Vec::new(),
+            exhausted_builds: vec![String::from("unit tests")],
+        };
+        assert_eq!(
+            notification(&pr, &exhausted),
+            Some((
+                String::from("Merge blocked: Fix the thing"),
+                format!(
+                    "PR not ready to merge: build failed; retried as many times as allowed: unit \
+                     tests\n{}",
+                    link
+                )
+            ))
+        );
+        assert_eq!(notification(&pr, &Outcome::NoTrigger), None);
+    }
+}
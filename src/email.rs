@@ -0,0 +1,475 @@
+#![cfg(feature = "email")]
+//! Emails to the authors of pull requests that have been blocked from merging for a long time
+//!
+//! Whether an author has already been emailed is stored in the data directory so that they aren't
+//! emailed again on every run.
+
+use crate::bitbucket::PullRequest;
+use crate::data_dir;
+use crate::search::Outcome;
+
+use anyhow::{Context, Result};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time::{Duration, OffsetDateTime};
+
+/// State files that haven't been updated for this long belong to pull requests that are gone
+const STALENESS_THRESHOLD: Duration = Duration::days(5);
+/// Minimum time between digests sent to the same person
+const DIGEST_INTERVAL: Duration = Duration::days(1);
+const DEFAULT_BLOCKED_HOURS: u64 = 24;
+
+/// How to secure the connection to the SMTP server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsMode {
+    /// Connect over TLS, on port 465 by default
+    Tls,
+    /// Upgrade the connection with STARTTLS, on port 587 by default
+    #[default]
+    Starttls,
+    /// Don't encrypt the connection, on port 25 by default
+    None,
+}
+
+/// SMTP server and email settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmailConfig {
+    pub smtp_host: String,
+    /// Defaults to the standard port for `tls`
+    pub smtp_port: Option<u16>,
+    #[serde(default)]
+    pub tls: TlsMode,
+    /// Whether to accept invalid TLS certificates, e.g. self-signed ones
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender address, e.g. `crabby-merge <crabby-merge@example.com>`
+    pub from: String,
+    /// Number of hours a pull request must be blocked before its author is emailed
+    #[serde(default = "default_blocked_hours")]
+    pub blocked_hours: u64,
+    /// Whether to send each author a daily digest of all their blocked pull requests instead of
+    /// one email per pull request
+    #[serde(default)]
+    pub digest: bool,
+}
+
+fn default_blocked_hours() -> u64 {
+    DEFAULT_BLOCKED_HOURS
+}
+
+impl EmailConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.from
+            .parse::<Mailbox>()
+            .with_context(|| format!("Invalid email sender: {}", self.from))?;
+        Ok(())
+    }
+}
+
+/// Since when a pull request has been blocked and whether its author knows
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+struct BlockedState {
+    blocked_since: OffsetDateTime,
+    notified: bool,
+}
+
+/// When a digest was last sent to someone
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+struct DigestState {
+    last_sent: OffsetDateTime,
+}
+
+fn state_path(prefix: &str, key: &str) -> PathBuf {
+    data_dir::state_file("email", &format!("{}-{}", prefix, key))
+}
+
+fn load<T: for<'de> Deserialize<'de>>(path: &Path) -> Option<T> {
+    let buf = std::fs::read(path).ok()?;
+    serde_json::from_slice(&buf).ok()
+}
+
+fn save<T: Serialize>(path: &Path, state: &T) {
+    let result = serde_json::to_vec(state)
+        .map_err(anyhow::Error::from)
+        .and_then(|buf| Ok(std::fs::write(path, buf)?));
+    if let Err(e) = result {
+        warn!("Could not save {}: {:#}", path.display(), e);
+    }
+}
+
+/// A pull request whose author should be emailed
+#[derive(Debug, Clone)]
+struct BlockedPr {
+    recipient: String,
+    url: String,
+    id: u32,
+    title: String,
+    reason: String,
+    state: BlockedState,
+    state_path: PathBuf,
+}
+
+impl BlockedPr {
+    fn describe(&self, now: OffsetDateTime) -> String {
+        format!(
+            "{}\n{}\nBlocked for {} hours: {}\n",
+            self.title,
+            self.url,
+            (now - self.state.blocked_since).whole_hours(),
+            self.reason
+        )
+    }
+}
+
+/// Emails the authors of pull requests that have been blocked for longer than a threshold, or
+/// whose failed builds won't be retried again
+pub struct EmailNotifier {
+    config: EmailConfig,
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    /// Pull requests to email about at the end of the run
+    pending: Mutex<Vec<BlockedPr>>,
+}
+
+impl fmt::Debug for EmailNotifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmailNotifier")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EmailNotifier {
+    pub fn new(config: &EmailConfig) -> Result<Self> {
+        config.validate()?;
+        let host = config.smtp_host.as_str();
+        let tls_parameters = || {
+            TlsParameters::builder(host.to_owned())
+                .dangerous_accept_invalid_certs(config.accept_invalid_certs)
+                .build()
+        };
+        let (tls, default_port) = match config.tls {
+            TlsMode::Tls => (Tls::Wrapper(tls_parameters()?), 465),
+            TlsMode::Starttls => (Tls::Required(tls_parameters()?), 587),
+            TlsMode::None => (Tls::None, 25),
+        };
+        let mut transport = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+            .port(config.smtp_port.unwrap_or(default_port))
+            .tls(tls);
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(Self {
+            config: config.clone(),
+            from: config.from.parse()?,
+            transport: transport.build(),
+            pending: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Updates the blocked state of a pull request after checking it
+    pub fn record(&self, pr: &PullRequest, outcome: &Outcome) {
+        let Some(key) = pr.key() else {
+            return;
+        };
+        let state_path = state_path("pr", &key);
+        if matches!(outcome, Outcome::Merged | Outcome::NoTrigger) {
+            std::fs::remove_file(&state_path).ok();
+            return;
+        }
+        let Some(reason) = outcome.block_reason() else {
+            return;
+        };
+
+        let now = OffsetDateTime::now_utc();
+        let state = load(&state_path).unwrap_or(BlockedState {
+            blocked_since: now,
+            notified: false,
+        });
+        // Saving on every run also keeps the file from being cleaned up as stale
+        save(&state_path, &state);
+        // Builds that won't be retried again need someone to look at them now
+        let gave_up = matches!(outcome, Outcome::MergeFailed { exhausted_builds, .. } if !exhausted_builds.is_empty());
+        if !gave_up && now - state.blocked_since < Duration::hours(self.config.blocked_hours as i64)
+        {
+            return;
+        }
+        let Some(recipient) = pr.author_email() else {
+            debug!(
+                "No email address for the author of {}",
+                pr.url().unwrap_or_default()
+            );
+            return;
+        };
+        self.pending.lock().unwrap().push(BlockedPr {
+            recipient: recipient.to_owned(),
+            url: pr.url().unwrap_or_default().to_owned(),
+            id: pr.id(),
+            title: pr.title.clone(),
+            reason,
+            state,
+            state_path,
+        });
+    }
+
+    /// Sends the emails for the pull requests recorded during this run
+    pub async fn flush(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let now = OffsetDateTime::now_utc();
+        if self.config.digest {
+            let mut by_recipient: BTreeMap<String, Vec<BlockedPr>> = BTreeMap::new();
+            for pr in pending {
+                by_recipient
+                    .entry(pr.recipient.clone())
+                    .or_default()
+                    .push(pr);
+            }
+            for (recipient, prs) in by_recipient {
+                let digest_path = state_path("digest", &recipient);
+                let due = load::<DigestState>(&digest_path)
+                    .is_none_or(|digest| now - digest.last_sent >= DIGEST_INTERVAL);
+                if !due {
+                    continue;
+                }
+                let subject = format!("crabby-merge: {} blocked pull request(s)", prs.len());
+                let body: Vec<String> = prs.iter().map(|pr| pr.describe(now)).collect();
+                if self.send(&recipient, subject, body.join("\n")).await {
+                    save(&digest_path, &DigestState { last_sent: now });
+                    for pr in prs {
+                        mark_notified(pr);
+                    }
+                }
+            }
+        } else {
+            for pr in pending.into_iter().filter(|pr| !pr.state.notified) {
+                let subject = format!("Pull request #{} is blocked: {}", pr.id, pr.title);
+                let body = format!(
+                    "crabby-merge can't merge your pull request.\n\n{}",
+                    pr.describe(now)
+                );
+                if self.send(&pr.recipient, subject, body).await {
+                    mark_notified(pr);
+                }
+            }
+        }
+    }
+
+    /// Sends an email, returning whether it was sent
+    async fn send(&self, recipient: &str, subject: String, body: String) -> bool {
+        let message = recipient
+            .parse::<Mailbox>()
+            .map_err(anyhow::Error::from)
+            .and_then(|to| {
+                Ok(Message::builder()
+                    .from(self.from.clone())
+                    .to(to)
+                    .subject(subject)
+                    .body(body)?)
+            });
+        let result = match message {
+            Ok(message) => self.transport.send(message).await.map_err(Into::into),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(_) => {
+                info!("Emailed {}", recipient);
+                true
+            }
+            Err(e) => {
+                warn!("Could not email {}: {:#}", recipient, e);
+                false
+            }
+        }
+    }
+}
+
+fn mark_notified(mut pr: BlockedPr) {
+    pr.state.notified = true;
+    save(&pr.state_path, &pr.state);
+}
+
+/// Clean out email state that hasn't been updated for `STALENESS_THRESHOLD`
+pub fn decruft() -> Result<()> {
+    data_dir::remove_stale_files("email", STALENESS_THRESHOLD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge_checks::Blocker;
+    use crate::search::MergeFailure;
+    use crate::test_fixtures;
+    use serde_json::json;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    fn pr(id: u32, email: &str) -> PullRequest {
+        test_fixtures::pr(
+            id,
+            json!({"author": {"user": {"name": "alice", "emailAddress": email}}}),
+        )
+    }
+
+    /// Accepts SMTP connections on a local port and returns the port and a channel of message data
+    async fn smtp_stand_in() -> (u16, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = socket.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                writer.write_all(b"220 localhost\r\n").await.unwrap();
+                let mut data: Option<String> = None;
+                while let Some(line) = lines.next_line().await.unwrap() {
+                    let reply: &[u8] = match data.as_mut() {
+                        Some(message) if line == "." => {
+                            sender.send(std::mem::take(message)).unwrap();
+                            data = None;
+                            b"250 queued\r\n"
+                        }
+                        Some(message) => {
+                            message.push_str(&line);
+                            message.push('\n');
+                            continue;
+                        }
+                        None if line.starts_with("DATA") => {
+                            data = Some(String::new());
+                            b"354 go ahead\r\n"
+                        }
+                        None if line.starts_with("QUIT") => {
+                            writer.write_all(b"221 bye\r\n").await.unwrap();
+                            break;
+                        }
+                        None => b"250 ok\r\n",
+                    };
+                    writer.write_all(reply).await.unwrap();
+                }
+            }
+        });
+        (port, receiver)
+    }
+
+    fn config(port: u16, digest: bool) -> EmailConfig {
+        EmailConfig {
+            smtp_host: String::from("127.0.0.1"),
+            smtp_port: Some(port),
+            tls: TlsMode::None,
+            accept_invalid_certs: false,
+            username: None,
+            password: None,
+            from: String::from("crabby-merge <crabby@example.com>"),
+            blocked_hours: 0,
+            digest,
+        }
+    }
+
+    fn notifier(port: u16, digest: bool) -> EmailNotifier {
+        EmailNotifier::new(&config(port, digest)).unwrap()
+    }
+
+    #[test]
+    fn invalid_config() {
+        let config = EmailConfig {
+            from: String::from("not an address"),
+            ..config(25, false)
+        };
+        assert!(EmailNotifier::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn exhausted_retries() {
+        let (port, mut messages) = smtp_stand_in().await;
+        let notifier = EmailNotifier::new(&EmailConfig {
+            blocked_hours: 24,
+            ..config(port, false)
+        })
+        .unwrap();
+        let building = Outcome::MergeFailed {
+            error: String::from("PR not ready to merge: build failed"),
+            reason: MergeFailure::Vetoed,
+            rebuilds: Vec::new(),
+            exhausted_builds: Vec::new(),
+        };
+        notifier.record(&pr(4, "carol@example.com"), &building);
+        notifier.flush().await;
+        assert!(messages.try_recv().is_err());
+
+        // Builds that won't be retried again are emailed about right away
+        let exhausted = Outcome::MergeFailed {
+            error: String::from("PR not ready to merge: build failed"),
+            reason: MergeFailure::Vetoed,
+            rebuilds: Vec::new(),
+            exhausted_builds: vec![String::from("unit tests")],
+        };
+        notifier.record(&pr(4, "carol@example.com"), &exhausted);
+        notifier.flush().await;
+        let message = messages.recv().await.unwrap();
+        assert!(message.contains("To: carol@example.com"));
+        assert!(message.contains("allowed: unit tests"));
+    }
+
+    #[tokio::test]
+    async fn emails_once() {
+        let (port, mut messages) = smtp_stand_in().await;
+        let notifier = notifier(port, false);
+        let pr = pr(1, "alice@example.com");
+        let blocked = Outcome::Blocked(vec![Blocker::Draft]);
+
+        notifier.record(&pr, &blocked);
+        notifier.flush().await;
+        let message = messages.recv().await.unwrap();
+        assert!(message.contains("To: alice@example.com"));
+        assert!(message.contains("Subject: Pull request #1 is blocked: Change 1"));
+        assert!(message.contains("pull request is a draft"));
+
+        // Already notified
+        notifier.record(&pr, &blocked);
+        notifier.flush().await;
+
+        // Unblocking resets the state
+        notifier.record(&pr, &Outcome::NoTrigger);
+        notifier.record(&pr, &blocked);
+        notifier.flush().await;
+        assert!(messages.recv().await.unwrap().contains("Change 1"));
+        assert!(messages.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn daily_digest() {
+        let (port, mut messages) = smtp_stand_in().await;
+        let notifier = notifier(port, true);
+        let blocked = Outcome::MergeFailed {
+            error: String::from("PR not ready to merge: conflicts"),
+            reason: MergeFailure::Conflicts,
+            rebuilds: Vec::new(),
+            exhausted_builds: Vec::new(),
+        };
+
+        notifier.record(&pr(2, "bob@example.com"), &blocked);
+        notifier.record(&pr(3, "bob@example.com"), &blocked);
+        notifier.flush().await;
+        let message = messages.recv().await.unwrap();
+        assert!(message.contains("Subject: crabby-merge: 2 blocked pull request(s)"));
+        assert!(message.contains("https://bitbucket/pr/2"));
+        assert!(message.contains("https://bitbucket/pr/3"));
+
+        // Not due again until tomorrow
+        notifier.record(&pr(2, "bob@example.com"), &blocked);
+        notifier.flush().await;
+        assert!(messages.try_recv().is_err());
+    }
+}
@@ -0,0 +1,232 @@
+#![cfg(feature = "github")]
+//! Re-running failed GitHub Actions workflow runs
+
+use crate::ci::{self, CiBackend, QueuedBuild, RebuildState, HTTP_CLIENT};
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::header::ACCEPT;
+use reqwest::{RequestBuilder, StatusCode};
+use serde::Deserialize;
+
+const GITHUB_URL: &str = "https://github.com";
+
+/// GitHub instance and credentials
+#[derive(Debug, Clone, Deserialize)]
+pub struct GithubConfig {
+    /// Token with permission to re-run workflows
+    pub token: String,
+    /// Web URL of the GitHub instance. Defaults to github.com.
+    #[serde(default = "default_url")]
+    pub url: String,
+    /// URL of the REST API. Defaults to `https://api.github.com` for github.com and
+    /// `{url}/api/v3` for GitHub Enterprise Server.
+    pub api_url: Option<String>,
+}
+
+fn default_url() -> String {
+    String::from(GITHUB_URL)
+}
+
+/// A workflow run, identified by its URL
+#[derive(Debug, PartialEq, Eq)]
+struct RunUrl {
+    owner: String,
+    repo: String,
+    id: u64,
+}
+
+/// A workflow run, as returned by the API
+#[derive(Debug, Deserialize)]
+struct WorkflowRun {
+    status: String,
+}
+
+/// GitHub Actions as a CI server to rebuild builds on
+#[derive(Debug)]
+pub struct GithubActions {
+    url: String,
+    api_url: String,
+    token: String,
+}
+
+impl GithubActions {
+    pub fn new(config: GithubConfig) -> Self {
+        let url = config.url.trim_end_matches('/').to_owned();
+        let api_url = match config.api_url {
+            Some(api_url) => api_url.trim_end_matches('/').to_owned(),
+            None if url == GITHUB_URL => String::from("https://api.github.com"),
+            None => format!("{}/api/v3", url),
+        };
+        Self {
+            url,
+            api_url,
+            token: config.token,
+        }
+    }
+
+    /// Parses the URL of a workflow run, or of one of its jobs or attempts
+    fn parse_url(&self, build_url: &str) -> Option<RunUrl> {
+        static PATH_REGEX: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"^/([^/]+)/([^/]+)/actions/runs/(\d+)(?:[/?#].*)?$").unwrap());
+
+        let path = build_url.strip_prefix(&self.url)?;
+        let captures = PATH_REGEX.captures(path)?;
+        Some(RunUrl {
+            owner: captures[1].to_owned(),
+            repo: captures[2].to_owned(),
+            id: captures[3].parse().ok()?,
+        })
+    }
+
+    fn run_api_url(&self, run: &RunUrl) -> String {
+        format!(
+            "{}/repos/{}/{}/actions/runs/{}",
+            self.api_url, run.owner, run.repo, run.id
+        )
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        request
+            .bearer_auth(&self.token)
+            .header(ACCEPT, "application/vnd.github+json")
+    }
+}
+
+impl CiBackend for GithubActions {
+    fn name(&self) -> &'static str {
+        "GitHub Actions"
+    }
+
+    fn recognizes(&self, build_url: &str) -> bool {
+        self.parse_url(build_url).is_some()
+    }
+
+    fn rebuild_state<'a>(
+        &'a self,
+        rebuild: &'a mut QueuedBuild,
+    ) -> BoxFuture<'a, Result<Option<RebuildState>>> {
+        Box::pin(async move {
+            let Some(run) = rebuild.url.as_deref().and_then(|url| self.parse_url(url)) else {
+                return Ok(None);
+            };
+            let request = self.authorize(HTTP_CLIENT.get(self.run_api_url(&run)));
+            let response = request.send().await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let run: WorkflowRun = ci::json_response(response, "Fetching workflow run").await?;
+            Ok(match run.status.as_str() {
+                "in_progress" => Some(RebuildState::Running),
+                "completed" => None,
+                // queued, requested, waiting or pending
+                _ => Some(RebuildState::Queued),
+            })
+        })
+    }
+
+    fn rebuild<'a>(&'a self, build_url: &'a str) -> BoxFuture<'a, Result<QueuedBuild>> {
+        Box::pin(async move {
+            let run = self
+                .parse_url(build_url)
+                .ok_or_else(|| anyhow!("Invalid URL: {}", build_url))?;
+            // Re-running only the failed jobs keeps the results of the jobs that passed
+            let url = format!("{}/rerun-failed-jobs", self.run_api_url(&run));
+            let response = self.authorize(HTTP_CLIENT.post(url)).send().await?;
+            let status = response.status();
+            if !status.is_success() {
+                return Err(anyhow!(
+                    "Re-run returned {}: {}",
+                    status,
+                    response.text().await.unwrap_or_default()
+                ));
+            }
+            // Re-runs are new attempts of the same run
+            Ok(QueuedBuild {
+                queue_url: None,
+                url: Some(format!(
+                    "{}/{}/{}/actions/runs/{}",
+                    self.url, run.owner, run.repo, run.id
+                )),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Response};
+    use serde_json::json;
+
+    fn backend(url: &str, api_url: Option<String>) -> GithubActions {
+        GithubActions::new(GithubConfig {
+            token: String::from("secret"),
+            url: String::from(url),
+            api_url,
+        })
+    }
+
+    #[test]
+    fn parses_urls() {
+        let github = backend(GITHUB_URL, None);
+        assert_eq!(github.api_url, "https://api.github.com");
+        let run = RunUrl {
+            owner: String::from("octo"),
+            repo: String::from("app"),
+            id: 42,
+        };
+        for url in [
+            "https://github.com/octo/app/actions/runs/42",
+            "https://github.com/octo/app/actions/runs/42/job/7",
+            "https://github.com/octo/app/actions/runs/42/attempts/2",
+        ] {
+            assert_eq!(github.parse_url(url).as_ref(), Some(&run), "{}", url);
+        }
+        assert!(!github.recognizes("https://github.com/octo/app/pull/1"));
+        assert!(!github.recognizes("https://ghe.example.com/octo/app/actions/runs/42"));
+
+        let enterprise = backend("https://ghe.example.com/", None);
+        assert_eq!(enterprise.api_url, "https://ghe.example.com/api/v3");
+        assert!(enterprise.recognizes("https://ghe.example.com/octo/app/actions/runs/42"));
+    }
+
+    #[tokio::test]
+    async fn reruns_failed_jobs() {
+        let (base_url, mut requests) = test_server::serve(|request| {
+            if request.header("authorization") != Some("Bearer secret") {
+                return Response::status(401);
+            }
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/api/v3/repos/octo/app/actions/runs/42/rerun-failed-jobs") => {
+                    Response::status(201)
+                }
+                ("GET", "/api/v3/repos/octo/app/actions/runs/42") => {
+                    Response::json(json!({"status": "in_progress"}))
+                }
+                _ => Response::status(404),
+            }
+        })
+        .await;
+        let github = backend(&base_url, None);
+        let build_url = format!("{}/octo/app/actions/runs/42/job/7", base_url);
+        let mut rebuild = github.rebuild(&build_url).await.unwrap();
+        assert_eq!(
+            rebuild.url,
+            Some(format!("{}/octo/app/actions/runs/42", base_url))
+        );
+        assert_eq!(
+            github.rebuild_state(&mut rebuild).await.unwrap(),
+            Some(RebuildState::Running)
+        );
+        assert_eq!(requests.recv().await.unwrap().method, "POST");
+
+        let mut gone = QueuedBuild {
+            queue_url: None,
+            url: Some(format!("{}/octo/app/actions/runs/43", base_url)),
+        };
+        assert_eq!(github.rebuild_state(&mut gone).await.unwrap(), None);
+    }
+}
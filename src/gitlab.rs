@@ -0,0 +1,241 @@
+#![cfg(feature = "gitlab")]
+//! Retrying failed GitLab CI pipelines and jobs
+
+use crate::ci::{self, CiBackend, QueuedBuild, RebuildState, HTTP_CLIENT};
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::{RequestBuilder, StatusCode};
+use serde::Deserialize;
+use url::form_urlencoded;
+
+/// GitLab instance and credentials
+#[derive(Debug, Clone, Deserialize)]
+pub struct GitlabConfig {
+    /// URL of the GitLab instance, e.g. `https://gitlab.example.com`
+    pub url: String,
+    /// Access token with the `api` scope
+    pub token: String,
+}
+
+/// What a build URL points at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Pipeline,
+    Job,
+}
+
+/// A pipeline or job, identified by its URL
+#[derive(Debug, PartialEq, Eq)]
+struct BuildUrl {
+    /// Full path of the project, e.g. `group/subgroup/project`
+    project: String,
+    kind: Kind,
+    id: u64,
+}
+
+/// A pipeline or job, as returned by the API
+#[derive(Debug, Deserialize)]
+struct Build {
+    status: String,
+    web_url: String,
+}
+
+/// GitLab CI as a CI server to rebuild builds on
+#[derive(Debug)]
+pub struct Gitlab {
+    url: String,
+    token: String,
+}
+
+impl Gitlab {
+    pub fn new(config: GitlabConfig) -> Self {
+        Self {
+            url: config.url.trim_end_matches('/').to_owned(),
+            token: config.token,
+        }
+    }
+
+    /// Parses the URL of a pipeline or job
+    fn parse_url(&self, build_url: &str) -> Option<BuildUrl> {
+        static PATH_REGEX: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"^/(.+?)/-/(pipelines|jobs)/(\d+)(?:[/?#].*)?$").unwrap());
+
+        let path = build_url.strip_prefix(&self.url)?;
+        let captures = PATH_REGEX.captures(path)?;
+        Some(BuildUrl {
+            project: captures[1].to_owned(),
+            kind: match &captures[2] {
+                "pipelines" => Kind::Pipeline,
+                _ => Kind::Job,
+            },
+            id: captures[3].parse().ok()?,
+        })
+    }
+
+    fn api_url(&self, build: &BuildUrl) -> String {
+        let project: String = form_urlencoded::byte_serialize(build.project.as_bytes()).collect();
+        let kind = match build.kind {
+            Kind::Pipeline => "pipelines",
+            Kind::Job => "jobs",
+        };
+        format!(
+            "{}/api/v4/projects/{}/{}/{}",
+            self.url, project, kind, build.id
+        )
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        request.header("PRIVATE-TOKEN", &self.token)
+    }
+}
+
+impl CiBackend for Gitlab {
+    fn name(&self) -> &'static str {
+        "GitLab"
+    }
+
+    fn recognizes(&self, build_url: &str) -> bool {
+        self.parse_url(build_url).is_some()
+    }
+
+    fn rebuild_state<'a>(
+        &'a self,
+        rebuild: &'a mut QueuedBuild,
+    ) -> BoxFuture<'a, Result<Option<RebuildState>>> {
+        Box::pin(async move {
+            let Some(build) = rebuild.url.as_deref().and_then(|url| self.parse_url(url)) else {
+                return Ok(None);
+            };
+            let response = self
+                .authorize(HTTP_CLIENT.get(self.api_url(&build)))
+                .send()
+                .await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let build: Build = ci::json_response(response, "Fetching build").await?;
+            Ok(match build.status.as_str() {
+                "created" | "waiting_for_resource" | "preparing" | "pending" | "scheduled" => {
+                    Some(RebuildState::Queued)
+                }
+                "running" => Some(RebuildState::Running),
+                // success, failed, canceled, skipped or manual
+                _ => None,
+            })
+        })
+    }
+
+    fn rebuild<'a>(&'a self, build_url: &'a str) -> BoxFuture<'a, Result<QueuedBuild>> {
+        Box::pin(async move {
+            let build = self
+                .parse_url(build_url)
+                .ok_or_else(|| anyhow!("Invalid URL: {}", build_url))?;
+            // Retrying a pipeline retries its failed jobs, and retrying a job creates a new job
+            let url = format!("{}/retry", self.api_url(&build));
+            let response = self.authorize(HTTP_CLIENT.post(url)).send().await?;
+            let retried: Build = ci::json_response(response, "Retry").await?;
+            Ok(QueuedBuild {
+                queue_url: None,
+                url: Some(retried.web_url),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Response};
+    use serde_json::json;
+
+    fn backend(url: &str) -> Gitlab {
+        Gitlab::new(GitlabConfig {
+            url: String::from(url),
+            token: String::from("secret"),
+        })
+    }
+
+    #[test]
+    fn parses_urls() {
+        let gitlab = backend("https://gitlab.example.com/");
+        assert_eq!(
+            gitlab.parse_url("https://gitlab.example.com/group/sub/app/-/pipelines/12"),
+            Some(BuildUrl {
+                project: String::from("group/sub/app"),
+                kind: Kind::Pipeline,
+                id: 12,
+            })
+        );
+        let job = gitlab
+            .parse_url("https://gitlab.example.com/group/app/-/jobs/34/")
+            .unwrap();
+        assert_eq!(job.kind, Kind::Job);
+        assert_eq!(
+            gitlab.api_url(&job),
+            "https://gitlab.example.com/api/v4/projects/group%2Fapp/jobs/34"
+        );
+        assert!(!gitlab.recognizes("https://gitlab.example.com/group/app/-/merge_requests/1"));
+        assert!(!gitlab.recognizes("https://other.example.com/group/app/-/pipelines/12"));
+    }
+
+    #[tokio::test]
+    async fn retries() {
+        let (base_url, _requests) = test_server::serve(|request| {
+            let host = request.header("host").unwrap_or_default();
+            if request.header("private-token") != Some("secret") {
+                return Response::status(401);
+            }
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/api/v4/projects/group%2Fapp/pipelines/12/retry") => {
+                    Response::json(json!({
+                        "status": "pending",
+                        "web_url": format!("http://{}/group/app/-/pipelines/12", host),
+                    }))
+                }
+                ("POST", "/api/v4/projects/group%2Fapp/jobs/34/retry") => Response::json(json!({
+                    "status": "pending",
+                    "web_url": format!("http://{}/group/app/-/jobs/35", host),
+                })),
+                ("GET", "/api/v4/projects/group%2Fapp/pipelines/12") => Response::json(json!({
+                    "status": "running",
+                    "web_url": format!("http://{}/group/app/-/pipelines/12", host),
+                })),
+                ("GET", "/api/v4/projects/group%2Fapp/jobs/35") => Response::json(json!({
+                    "status": "failed",
+                    "web_url": format!("http://{}/group/app/-/jobs/35", host),
+                })),
+                _ => Response::status(404),
+            }
+        })
+        .await;
+        let gitlab = backend(&base_url);
+
+        let mut pipeline = gitlab
+            .rebuild(&format!("{}/group/app/-/pipelines/12", base_url))
+            .await
+            .unwrap();
+        assert_eq!(
+            pipeline.url,
+            Some(format!("{}/group/app/-/pipelines/12", base_url))
+        );
+        assert_eq!(
+            gitlab.rebuild_state(&mut pipeline).await.unwrap(),
+            Some(RebuildState::Running)
+        );
+
+        let mut job = gitlab
+            .rebuild(&format!("{}/group/app/-/jobs/34", base_url))
+            .await
+            .unwrap();
+        assert_eq!(job.url, Some(format!("{}/group/app/-/jobs/35", base_url)));
+        assert_eq!(gitlab.rebuild_state(&mut job).await.unwrap(), None);
+
+        assert!(gitlab
+            .rebuild(&format!("{}/group/other/-/jobs/1", base_url))
+            .await
+            .is_err());
+    }
+}
@@ -1,36 +1,19 @@
-#![cfg(feature = "jenkins")]
+#![cfg(feature = "ci")]
+
+use crate::bitbucket::BuildStatus;
+use crate::ci::QueuedBuild;
+use crate::data_dir::DATA_DIR;
 
 use anyhow::Result;
-#[cfg(not(test))]
-use directories::ProjectDirs;
 use log::*;
-use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use time::{Duration, OffsetDateTime};
 
 const STALENESS_THRESHOLD: Duration = Duration::days(5);
-static CRATE_NAME: &str = "crabby-merge";
-#[cfg(not(test))]
-static DATA_DIR: Lazy<PathBuf> = Lazy::new(|| {
-    let dir = ProjectDirs::from("", "", CRATE_NAME)
-        .expect("Could not get project directory")
-        .data_dir()
-        .to_path_buf();
-    std::fs::create_dir_all(&dir).ok();
-    dir
-});
-#[cfg(test)]
-static DATA_DIR: Lazy<PathBuf> = Lazy::new(|| {
-    // Create a directory in the temp directory
-    // TODO: figure out how to remove the directory afterward
-    let temp_dir = Box::leak(Box::new(
-        tempdir::TempDir::new(CRATE_NAME).expect("Could not get project directory"),
-    ));
-    temp_dir.path().to_path_buf()
-});
 
 /// Retry history for a single pull request
 ///
@@ -39,9 +22,45 @@ static DATA_DIR: Lazy<PathBuf> = Lazy::new(|| {
 /// the filename.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct History {
+    last_update: OffsetDateTime,
+    /// Retry history of each build, by build key
+    #[serde(default)]
+    builds: HashMap<String, BuildHistory>,
+    /// URLs of failed builds that were reported as genuine failures
+    #[serde(default)]
+    genuine_failures: BTreeSet<String>,
+    /// Retry history shared by all builds, from before retries were tracked per build
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    legacy: Option<LegacyHistory>,
+    /// Number of retries of all builds, in files written before retries were tracked per build
+    #[serde(default, skip_serializing)]
+    n_retries: Option<u32>,
+    /// The last rebuild of each build by build name, in files written before retries were tracked
+    /// per build
+    #[serde(default, skip_serializing)]
+    rebuilds: HashMap<String, QueuedBuild>,
+}
+
+/// Retry history of a single build
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BuildHistory {
     /// Number of retries already made
     n_retries: u32,
     last_update: OffsetDateTime,
+    /// The last rebuild of the build
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rebuild: Option<QueuedBuild>,
+}
+
+/// Retry history shared by all builds of a commit, as it was tracked by earlier versions. Builds
+/// without history of their own inherit it.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+struct LegacyHistory {
+    n_retries: u32,
+    last_update: OffsetDateTime,
+    /// The last rebuild of each build, by build name
+    #[serde(default)]
+    rebuilds: HashMap<String, QueuedBuild>,
 }
 
 impl History {
@@ -52,13 +71,47 @@ impl History {
         path
     }
 
-    /// Save new history file for a given id, overwriting any existing file
-    pub fn save(id: &str, n_retries: u32) -> Result<()> {
-        let history = History {
-            n_retries,
+    /// Save the number of retries of a build for a given id, keeping its recorded rebuild
+    pub fn save(id: &str, build: &BuildStatus, n_retries: u32) -> Result<()> {
+        let mut history = Self::load(id).ok().flatten().unwrap_or_else(Self::empty);
+        let mut build_history = history.build(build).unwrap_or_else(BuildHistory::empty);
+        build_history.n_retries = n_retries;
+        build_history.last_update = OffsetDateTime::now_utc();
+        history.builds.insert(build.key.clone(), build_history);
+        history.write(id)
+    }
+
+    /// Record the last rebuild of a build for a given id. The time of the build's last update,
+    /// which the retry backoff is based on, is left alone.
+    pub fn save_rebuild(id: &str, build: &BuildStatus, rebuild: QueuedBuild) -> Result<()> {
+        let mut history = Self::load(id)?.unwrap_or_else(Self::empty);
+        let mut build_history = history.build(build).unwrap_or_else(BuildHistory::empty);
+        build_history.rebuild = Some(rebuild);
+        history.builds.insert(build.key.clone(), build_history);
+        history.write(id)
+    }
+
+    /// Record that a failed build was reported as a genuine failure for a given id
+    pub fn save_genuine_failure(id: &str, build_url: &str) -> Result<()> {
+        let mut history = Self::load(id)?.unwrap_or_else(Self::empty);
+        history.genuine_failures.insert(build_url.to_owned());
+        history.write(id)
+    }
+
+    fn empty() -> Self {
+        History {
             last_update: OffsetDateTime::now_utc(),
-        };
-        let buf = serde_json::to_vec(&history)?;
+            builds: HashMap::new(),
+            genuine_failures: BTreeSet::new(),
+            legacy: None,
+            n_retries: None,
+            rebuilds: HashMap::new(),
+        }
+    }
+
+    fn write(&mut self, id: &str) -> Result<()> {
+        self.last_update = OffsetDateTime::now_utc();
+        let buf = serde_json::to_vec(self)?;
         Ok(File::create(Self::path(id))?.write_all(&buf)?)
     }
 
@@ -68,7 +121,15 @@ impl History {
             Ok(mut file) => file.read_to_end(&mut buf)?,
             Err(_) => return Ok(None),
         };
-        let history: Self = serde_json::from_slice(&buf)?;
+        let mut history: Self = serde_json::from_slice(&buf)?;
+        // Files written before retries were tracked per build have the retry count at the top
+        if let Some(n_retries) = history.n_retries.take() {
+            history.legacy = Some(LegacyHistory {
+                n_retries,
+                last_update: history.last_update,
+                rebuilds: std::mem::take(&mut history.rebuilds),
+            });
+        }
         Ok(Some(history))
     }
 
@@ -87,23 +148,65 @@ impl History {
         OffsetDateTime::now_utc() - self.last_update
     }
 
-    /// Return the number of previous retries
+    /// Return the retry history of a build, if it was retried or rebuilt before
+    pub fn build(&self, build: &BuildStatus) -> Option<BuildHistory> {
+        if let Some(build_history) = self.builds.get(&build.key) {
+            return Some(build_history.clone());
+        }
+        let legacy = self.legacy.as_ref()?;
+        Some(BuildHistory {
+            n_retries: legacy.n_retries,
+            last_update: legacy.last_update,
+            rebuild: legacy.rebuilds.get(&build.name).cloned(),
+        })
+    }
+
+    /// Return whether a failed build was already reported as a genuine failure
+    pub fn reported_genuine_failure(&self, build_url: &str) -> bool {
+        self.genuine_failures.contains(build_url)
+    }
+}
+
+impl BuildHistory {
+    fn empty() -> Self {
+        BuildHistory {
+            n_retries: 0,
+            last_update: OffsetDateTime::now_utc(),
+            rebuild: None,
+        }
+    }
+
+    /// Return the time since the build was last retried
+    pub fn age(&self) -> Duration {
+        OffsetDateTime::now_utc() - self.last_update
+    }
+
+    /// Return the number of previous retries of the build
     pub fn n_retries(&self) -> u32 {
         self.n_retries
     }
+
+    /// Return the last rebuild of the build
+    pub fn rebuild(&self) -> Option<&QueuedBuild> {
+        self.rebuild.as_ref()
+    }
 }
 
 /// Clean out history files older than `STALENESS_THRESHOLD`
 pub fn decruft() -> Result<()> {
     debug!("Cleaning {}", DATA_DIR.display());
     for entry in std::fs::read_dir(&*DATA_DIR)?.flatten() {
+        // Other kinds of state are kept in subdirectories
+        if !entry.file_type().is_ok_and(|file_type| file_type.is_file()) {
+            continue;
+        }
         let delete = match History::from_file(&entry.path()) {
             Ok(Some(history)) => history.age() >= STALENESS_THRESHOLD,
             Ok(None) => false,
             Err(_) => true,
         };
         if delete {
-            std::fs::remove_file(&entry.path()).ok();
+            std::fs::remove_file(entry.path()).ok();
         }
     }
     Ok(())
@@ -112,15 +215,95 @@ pub fn decruft() -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bitbucket::BuildState;
+
+    fn build(key: &str, name: &str) -> BuildStatus {
+        BuildStatus {
+            state: BuildState::Failed,
+            key: key.to_owned(),
+            name: name.to_owned(),
+            url: format!("https://jenkins/job/{}/1/", key),
+        }
+    }
 
     #[test]
     fn loopback() {
-        History::save("pandas", 5).unwrap();
+        let unit = build("unit", "unit tests");
+        History::save("pandas", &unit, 5).unwrap();
         let history_loaded = History::load("pandas").unwrap().unwrap();
-        assert_eq!(history_loaded.n_retries, 5);
+        let build_loaded = history_loaded.build(&unit).unwrap();
+        assert_eq!(build_loaded.n_retries(), 5);
+        assert!(build_loaded.age() < Duration::seconds(10));
+        assert!(build_loaded.age() > Duration::ZERO);
         assert!(history_loaded.age() < Duration::seconds(10));
-        assert!(history_loaded.age() > Duration::ZERO);
+        assert_eq!(history_loaded.build(&build("lint", "lint")), None);
         History::delete("pandas").unwrap();
         assert!(History::delete("pandas").is_err());
     }
+
+    #[test]
+    fn rebuilds_are_kept() {
+        let unit = build("unit", "unit tests");
+        let rebuild = QueuedBuild {
+            queue_url: Some(String::from("https://jenkins/queue/item/1/")),
+            url: None,
+        };
+        History::save_rebuild("koalas", &unit, rebuild.clone()).unwrap();
+        History::save_genuine_failure("koalas", "https://jenkins/job/lint/1/").unwrap();
+        History::save("koalas", &unit, 1).unwrap();
+        let history = History::load("koalas").unwrap().unwrap();
+        let unit_history = history.build(&unit).unwrap();
+        assert_eq!(unit_history.n_retries(), 1);
+        assert_eq!(unit_history.rebuild(), Some(&rebuild));
+        assert_eq!(history.build(&build("lint", "lint")), None);
+        assert!(history.reported_genuine_failure("https://jenkins/job/lint/1/"));
+        assert!(!history.reported_genuine_failure("https://jenkins/job/lint/2/"));
+        History::delete("koalas").unwrap();
+    }
+
+    #[test]
+    fn builds_are_tracked_separately() {
+        let unit = build("unit", "unit tests");
+        let integration = build("integration", "integration tests");
+        History::save("otters", &unit, 3).unwrap();
+        History::save("otters", &integration, 1).unwrap();
+        let history = History::load("otters").unwrap().unwrap();
+        assert_eq!(history.build(&unit).unwrap().n_retries(), 3);
+        assert_eq!(history.build(&integration).unwrap().n_retries(), 1);
+        History::delete("otters").unwrap();
+    }
+
+    #[test]
+    fn legacy_history_is_migrated() {
+        let last_update = OffsetDateTime::now_utc() - Duration::minutes(1);
+        let rebuild = QueuedBuild {
+            queue_url: None,
+            url: Some(String::from("https://jenkins/job/unit/2/")),
+        };
+        let legacy = serde_json::json!({
+            "n_retries": 2,
+            "last_update": last_update,
+            "rebuilds": {"unit tests": rebuild},
+        });
+        std::fs::write(History::path("lemurs"), legacy.to_string()).unwrap();
+
+        // Every build inherits the shared retry count until it is retried again
+        let unit = build("unit", "unit tests");
+        let integration = build("integration", "integration tests");
+        let history = History::load("lemurs").unwrap().unwrap();
+        let unit_history = history.build(&unit).unwrap();
+        assert_eq!(unit_history.n_retries(), 2);
+        assert_eq!(unit_history.last_update, last_update);
+        assert_eq!(unit_history.rebuild(), Some(&rebuild));
+        let integration_history = history.build(&integration).unwrap();
+        assert_eq!(integration_history.n_retries(), 2);
+        assert_eq!(integration_history.rebuild(), None);
+
+        History::save("lemurs", &integration, 3).unwrap();
+        let history = History::load("lemurs").unwrap().unwrap();
+        assert_eq!(history.build(&unit).unwrap().n_retries(), 2);
+        assert_eq!(history.build(&unit).unwrap().rebuild(), Some(&rebuild));
+        assert_eq!(history.build(&integration).unwrap().n_retries(), 3);
+        History::delete("lemurs").unwrap();
+    }
 }
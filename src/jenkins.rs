@@ -1,17 +1,38 @@
 #![cfg(feature = "jenkins")]
 
-use anyhow::{anyhow, Result};
+use crate::ci::{CiBackend, QueuedBuild, RebuildState};
+use anyhow::{anyhow, Context, Result};
 use jenkins_api::{
     action::{parameters::*, ParametersAction},
-    build::WorkflowRun,
+    build::CommonBuild,
 };
+
+use futures::future::BoxFuture;
 use log::*;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use reqwest::header::ACCEPT;
+use reqwest::header::{ACCEPT, LOCATION};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use url::Url;
 
 pub const DEFAULT_RETRY_LIMIT: u32 = 5;
 
+/// How often to check whether a queued rebuild has started
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long to wait for a queued rebuild to start before leaving it to a later run
+const QUEUE_POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How much of the end of a build log to search for flaky failures
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+
+/// `_class` of Pipeline builds
+const WORKFLOW_RUN_CLASS: &str = "org.jenkinsci.plugins.workflow.job.WorkflowRun";
+
 #[derive(Debug, Clone)]
 /// Authentication information
 pub struct Auth {
@@ -27,8 +48,460 @@ impl Auth {
     }
 }
 
+/// The URL of a Jenkins server, optionally with a `*.` wildcard at the start of the host
 #[derive(Debug, Clone)]
-/// A Bitbucket API client
+struct ServerPattern {
+    scheme: String,
+    host: String,
+    port: Option<u16>,
+    /// Path of the server's root, without a trailing slash
+    path: String,
+}
+
+impl ServerPattern {
+    fn parse(pattern: &str) -> Result<Self> {
+        let url = Url::parse(pattern).with_context(|| format!("Invalid URL: {}", pattern))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("URL has no host: {}", pattern))?;
+        Ok(Self {
+            scheme: url.scheme().to_owned(),
+            host: host.to_owned(),
+            port: url.port_or_known_default(),
+            path: url.path().trim_end_matches('/').to_owned(),
+        })
+    }
+
+    /// Returns whether a URL is on the server
+    fn matches(&self, url: &Url) -> bool {
+        let host = url.host_str().unwrap_or_default();
+        let host_matches = match self.host.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.ends_with('.')),
+            None => host == self.host,
+        };
+        let path_matches = url
+            .path()
+            .strip_prefix(&self.path)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+        url.scheme() == self.scheme
+            && host_matches
+            && url.port_or_known_default() == self.port
+            && path_matches
+    }
+}
+
+/// Jenkins servers and the credentials to use on each. Credentials are never sent to other
+/// servers.
+#[derive(Debug, Clone, Default)]
+pub struct Servers {
+    /// Most specific first
+    servers: Vec<(ServerPattern, Auth)>,
+}
+
+impl Servers {
+    /// Adds a server, given as the URL of its root. A `*.` wildcard at the start of the host
+    /// matches any subdomain.
+    pub fn add(&mut self, pattern: &str, credentials: Auth) -> Result<()> {
+        self.servers
+            .push((ServerPattern::parse(pattern)?, credentials));
+        // Longer paths, then exact hosts, win
+        self.servers.sort_by_key(|(pattern, _)| {
+            (
+                std::cmp::Reverse(pattern.path.len()),
+                pattern.host.starts_with("*."),
+            )
+        });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    /// Returns the credentials for the server a URL is on, if it's one of the servers
+    pub fn credentials(&self, url: &str) -> Option<&Auth> {
+        let url = Url::parse(url).ok()?;
+        self.servers
+            .iter()
+            .find(|(pattern, _)| pattern.matches(&url))
+            .map(|(_, credentials)| credentials)
+    }
+}
+
+/// A crumb that must be sent with POST requests to Jenkins servers with CSRF protection
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Crumb {
+    crumb: String,
+    /// Name of the header to send the crumb in
+    crumb_request_field: String,
+}
+
+/// An item in the Jenkins build queue
+#[derive(Debug, Deserialize)]
+struct QueueItem {
+    #[serde(default)]
+    cancelled: bool,
+    /// The build, once it has left the queue
+    executable: Option<Executable>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Executable {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Queue {
+    items: Vec<QueueListItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueueListItem {
+    id: u64,
+    /// URL of the item, relative to the Jenkins root URL
+    url: String,
+    task: QueueTask,
+    #[serde(default)]
+    actions: Vec<QueueAction>,
+}
+
+impl QueueListItem {
+    fn causes(&self) -> impl Iterator<Item = &Cause> {
+        self.actions.iter().flat_map(|action| &action.causes)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct QueueAction {
+    #[serde(default)]
+    causes: Vec<Cause>,
+}
+
+/// Why a build was queued
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Cause {
+    /// e.g. "Replayed #12" or "Restarted from build #12, stage Test"
+    short_description: Option<String>,
+    /// Number of the build that was rebuilt, for Rebuilder causes
+    upstream_build: Option<u64>,
+    /// User who triggered the build, for user causes
+    user_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueueTask {
+    url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BuildProgress {
+    building: bool,
+}
+
+/// Test results of a build
+#[derive(Debug, Deserialize)]
+struct TestReport {
+    suites: Vec<TestSuite>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TestSuite {
+    cases: Vec<TestCase>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TestCase {
+    class_name: String,
+    name: String,
+    status: String,
+}
+
+impl TestCase {
+    fn failed(&self) -> bool {
+        self.status == "FAILED" || self.status == "REGRESSION"
+    }
+}
+
+/// Known causes of flaky build failures
+#[derive(Debug, Clone, Default)]
+pub struct FlakyFailures {
+    /// Patterns of flaky test names, matched against `ClassName.testName`
+    pub tests: Vec<Regex>,
+    /// Patterns of log messages that indicate a flaky failure
+    pub log_patterns: Vec<Regex>,
+}
+
+impl FlakyFailures {
+    /// Returns whether no flaky failures are configured, in which case any failure is retried
+    pub fn is_empty(&self) -> bool {
+        self.tests.is_empty() && self.log_patterns.is_empty()
+    }
+
+    /// Returns whether there are failed tests and all of them are known to be flaky
+    fn all_tests_flaky(&self, failed_tests: &[String]) -> bool {
+        !failed_tests.is_empty()
+            && failed_tests
+                .iter()
+                .all(|test| self.tests.iter().any(|pattern| pattern.is_match(test)))
+    }
+
+    /// Returns whether a build log contains a known flaky failure
+    fn log_flaky(&self, log: &str) -> bool {
+        self.log_patterns
+            .iter()
+            .any(|pattern| pattern.is_match(log))
+    }
+}
+
+/// A Pipeline build, as described by the workflow API
+#[derive(Debug, Deserialize)]
+struct PipelineRun {
+    stages: Vec<Stage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Stage {
+    name: String,
+    status: String,
+}
+
+/// Returns the path of a URL without a trailing slash
+fn url_path(url: &str) -> Option<String> {
+    Url::parse(url)
+        .ok()
+        .map(|url| url.path().trim_end_matches('/').to_owned())
+}
+
+/// A Jenkins API client
+///
+/// Keeps session cookies and CSRF crumbs so that they can be reused for every request to the same
+/// Jenkins server.
+#[derive(Debug)]
+pub struct Client {
+    http_client: reqwest::Client,
+    /// Crumbs by Jenkins root URL. `None` if the server doesn't use CSRF protection.
+    crumbs: Mutex<HashMap<String, Option<Crumb>>>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Self {
+            // Crumbs are only valid for the session they were issued in
+            http_client: reqwest::Client::builder()
+                .cookie_store(true)
+                .build()
+                .expect("Could not build HTTP client"),
+            crumbs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Performs a GET request
+    async fn get(&self, url: &str, credentials: &Auth) -> Result<Response> {
+        Ok(self
+            .http_client
+            .get(url)
+            .header(ACCEPT, "application/json")
+            .basic_auth(&credentials.username, Some(&credentials.password))
+            .send()
+            .await?)
+    }
+
+    /// Returns the crumb for a Jenkins server, fetching it if it isn't cached
+    async fn crumb(&self, root_url: &str, credentials: &Auth) -> Result<Option<Crumb>> {
+        if let Some(crumb) = self.crumbs.lock().unwrap().get(root_url) {
+            return Ok(crumb.clone());
+        }
+        let response = self
+            .get(&format!("{}/crumbIssuer/api/json", root_url), credentials)
+            .await?;
+        let crumb = match response.status() {
+            // CSRF protection is disabled
+            StatusCode::NOT_FOUND => None,
+            status if status.is_success() => Some(response.json().await?),
+            status => return Err(anyhow!("Fetching CSRF crumb returned {}", status)),
+        };
+        self.crumbs
+            .lock()
+            .unwrap()
+            .insert(root_url.to_owned(), crumb.clone());
+        Ok(crumb)
+    }
+
+    /// Sends a POST request built by `request` with a crumb attached, if the server needs one.
+    /// Crumbs are only fetched if the root URL of the job's server is on one of `servers`.
+    ///
+    /// If the server rejects a cached crumb, e.g. because the session expired, a new one is fetched
+    /// and the request is retried once.
+    async fn post(
+        &self,
+        job: &Job,
+        servers: &Servers,
+        request: RequestBuilder,
+    ) -> Result<Response> {
+        let root_url = job.root_url();
+        let root_credentials = servers.credentials(root_url);
+        if root_credentials.is_none() {
+            debug!(
+                "Not fetching a crumb from {}, which isn't a configured server",
+                root_url
+            );
+        }
+        let mut retried = false;
+        loop {
+            let mut attempt = request
+                .try_clone()
+                .ok_or_else(|| anyhow!("Request can't be retried"))?
+                .basic_auth(&job.credentials.username, Some(&job.credentials.password));
+            let crumb = match root_credentials {
+                Some(credentials) => self.crumb(root_url, credentials).await?,
+                None => None,
+            };
+            if let Some(crumb) = &crumb {
+                attempt = attempt.header(crumb.crumb_request_field.as_str(), crumb.crumb.as_str());
+            }
+            let response = attempt.send().await?;
+            if response.status() == StatusCode::FORBIDDEN && crumb.is_some() && !retried {
+                debug!("Jenkins rejected crumb, fetching a new one");
+                self.crumbs.lock().unwrap().remove(root_url);
+                retried = true;
+                continue;
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Fetches an item in the build queue, or returns `None` if Jenkins has forgotten about it
+    async fn queue_item(&self, queue_url: &str, credentials: &Auth) -> Result<Option<QueueItem>> {
+        let url = format!("{}/api/json", queue_url.trim_end_matches('/'));
+        let response = self.get(&url, credentials).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.json().await?)),
+            status => Err(anyhow!("Fetching queue item returned {}", status)),
+        }
+    }
+
+    /// Returns whether a rebuild is still queued or running, or `None` if it has finished or
+    /// can't be followed any more. Fills in the URL of the new build once it leaves the queue.
+    /// URLs that aren't on one of `servers` aren't followed.
+    pub async fn rebuild_state(
+        &self,
+        queued: &mut QueuedBuild,
+        servers: &Servers,
+    ) -> Result<Option<RebuildState>> {
+        if queued.url.is_none() {
+            let Some(queue_url) = &queued.queue_url else {
+                return Ok(None);
+            };
+            let Some(credentials) = servers.credentials(queue_url) else {
+                warn!(
+                    "Not following queue item on an unknown server: {}",
+                    queue_url
+                );
+                return Ok(None);
+            };
+            match self.queue_item(queue_url, credentials).await? {
+                Some(item) if !item.cancelled => match item.executable {
+                    Some(executable) if servers.credentials(&executable.url).is_some() => {
+                        queued.url = Some(executable.url)
+                    }
+                    Some(executable) => {
+                        warn!(
+                            "Not following build on an unknown server: {}",
+                            executable.url
+                        );
+                        return Ok(None);
+                    }
+                    None => return Ok(Some(RebuildState::Queued)),
+                },
+                _ => return Ok(None),
+            }
+        }
+        let url = queued.url.as_deref().unwrap_or_default();
+        let Some(credentials) = servers.credentials(url) else {
+            warn!("Not following build on an unknown server: {}", url);
+            return Ok(None);
+        };
+        let response = self
+            .get(
+                &format!("{}/api/json?tree=building", url.trim_end_matches('/')),
+                credentials,
+            )
+            .await?;
+        match response.status() {
+            // The build was deleted
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                let progress: BuildProgress = response.json().await?;
+                Ok(progress.building.then_some(RebuildState::Running))
+            }
+            status => Err(anyhow!("Fetching build returned {}", status)),
+        }
+    }
+}
+
+/// Matches build URLs, capturing the job URL and the build number
+static URL_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(.*)/(\d+)(?:/)?(?:display/redirect)?$").unwrap());
+
+/// A Jenkins API client shared by all rebuilds
+static CLIENT: Lazy<Client> = Lazy::new(Client::new);
+
+/// A way of triggering a build that repeats an existing one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RebuildMethod {
+    /// Restarting the failed stage of a declarative Pipeline, keeping the results of the stages
+    /// before it
+    RestartStage,
+    /// Pipeline replay, which reruns the same script at the same SCM revision with the same
+    /// parameters and causes
+    Replay,
+    /// The Rebuilder plugin, which copies the causes of the build
+    Rebuilder,
+    /// Triggering a job without parameters
+    Build,
+    /// Triggering a job with the parameters of the build
+    BuildWithParameters,
+}
+
+impl RebuildMethod {
+    /// Returns the methods that can rebuild a build, in order of preference. Methods that depend
+    /// on plugins are skipped if Jenkins doesn't support them.
+    fn candidates(
+        class: Option<&str>,
+        parameterized: bool,
+        restart_failed_stage: bool,
+    ) -> Vec<Self> {
+        let mut methods = Vec::new();
+        if class == Some(WORKFLOW_RUN_CLASS) {
+            if restart_failed_stage {
+                methods.push(Self::RestartStage);
+            }
+            methods.push(Self::Replay);
+        }
+        if parameterized {
+            // Rebuilder only rebuilds parameterized builds after asking for the parameters in a form
+            methods.push(Self::BuildWithParameters);
+        } else {
+            methods.extend([Self::Rebuilder, Self::Build]);
+        }
+        methods
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A Jenkins build
 pub struct Job {
     base_url: String,
     /// Numerical job id
@@ -38,9 +511,6 @@ pub struct Job {
 
 impl Job {
     pub fn new(job_url: &str, credentials: Auth) -> Result<Self> {
-        static URL_REGEX: Lazy<Regex> =
-            Lazy::new(|| Regex::new(r"^(.*)/(\d+)(?:/)?(?:display/redirect)?$").unwrap());
-
         let captures = URL_REGEX
             .captures(job_url)
             .ok_or_else(|| anyhow!("Invalid URL: {}", job_url))?;
@@ -52,6 +522,26 @@ impl Job {
         })
     }
 
+    /// Returns the URL of the Jenkins server, which is everything before the first `/job/` path
+    /// segment, or just the origin if there isn't one
+    fn root_url(&self) -> &str {
+        match self.base_url.find("/job/") {
+            Some(i) => &self.base_url[..i],
+            None => {
+                let path_start = self
+                    .base_url
+                    .find("://")
+                    .and_then(|scheme_end| {
+                        self.base_url[scheme_end + 3..]
+                            .find('/')
+                            .map(|i| scheme_end + 3 + i)
+                    })
+                    .unwrap_or(self.base_url.len());
+                &self.base_url[..path_start]
+            }
+        }
+    }
+
     fn job_url(&self) -> String {
         format!("{}/{}/api/json", self.base_url, self.id)
     }
@@ -60,62 +550,408 @@ impl Job {
         format!("{}/buildWithParameters", self.base_url)
     }
 
-    async fn fetch_build(&self, client: &reqwest::Client) -> Result<WorkflowRun> {
+    fn rebuild_url(&self, method: RebuildMethod) -> String {
+        match method {
+            RebuildMethod::RestartStage => format!("{}/{}/restart/restart", self.base_url, self.id),
+            RebuildMethod::Replay => format!("{}/{}/replay/rebuild", self.base_url, self.id),
+            RebuildMethod::Rebuilder => format!("{}/{}/rebuild/", self.base_url, self.id),
+            RebuildMethod::Build => format!("{}/build", self.base_url),
+            RebuildMethod::BuildWithParameters => self.trigger_url(),
+        }
+    }
+
+    async fn fetch_build(&self, client: &Client) -> Result<CommonBuild> {
         Ok(client
-            .get(&self.job_url())
-            .header(ACCEPT, "application/json")
-            .basic_auth(&self.credentials.username, Some(&self.credentials.password))
-            .send()
+            .get(&self.job_url(), &self.credentials)
             .await?
             .json()
             .await?)
     }
 
-    /// Trigger a rebuild of the Jenkins job represented by `self`
-    pub async fn rebuild(&self, client: &reqwest::Client) -> Result<()> {
+    /// Returns the name of the first stage of the build that failed
+    async fn failed_stage(&self, client: &Client) -> Result<Option<String>> {
+        let url = format!("{}/{}/wfapi/describe", self.base_url, self.id);
+        let response = client.get(&url, &self.credentials).await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Fetching stages returned {}", response.status()));
+        }
+        let run: PipelineRun = response.json().await?;
+        Ok(run
+            .stages
+            .into_iter()
+            .find(|stage| stage.status == "FAILED")
+            .map(|stage| stage.name))
+    }
+
+    /// Returns the names of the tests that failed in the build, in the form `ClassName.testName`
+    async fn failed_tests(&self, client: &Client) -> Result<Vec<String>> {
+        let url = format!(
+            "{}/{}/testReport/api/json?tree=suites[cases[className,name,status]]",
+            self.base_url, self.id
+        );
+        let response = client.get(&url, &self.credentials).await?;
+        match response.status() {
+            // The build didn't get far enough to publish any test results
+            StatusCode::NOT_FOUND => Ok(Vec::new()),
+            status if status.is_success() => {
+                let report: TestReport = response.json().await?;
+                Ok(report
+                    .suites
+                    .into_iter()
+                    .flat_map(|suite| suite.cases)
+                    .filter(TestCase::failed)
+                    .map(|case| format!("{}.{}", case.class_name, case.name))
+                    .collect())
+            }
+            status => Err(anyhow!("Fetching test report returned {}", status)),
+        }
+    }
+
+    /// Returns the last `MAX_LOG_BYTES` of the console log of the build
+    async fn console_text(&self, client: &Client) -> Result<String> {
+        let url = &format!("{}/{}/logText/progressiveText", self.base_url, self.id);
+        let fetch = |start: u64| async move {
+            let response = client
+                .get(&format!("{}?start={}", url, start), &self.credentials)
+                .await?;
+            if !response.status().is_success() {
+                return Err(anyhow!(
+                    "Fetching console log returned {}",
+                    response.status()
+                ));
+            }
+            Ok(response)
+        };
+        // Jenkins sends the size of the log before the log itself, so only the headers of the
+        // first response are needed to find where the end of the log starts
+        let mut response = fetch(0).await?;
+        let size: u64 = response
+            .headers()
+            .get("X-Text-Size")
+            .and_then(|size| size.to_str().ok()?.parse().ok())
+            .unwrap_or_default();
+        if size > MAX_LOG_BYTES {
+            response = fetch(size - MAX_LOG_BYTES).await?;
+        }
+        let mut log = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            log.extend_from_slice(&chunk);
+            if log.len() as u64 >= MAX_LOG_BYTES {
+                log.truncate(MAX_LOG_BYTES as usize);
+                break;
+            }
+        }
+        Ok(String::from_utf8_lossy(&log).into_owned())
+    }
+
+    /// Returns whether the build failed because of a known flaky failure
+    pub async fn failed_flakily(&self, client: &Client, flaky: &FlakyFailures) -> Result<bool> {
+        if !flaky.tests.is_empty() {
+            let failed_tests = self.failed_tests(client).await?;
+            if flaky.all_tests_flaky(&failed_tests) {
+                debug!("All failed tests are flaky: {}", failed_tests.join(", "));
+                return Ok(true);
+            }
+        }
+        if !flaky.log_patterns.is_empty() && flaky.log_flaky(&self.console_text(client).await?) {
+            debug!("Build log contains a flaky failure");
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Returns whether a queue item is a rebuild of this build triggered by our user. Items without
+    /// a user cause, e.g. builds triggered by an upstream build, are never ours.
+    fn queued_rebuild(&self, item: &QueueListItem) -> bool {
+        static BUILD_NUMBER_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"#(\d+)\b").unwrap());
+
+        let rebuilds_this_build = item.causes().any(|cause| {
+            cause
+                .upstream_build
+                .map(|number| number.to_string())
+                .as_deref()
+                == Some(&self.id)
+                || cause
+                    .short_description
+                    .as_deref()
+                    .is_some_and(|description| {
+                        BUILD_NUMBER_REGEX
+                            .captures_iter(description)
+                            .any(|captures| captures[1] == self.id)
+                    })
+        });
+        let users: Vec<&str> = item
+            .causes()
+            .filter_map(|cause| cause.user_id.as_deref())
+            .collect();
+        rebuilds_this_build
+            && !users.is_empty()
+            && users.iter().all(|&user| user == self.credentials.username)
+    }
+
+    /// Returns the URL of the newest queue item that rebuilds this build, if it is in the queue.
+    /// Other builds of the job, e.g. of other pull requests or triggered by people, are ignored.
+    /// The queue is only searched if the root URL of the job's server is on one of `servers`.
+    async fn find_queue_item(&self, client: &Client, servers: &Servers) -> Result<Option<String>> {
+        let root_url = self.root_url();
+        let Some(credentials) = servers.credentials(root_url) else {
+            debug!(
+                "Not searching the queue of {}, which isn't a configured server",
+                root_url
+            );
+            return Ok(None);
+        };
+        let response = client
+            .get(
+                &format!(
+                    "{}/queue/api/json?tree=items[id,url,task[url],actions[causes[shortDescription,upstreamBuild,userId]]]",
+                    root_url
+                ),
+                credentials,
+            )
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Fetching queue returned {}", response.status()));
+        }
+        let queue: Queue = response.json().await?;
+        let job_path = url_path(&self.base_url);
+        Ok(queue
+            .items
+            .into_iter()
+            .filter(|item| item.task.url.as_deref().and_then(url_path) == job_path)
+            .filter(|item| self.queued_rebuild(item))
+            .max_by_key(|item| item.id)
+            .map(|item| format!("{}/{}", root_url, item.url)))
+    }
+
+    /// Trigger a rebuild of the Jenkins job represented by `self`, and wait for a while for the new
+    /// build to leave the queue. The queue item and new build are only followed if they're on one
+    /// of `servers`.
+    pub async fn rebuild(
+        &self,
+        client: &Client,
+        servers: &Servers,
+        restart_failed_stage: bool,
+    ) -> Result<QueuedBuild> {
         let build = self.fetch_build(client).await?;
         let build_parameters = build
             .actions
             .iter()
             .find_map(|action| action.as_variant::<ParametersAction>().ok())
-            .ok_or_else(|| anyhow!("Could not find build parameters"))?
-            .parameters;
-
-        let mut request = client.post(&self.trigger_url());
-        for param in build_parameters {
-            // Assume all build parameters are either string or boolean parameters
-            if let Ok(param) = param.as_variant::<StringParameterValue>() {
-                request = request.query(&[(&param.name, &param.value)]);
-            } else if let Ok(param) = param.as_variant::<BooleanParameterValue>() {
-                request = request.query(&[(&param.name, &param.value.to_string())]);
-            } else {
-                warn!("Parameter is not a String or Boolean parameter");
-            }
-        }
-        let response = request
-            .basic_auth(&self.credentials.username, Some(&self.credentials.password))
-            .send()
-            .await?;
-        let status = response.status();
-        if status.is_success() {
-            Ok(())
-        } else {
-            Err(anyhow!("Rebuild returned {}", response.status()))
+            .map(|action| action.parameters)
+            .unwrap_or_default();
+
+        let methods = RebuildMethod::candidates(
+            build.class.as_deref(),
+            !build_parameters.is_empty(),
+            restart_failed_stage,
+        );
+        for (i, &method) in methods.iter().enumerate() {
+            let mut request = client.http_client.post(self.rebuild_url(method));
+            match method {
+                RebuildMethod::RestartStage => {
+                    let stage = match self.failed_stage(client).await {
+                        Ok(Some(stage)) => stage,
+                        Ok(None) => {
+                            debug!("No failed stage to restart");
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("Could not find failed stage: {:#}", e);
+                            continue;
+                        }
+                    };
+                    info!("Restarting stage {}", stage);
+                    let form = serde_json::json!({ "stageName": stage }).to_string();
+                    request = request.form(&[("stageName", stage.as_str()), ("json", &form)]);
+                }
+                RebuildMethod::BuildWithParameters => {
+                    // Rebuilding with a parameter missing could do something other than the
+                    // original build, so refuse to rebuild at all if any parameter can't be
+                    // replayed
+                    let params = build_parameters
+                        .iter()
+                        .map(|param| Ok((param.name.clone(), parameter_value(param)?)))
+                        .collect::<Result<Vec<(String, String)>>>()
+                        .context("Not rebuilding")?;
+                    request = request.query(&params);
+                }
+                _ => (),
+            }
+            let response = client.post(self, servers, request).await?;
+            let status = response.status();
+            // Stages can't be restarted if e.g. the Pipeline isn't declarative or has changed
+            let unavailable =
+                status == StatusCode::NOT_FOUND || method == RebuildMethod::RestartStage;
+            if !status.is_success() && unavailable && i + 1 < methods.len() {
+                debug!(
+                    "{:?} is not available, falling back to {:?}",
+                    method,
+                    methods[i + 1]
+                );
+                continue;
+            }
+            if !status.is_success() {
+                return Err(anyhow!("Rebuild returned {}", status));
+            }
+
+            // Triggering a job responds with the queue item, but replays and Rebuilder redirect to
+            // the job's page, so the queue has to be searched for those
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| response.url().join(location).ok())
+                .map(String::from)
+                .filter(|location| {
+                    let known = servers.credentials(location).is_some();
+                    if !known {
+                        warn!("Ignoring queue item on an unknown server: {}", location);
+                    }
+                    known
+                });
+            let queue_url = match location {
+                Some(location) => Some(location),
+                None => self
+                    .find_queue_item(client, servers)
+                    .await
+                    .unwrap_or_else(|e| {
+                        warn!("Could not find queued rebuild: {:#}", e);
+                        None
+                    }),
+            };
+            let mut queued = QueuedBuild {
+                queue_url,
+                url: None,
+            };
+            let deadline = Instant::now() + QUEUE_POLL_TIMEOUT;
+            loop {
+                match client.rebuild_state(&mut queued, servers).await {
+                    Ok(Some(RebuildState::Queued)) if Instant::now() < deadline => {
+                        tokio::time::sleep(QUEUE_POLL_INTERVAL).await;
+                    }
+                    Ok(_) => break,
+                    Err(e) => {
+                        warn!("Could not check queued rebuild: {:#}", e);
+                        break;
+                    }
+                }
+            }
+            return Ok(queued);
+        }
+        unreachable!("There is always a rebuild method")
+    }
+}
+
+/// Returns the value to pass to Jenkins to replay a build parameter
+fn parameter_value(param: &CommonParameter) -> Result<String> {
+    if let Ok(param) = param.as_variant::<StringParameterValue>() {
+        // Also used for choice parameters
+        Ok(param.value)
+    } else if let Ok(param) = param.as_variant::<BooleanParameterValue>() {
+        Ok(param.value.to_string())
+    } else if let Ok(param) = param.as_variant::<TextParameterValue>() {
+        Ok(param.value)
+    } else if let Ok(param) = param.as_variant::<RunParameterValue>() {
+        Ok(format!("{}#{}", param.job_name, param.number))
+    } else if param.as_variant::<PasswordParameterValue>().is_ok() {
+        Err(anyhow!(
+            "password parameter {} can't be replayed because Jenkins doesn't expose its value",
+            param.name
+        ))
+    } else if param.as_variant::<FileParameterValue>().is_ok() {
+        Err(anyhow!(
+            "file parameter {} can't be replayed because Jenkins doesn't expose its contents",
+            param.name
+        ))
+    } else {
+        // Parameter types from plugins, e.g. credentials or Git parameters, usually have a simple
+        // value field
+        let class = param.class.as_deref().unwrap_or("unknown");
+        match serde_json::to_value(param)?.get("value") {
+            Some(serde_json::Value::String(value)) => Ok(value.clone()),
+            Some(value @ (serde_json::Value::Bool(_) | serde_json::Value::Number(_))) => {
+                Ok(value.to_string())
+            }
+            _ => Err(anyhow!(
+                "parameter {} has unsupported type {}",
+                param.name,
+                class
+            )),
         }
     }
 }
 
-/// Attempt to rebuild the given build
-#[cfg(feature = "jenkins")]
-pub async fn rebuild(build_url: &str, jenkins_auth: Auth) -> Result<()> {
-    let job = Job::new(build_url, jenkins_auth.clone())?;
-    let client = reqwest::Client::new();
-    job.rebuild(&client).await
+/// Jenkins as a CI server to rebuild builds on
+#[derive(Debug)]
+pub struct JenkinsBackend {
+    servers: Servers,
+    restart_failed_stage: bool,
+    flaky_failures: FlakyFailures,
+}
+
+impl JenkinsBackend {
+    pub fn new(
+        servers: Servers,
+        restart_failed_stage: bool,
+        flaky_failures: FlakyFailures,
+    ) -> Self {
+        Self {
+            servers,
+            restart_failed_stage,
+            flaky_failures,
+        }
+    }
+
+    fn credentials(&self, url: &str) -> Result<&Auth> {
+        self.servers
+            .credentials(url)
+            .ok_or_else(|| anyhow!("No Jenkins credentials configured for {}", url))
+    }
+}
+
+impl CiBackend for JenkinsBackend {
+    fn name(&self) -> &'static str {
+        "Jenkins"
+    }
+
+    fn recognizes(&self, build_url: &str) -> bool {
+        URL_REGEX.is_match(build_url) && self.servers.credentials(build_url).is_some()
+    }
+
+    fn rebuild_state<'a>(
+        &'a self,
+        rebuild: &'a mut QueuedBuild,
+    ) -> BoxFuture<'a, Result<Option<RebuildState>>> {
+        Box::pin(async move { CLIENT.rebuild_state(rebuild, &self.servers).await })
+    }
+
+    fn rebuild<'a>(&'a self, build_url: &'a str) -> BoxFuture<'a, Result<QueuedBuild>> {
+        Box::pin(async move {
+            let job = Job::new(build_url, self.credentials(build_url)?.clone())?;
+            job.rebuild(&CLIENT, &self.servers, self.restart_failed_stage)
+                .await
+        })
+    }
+
+    fn failed_flakily<'a>(&'a self, build_url: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            if self.flaky_failures.is_empty() {
+                return Ok(true);
+            }
+            let job = Job::new(build_url, self.credentials(build_url)?.clone())?;
+            job.failed_flakily(&CLIENT, &self.flaky_failures).await
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_server::{self, Request, Response};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     #[test]
     fn url_with_suffix() {
@@ -162,4 +998,626 @@ mod tests {
             job.trigger_url()
         );
     }
+
+    #[test]
+    fn root_urls() {
+        let auth = Auth::new(String::from("user"), String::from("hunter2"));
+        let job = Job::new(
+            "https://ci.example.com/jenkins/job/a/job/b/7/",
+            auth.clone(),
+        )
+        .unwrap();
+        assert_eq!(job.root_url(), "https://ci.example.com/jenkins");
+        let job = Job::new("http://www.myjenkins.com/project/101", auth).unwrap();
+        assert_eq!(job.root_url(), "http://www.myjenkins.com");
+    }
+
+    #[test]
+    fn credentials_are_scoped_to_servers() {
+        let mut servers = Servers::default();
+        for (pattern, username) in [
+            ("https://ci.example.com", "ci"),
+            ("https://ci.example.com/team-b/", "team-b"),
+            ("https://Jenkins.example.com", "jenkins"),
+            ("https://*.example.org", "org"),
+        ] {
+            let credentials = Auth::new(String::from(username), String::from("hunter2"));
+            servers.add(pattern, credentials).unwrap();
+        }
+        let username = |url| servers.credentials(url).map(|auth| auth.username.as_str());
+        assert_eq!(username("https://ci.example.com/job/a/1/"), Some("ci"));
+        assert_eq!(
+            username("https://ci.example.com/team-b/job/a/1/"),
+            Some("team-b")
+        );
+        assert_eq!(
+            username("https://ci.example.com/team-bb/job/a/1/"),
+            Some("ci")
+        );
+        assert_eq!(
+            username("https://jenkins.example.com:443/job/a/1/"),
+            Some("jenkins")
+        );
+        assert_eq!(username("https://a.b.example.org/job/a/1/"), Some("org"));
+        for url in [
+            "http://ci.example.com/job/a/1/",
+            "https://ci.example.com:8443/job/a/1/",
+            "https://ci.example.com.evil.com/job/a/1/",
+            "https://example.org/job/a/1/",
+            "https://notexample.org/job/a/1/",
+            "not a url",
+        ] {
+            assert_eq!(username(url), None, "{}", url);
+        }
+        assert!(servers
+            .add("/job/a/1/", Auth::new(String::new(), String::new()))
+            .is_err());
+
+        let backend = JenkinsBackend::new(servers, false, FlakyFailures::default());
+        assert!(backend.recognizes("https://ci.example.com/job/a/1/"));
+        assert!(!backend.recognizes("https://elsewhere.example.com/job/a/1/"));
+    }
+
+    /// Serves crumbs from `crumbs` in turn, and accepts POST requests with the last crumb served
+    async fn crumb_server(
+        crumbs: &'static [&'static str],
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<Request>) {
+        let served = Arc::new(AtomicUsize::new(0));
+        test_server::serve(move |request| {
+            let n = served.load(Ordering::SeqCst);
+            match request.path.as_str() {
+                "/crumbIssuer/api/json" => {
+                    served.fetch_add(1, Ordering::SeqCst);
+                    Response::json(json!({
+                        "crumb": crumbs[n],
+                        "crumbRequestField": "Jenkins-Crumb",
+                    }))
+                    .header("Set-Cookie", &format!("JSESSIONID={}; Path=/", n))
+                }
+                _ if n > 0
+                    && request.header("jenkins-crumb") == Some(crumbs[n - 1])
+                    && request.header("cookie") == Some(&format!("JSESSIONID={}", n - 1)) =>
+                {
+                    Response::status(201)
+                }
+                _ => Response::status(403),
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn crumbs_are_cached() {
+        let (base_url, mut requests) = crumb_server(&["abc"]).await;
+        let (servers, auth) = server(&base_url);
+        let job = Job::new(&format!("{}/job/foo/1", base_url), auth).unwrap();
+        let client = Client::new();
+        for _ in 0..2 {
+            let request = client.http_client.post(job.trigger_url());
+            let response = client.post(&job, &servers, request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+        let mut paths = Vec::new();
+        while let Ok(request) = requests.try_recv() {
+            paths.push(format!("{} {}", request.method, request.path));
+        }
+        assert_eq!(
+            paths,
+            vec![
+                "GET /crumbIssuer/api/json",
+                "POST /job/foo/buildWithParameters",
+                "POST /job/foo/buildWithParameters"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stale_crumbs_are_refreshed() {
+        let (base_url, _requests) = crumb_server(&["new"]).await;
+        let (servers, auth) = server(&base_url);
+        let job = Job::new(&format!("{}/job/foo/1", base_url), auth).unwrap();
+        let client = Client::new();
+        client.crumbs.lock().unwrap().insert(
+            base_url,
+            Some(Crumb {
+                crumb: String::from("expired"),
+                crumb_request_field: String::from("Jenkins-Crumb"),
+            }),
+        );
+        let request = client.http_client.post(job.trigger_url());
+        let response = client.post(&job, &servers, request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    fn parameter(json: serde_json::Value) -> CommonParameter {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn parameter_values() {
+        let values = [
+            json!({"_class": "hudson.model.StringParameterValue", "name": "a", "value": "x"}),
+            json!({"_class": "hudson.model.BooleanParameterValue", "name": "b", "value": true}),
+            json!({"_class": "hudson.model.TextParameterValue", "name": "c", "value": "1\n2"}),
+            json!({"_class": "hudson.model.RunParameterValue", "name": "d", "jobName": "job", "number": "5"}),
+            json!({"_class": "com.cloudbees.plugins.credentials.CredentialsParameterValue", "name": "e", "value": "creds"}),
+        ]
+        .map(|json| parameter_value(&parameter(json)).unwrap());
+        assert_eq!(values, ["x", "true", "1\n2", "job#5", "creds"]);
+
+        let error = parameter_value(&parameter(
+            json!({"_class": "hudson.model.PasswordParameterValue", "name": "secret"}),
+        ))
+        .unwrap_err();
+        assert!(error.to_string().contains("password parameter secret"));
+        assert!(parameter_value(&parameter(
+            json!({"_class": "hudson.model.FileParameterValue", "name": "upload"})
+        ))
+        .is_err());
+        let error = parameter_value(&parameter(
+            json!({"_class": "com.example.FancyParameterValue", "name": "f", "value": {"x": 1}}),
+        ))
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "parameter f has unsupported type com.example.FancyParameterValue"
+        );
+    }
+
+    #[test]
+    fn rebuild_methods() {
+        use RebuildMethod::*;
+        assert_eq!(
+            RebuildMethod::candidates(Some(WORKFLOW_RUN_CLASS), true, false),
+            vec![Replay, BuildWithParameters]
+        );
+        assert_eq!(
+            RebuildMethod::candidates(Some(WORKFLOW_RUN_CLASS), false, true),
+            vec![RestartStage, Replay, Rebuilder, Build]
+        );
+        assert_eq!(
+            RebuildMethod::candidates(Some("hudson.model.FreeStyleBuild"), false, true),
+            vec![Rebuilder, Build]
+        );
+
+        let auth = Auth::new(String::from("user"), String::from("hunter2"));
+        let job = Job::new("https://ci/job/a/job/main/3/", auth).unwrap();
+        assert_eq!(
+            job.rebuild_url(Replay),
+            "https://ci/job/a/job/main/3/replay/rebuild"
+        );
+        assert_eq!(
+            job.rebuild_url(Rebuilder),
+            "https://ci/job/a/job/main/3/rebuild/"
+        );
+        assert_eq!(job.rebuild_url(Build), "https://ci/job/a/job/main/build");
+        assert_eq!(
+            job.rebuild_url(RestartStage),
+            "https://ci/job/a/job/main/3/restart/restart"
+        );
+    }
+
+    /// Serves a build with the given class and actions, and answers POST requests to `available`
+    /// paths
+    async fn build_server(
+        class: &'static str,
+        actions: serde_json::Value,
+        available: &'static [&'static str],
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<Request>) {
+        test_server::serve(move |request| match request.path.as_str() {
+            "/job/foo/1/api/json" => Response::json(json!({
+                "_class": class,
+                "url": "http://jenkins/job/foo/1/",
+                "number": 1,
+                "duration": 10,
+                "estimatedDuration": 10,
+                "timestamp": 0,
+                "keepLog": false,
+                "result": "FAILURE",
+                "displayName": "#1",
+                "building": false,
+                "id": "1",
+                "queueId": 1,
+                "actions": actions,
+                "artifacts": [],
+            })),
+            "/job/foo/1/wfapi/describe" => Response::json(json!({
+                "stages": [
+                    {"name": "Build", "status": "SUCCESS"},
+                    {"name": "Integration", "status": "FAILED"},
+                ],
+            })),
+            "/job/foo/1/restart/restart" if !request.body.contains("stageName=Integration") => {
+                Response::status(400)
+            }
+            path if request.method == "POST"
+                && available.contains(&path.split('?').next().unwrap()) =>
+            {
+                Response::status(201)
+            }
+            _ => Response::status(404),
+        })
+        .await
+    }
+
+    /// A Jenkins server at `base_url`, and the credentials to use on it
+    fn server(base_url: &str) -> (Servers, Auth) {
+        let auth = Auth::new(String::from("user"), String::from("hunter2"));
+        let mut servers = Servers::default();
+        servers.add(base_url, auth.clone()).unwrap();
+        (servers, auth)
+    }
+
+    async fn rebuild_requests(
+        class: &'static str,
+        actions: serde_json::Value,
+        available: &'static [&'static str],
+        restart_failed_stage: bool,
+    ) -> (Result<QueuedBuild>, Vec<String>) {
+        let (base_url, mut requests) = build_server(class, actions, available).await;
+        let (servers, auth) = server(&base_url);
+        let job = Job::new(&format!("{}/job/foo/1", base_url), auth).unwrap();
+        let result = job
+            .rebuild(&Client::new(), &servers, restart_failed_stage)
+            .await;
+        let mut posts = Vec::new();
+        while let Ok(request) = requests.try_recv() {
+            if request.method == "POST" {
+                posts.push(request.path);
+            }
+        }
+        (result, posts)
+    }
+
+    #[tokio::test]
+    async fn rebuilds() {
+        let parameters = json!([{
+            "_class": "hudson.model.ParametersAction",
+            "parameters": [
+                {"_class": "hudson.model.StringParameterValue", "name": "a", "value": "x"},
+            ],
+        }]);
+        let (result, posts) = rebuild_requests(
+            WORKFLOW_RUN_CLASS,
+            parameters.clone(),
+            &["/job/foo/1/replay/rebuild"],
+            false,
+        )
+        .await;
+        result.unwrap();
+        assert_eq!(posts, vec!["/job/foo/1/replay/rebuild"]);
+
+        let (result, posts) = rebuild_requests(
+            WORKFLOW_RUN_CLASS,
+            parameters,
+            &["/job/foo/buildWithParameters"],
+            false,
+        )
+        .await;
+        result.unwrap();
+        assert_eq!(
+            posts,
+            vec![
+                "/job/foo/1/replay/rebuild",
+                "/job/foo/buildWithParameters?a=x"
+            ]
+        );
+
+        let (result, posts) = rebuild_requests(
+            "hudson.model.FreeStyleBuild",
+            json!([{}]),
+            &["/job/foo/build"],
+            false,
+        )
+        .await;
+        result.unwrap();
+        assert_eq!(posts, vec!["/job/foo/1/rebuild/", "/job/foo/build"]);
+
+        let (result, _) =
+            rebuild_requests("hudson.model.FreeStyleBuild", json!([]), &[], false).await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Rebuild returned 404 Not Found"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follows_queued_rebuilds() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let served_polls = Arc::clone(&polls);
+        let (base_url, _requests) = test_server::serve(move |request| {
+            let host = request.header("host").unwrap_or_default();
+            match request.path.as_str() {
+                "/job/foo/1/api/json" => Response::json(json!({
+                    "url": "http://jenkins/job/foo/1/",
+                    "number": 1,
+                    "duration": 10,
+                    "estimatedDuration": 10,
+                    "timestamp": 0,
+                    "keepLog": false,
+                    "result": "FAILURE",
+                    "displayName": "#1",
+                    "building": false,
+                    "id": "1",
+                    "queueId": 1,
+                    "actions": [],
+                    "artifacts": [],
+                })),
+                "/job/foo/1/rebuild/" => Response::status(404),
+                "/job/foo/build" => Response::status(201).header("Location", "/queue/item/7/"),
+                // The build waits in the queue for one poll
+                "/queue/item/7/api/json" if served_polls.fetch_add(1, Ordering::SeqCst) == 0 => {
+                    Response::json(json!({"why": "Waiting for next available executor"}))
+                }
+                "/queue/item/7/api/json" => Response::json(json!({
+                    "executable": {"number": 2, "url": format!("http://{}/job/foo/2/", host)},
+                })),
+                "/job/foo/2/api/json?tree=building" => Response::json(json!({"building": true})),
+                _ => Response::status(404),
+            }
+        })
+        .await;
+        let (servers, auth) = server(&base_url);
+        let job = Job::new(&format!("{}/job/foo/1", base_url), auth).unwrap();
+        let client = Client::new();
+        let mut queued = job.rebuild(&client, &servers, false).await.unwrap();
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            queued,
+            QueuedBuild {
+                queue_url: Some(format!("{}/queue/item/7/", base_url)),
+                url: Some(format!("{}/job/foo/2/", base_url)),
+            }
+        );
+        assert_eq!(
+            client.rebuild_state(&mut queued, &servers).await.unwrap(),
+            Some(RebuildState::Running)
+        );
+
+        // Jenkins forgets about queue items a while after they leave the queue
+        let mut forgotten = QueuedBuild {
+            queue_url: Some(format!("{}/queue/item/6/", base_url)),
+            url: None,
+        };
+        assert_eq!(
+            client
+                .rebuild_state(&mut forgotten, &servers)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn only_follows_rebuilds_on_known_servers() {
+        let (base_url, mut requests) = test_server::serve(|request| {
+            // The same server under another name, which isn't configured
+            let port = request.header("host").unwrap_or_default().split(':').nth(1);
+            let elsewhere = format!("http://localhost:{}", port.unwrap_or_default());
+            match request.path.as_str() {
+                "/queue/item/7/api/json" => Response::json(json!({
+                    "executable": {"number": 2, "url": format!("{}/job/foo/2/", elsewhere)},
+                })),
+                _ => Response::json(json!({"building": true})),
+            }
+        })
+        .await;
+        let (servers, _) = server(&base_url);
+        let client = Client::new();
+        let elsewhere = base_url.replace("127.0.0.1", "localhost");
+        let queue_url = format!("{}/queue/item/7/", base_url);
+        for (mut queued, requested) in [
+            (
+                QueuedBuild {
+                    queue_url: Some(queue_url.clone()),
+                    url: None,
+                },
+                vec!["/queue/item/7/api/json"],
+            ),
+            (
+                QueuedBuild {
+                    queue_url: Some(format!("{}/queue/item/7/", elsewhere)),
+                    url: None,
+                },
+                vec![],
+            ),
+            (
+                QueuedBuild {
+                    queue_url: Some(queue_url),
+                    url: Some(format!("{}/job/foo/2/", elsewhere)),
+                },
+                vec![],
+            ),
+        ] {
+            let url = queued.url.clone();
+            assert_eq!(
+                client.rebuild_state(&mut queued, &servers).await.unwrap(),
+                None
+            );
+            assert_eq!(queued.url, url);
+            let mut paths = Vec::new();
+            while let Ok(request) = requests.try_recv() {
+                assert_eq!(request.header("host"), Some(&base_url[7..]));
+                paths.push(request.path);
+            }
+            assert_eq!(paths, requested);
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_queued_rebuilds() {
+        let (base_url, _requests) = test_server::serve(|request| {
+            let host = request.header("host").unwrap_or_default();
+            let item = |id: u64, job: &str, causes: serde_json::Value| {
+                json!({
+                    "id": id,
+                    "url": format!("queue/item/{}/", id),
+                    "task": {"url": format!("http://{}/job/{}/", host, job)},
+                    "actions": [{"causes": causes}, {}],
+                })
+            };
+            let items = match request.path.split('?').next().unwrap() {
+                "/queue/api/json" => vec![
+                    item(
+                        8,
+                        "foo",
+                        json!([{"shortDescription": "Replayed #1"}, {"userId": "user"}]),
+                    ),
+                    // Newer, but replays a build of another pull request
+                    item(
+                        9,
+                        "foo",
+                        json!([{"shortDescription": "Replayed #12"}, {"userId": "user"}]),
+                    ),
+                    // Someone else rebuilding the same build
+                    item(
+                        10,
+                        "foo",
+                        json!([{"upstreamBuild": 1}, {"userId": "alice"}]),
+                    ),
+                    // A build of another job
+                    item(11, "bar", json!([{"shortDescription": "Replayed #1"}])),
+                    // Newer, but triggered by build 1 of an upstream job rather than by us
+                    item(13, "foo", json!([{"upstreamBuild": 1}])),
+                    // A build someone started by hand
+                    item(
+                        12,
+                        "foo",
+                        json!([{"shortDescription": "Started by user alice", "userId": "alice"}]),
+                    ),
+                ],
+                _ => return Response::status(404),
+            };
+            Response::json(json!({ "items": items }))
+        })
+        .await;
+        let client = Client::new();
+        let (servers, auth) = server(&base_url);
+        let job = |number: u32| {
+            Job::new(&format!("{}/job/foo/{}", base_url, number), auth.clone()).unwrap()
+        };
+        assert_eq!(
+            job(1).find_queue_item(&client, &servers).await.unwrap(),
+            Some(format!("{}/queue/item/8/", base_url))
+        );
+        assert_eq!(
+            job(2).find_queue_item(&client, &servers).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn root_requests_stay_on_configured_servers() {
+        let (base_url, mut requests) =
+            test_server::serve(
+                |request| match (request.method.as_str(), request.path.as_str()) {
+                    ("GET", "/teamA/foo/1/api/json") => Response::json(json!({
+                        "_class": "hudson.model.FreeStyleBuild",
+                        "url": "http://jenkins/teamA/foo/1/",
+                        "number": 1,
+                        "duration": 10,
+                        "estimatedDuration": 10,
+                        "timestamp": 0,
+                        "keepLog": false,
+                        "result": "FAILURE",
+                        "displayName": "#1",
+                        "building": false,
+                        "id": "1",
+                        "queueId": 1,
+                        "actions": [],
+                        "artifacts": [],
+                    })),
+                    ("POST", "/teamA/foo/build") => Response::status(201),
+                    _ => Response::status(404),
+                },
+            )
+            .await;
+        // Without `/job/` in the URL, the root URL is guessed to be the origin, which is outside
+        // the configured server
+        let (servers, auth) = server(&format!("{}/teamA", base_url));
+        let job = Job::new(&format!("{}/teamA/foo/1", base_url), auth).unwrap();
+        let queued = job.rebuild(&Client::new(), &servers, false).await.unwrap();
+        assert_eq!(queued.queue_url, None);
+        let mut paths = Vec::new();
+        while let Ok(request) = requests.try_recv() {
+            paths.push(format!("{} {}", request.method, request.path));
+        }
+        assert_eq!(
+            paths,
+            vec![
+                "GET /teamA/foo/1/api/json",
+                "POST /teamA/foo/1/rebuild/",
+                "POST /teamA/foo/build"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn restarts_failed_stage() {
+        let (result, posts) = rebuild_requests(
+            WORKFLOW_RUN_CLASS,
+            json!([]),
+            &["/job/foo/1/restart/restart"],
+            true,
+        )
+        .await;
+        result.unwrap();
+        assert_eq!(posts, vec!["/job/foo/1/restart/restart"]);
+
+        let (result, posts) = rebuild_requests(
+            WORKFLOW_RUN_CLASS,
+            json!([]),
+            &["/job/foo/1/replay/rebuild"],
+            true,
+        )
+        .await;
+        result.unwrap();
+        assert_eq!(
+            posts,
+            vec!["/job/foo/1/restart/restart", "/job/foo/1/replay/rebuild"]
+        );
+    }
+
+    #[tokio::test]
+    async fn flaky_failures() {
+        let (base_url, _requests) = test_server::serve(|request| match request.path.as_str() {
+            path if path.starts_with("/job/foo/1/testReport/api/json") => Response::json(json!({
+                "suites": [{"cases": [
+                    {"className": "com.example.ApiTest", "name": "timesOut", "status": "FAILED"},
+                    {"className": "com.example.ApiTest", "name": "works", "status": "PASSED"},
+                ]}],
+            })),
+            path if path.starts_with("/job/foo/2/testReport/api/json") => Response::json(json!({
+                "suites": [{"cases": [
+                    {"className": "com.example.ApiTest", "name": "timesOut", "status": "FAILED"},
+                    {"className": "com.example.DbTest", "name": "migrates", "status": "REGRESSION"},
+                ]}],
+            })),
+            "/job/foo/1/logText/progressiveText?start=0"
+            | "/job/foo/2/logText/progressiveText?start=0" => {
+                Response::text("BUILD FAILED").header("X-Text-Size", "12")
+            }
+            // Only the end of long logs is fetched
+            "/job/foo/3/logText/progressiveText?start=0" => {
+                Response::text("").header("X-Text-Size", &(MAX_LOG_BYTES + 100).to_string())
+            }
+            "/job/foo/3/logText/progressiveText?start=100" => {
+                Response::text("error: Connection reset by peer")
+            }
+            _ => Response::status(404),
+        })
+        .await;
+        let auth = Auth::new(String::from("user"), String::from("hunter2"));
+        let flaky = FlakyFailures {
+            tests: vec![Regex::new(r"^com\.example\.ApiTest\.timesOut$").unwrap()],
+            log_patterns: vec![Regex::new("Connection reset").unwrap()],
+        };
+        let client = Client::new();
+        let mut results = Vec::new();
+        for build in 1..=3 {
+            let job = Job::new(&format!("{}/job/foo/{}", base_url, build), auth.clone()).unwrap();
+            results.push(job.failed_flakily(&client, &flaky).await.unwrap());
+        }
+        assert_eq!(results, vec![true, false, true]);
+    }
 }
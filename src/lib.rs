@@ -1,10 +1,30 @@
-mod backoff;
+pub mod backoff;
 pub mod bitbucket;
+pub mod buildkite;
+pub mod ci;
+pub mod codeowners;
 mod config;
+mod data_dir;
+pub mod desktop;
+pub mod email;
+pub mod github_actions;
+pub mod gitlab;
 pub mod history_file;
 pub mod jenkins;
+pub mod logging;
+pub mod merge_checks;
+pub mod metrics;
+pub mod notifier;
+mod rate_limit;
 pub mod search;
+pub mod stack;
+pub mod summary;
+pub mod teamcity;
+#[cfg(test)]
+mod test_fixtures;
+#[cfg(test)]
+mod test_server;
 
-pub use crate::config::Config;
-#[cfg(feature = "jenkins")]
+pub use crate::config::{Config, RuntimeFlavor};
+#[cfg(feature = "ci")]
 pub use history_file::History;
@@ -0,0 +1,238 @@
+//! Logging to stderr, as plain text or JSON, with the pull request being checked attached to every
+//! event
+
+use crate::bitbucket::PullRequest;
+
+use anyhow::Result;
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::io::Write;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Format of log lines
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// The pull request and build that log events relate to
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LogContext {
+    #[serde(rename = "pr_url", skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repo: Option<String>,
+    #[serde(rename = "pr_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build: Option<String>,
+}
+
+impl LogContext {
+    fn new(pr: &PullRequest) -> Self {
+        Self {
+            url: pr.url().map(String::from),
+            project: pr.project_key().map(String::from),
+            repo: pr.repo_slug().map(String::from),
+            id: Some(pr.id()),
+            hash: pr.hash().map(String::from),
+            build: None,
+        }
+    }
+}
+
+tokio::task_local! {
+    static CONTEXT: LogContext;
+}
+
+/// Runs `f` with events logged from it tagged with `pr`
+pub async fn with_pr<F: Future>(pr: &PullRequest, f: F) -> F::Output {
+    CONTEXT.scope(LogContext::new(pr), f).await
+}
+
+/// Runs `f` with events logged from it tagged with the build `name`, in addition to any pull
+/// request they are already tagged with
+pub async fn with_build<F: Future>(name: &str, f: F) -> F::Output {
+    let mut context = current_context();
+    context.build = Some(name.to_owned());
+    CONTEXT.scope(context, f).await
+}
+
+/// Returns the context of the current task
+fn current_context() -> LogContext {
+    CONTEXT.try_with(Clone::clone).unwrap_or_default()
+}
+
+/// Writes log events to stderr
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        let line = format_record(self.format, &timestamp, record, &current_context());
+        // Logging must not fail the program, so write errors are ignored
+        let _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Formats a single log event
+fn format_record(
+    format: LogFormat,
+    timestamp: &str,
+    record: &Record,
+    context: &LogContext,
+) -> String {
+    match format {
+        LogFormat::Text => {
+            let prefix = match (&context.url, &context.build) {
+                (Some(url), Some(build)) => format!("[{} {}] ", url, build),
+                (Some(url), None) => format!("[{}] ", url),
+                (None, Some(build)) => format!("[{}] ", build),
+                (None, None) => String::new(),
+            };
+            format!(
+                "{} {:<5} [{}] {}{}",
+                timestamp,
+                record.level(),
+                record.target(),
+                prefix,
+                record.args()
+            )
+        }
+        LogFormat::Json => {
+            let mut event = serde_json::json!({
+                "timestamp": timestamp,
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            if let serde_json::Value::Object(context) = serde_json::to_value(context).unwrap() {
+                event.as_object_mut().unwrap().extend(context);
+            }
+            event.to_string()
+        }
+    }
+}
+
+/// Installs the global logger
+pub fn init(level: LevelFilter, format: LogFormat) -> Result<()> {
+    log::set_boxed_logger(Box::new(Logger { level, format }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn record_with<T>(f: impl FnOnce(&Record) -> T) -> T {
+        f(&Record::builder()
+            .args(format_args!("Merged"))
+            .level(Level::Info)
+            .target("crabby_merge::search")
+            .build())
+    }
+
+    fn context() -> LogContext {
+        LogContext {
+            url: Some(String::from("https://bitbucket/pr/1")),
+            project: Some(String::from("PROJ")),
+            repo: Some(String::from("repo")),
+            id: Some(1),
+            hash: Some(String::from("abc123")),
+            build: None,
+        }
+    }
+
+    #[test]
+    fn text_format() {
+        let line = record_with(|record| {
+            format_record(LogFormat::Text, "now", record, &LogContext::default())
+        });
+        assert_eq!(line, "now INFO  [crabby_merge::search] Merged");
+        let line = record_with(|record| format_record(LogFormat::Text, "now", record, &context()));
+        assert_eq!(
+            line,
+            "now INFO  [crabby_merge::search] [https://bitbucket/pr/1] Merged"
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let line = record_with(|record| {
+            format_record(
+                LogFormat::Json,
+                "now",
+                record,
+                &LogContext {
+                    build: Some(String::from("unit tests")),
+                    ..context()
+                },
+            )
+        });
+        let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({
+                "timestamp": "now",
+                "level": "INFO",
+                "target": "crabby_merge::search",
+                "message": "Merged",
+                "pr_url": "https://bitbucket/pr/1",
+                "project": "PROJ",
+                "repo": "repo",
+                "pr_id": 1,
+                "hash": "abc123",
+                "build": "unit tests",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn nested_contexts() {
+        assert_eq!(current_context(), LogContext::default());
+        CONTEXT
+            .scope(context(), async {
+                with_build("unit tests", async {
+                    assert_eq!(
+                        current_context(),
+                        LogContext {
+                            build: Some(String::from("unit tests")),
+                            ..context()
+                        }
+                    );
+                })
+                .await;
+                assert_eq!(current_context(), context());
+            })
+            .await;
+    }
+}
@@ -44,114 +44,577 @@
 //! check_own_prs = true
 //! # Whether to search pull requests the user has approved
 //! check_approved_prs = false
+//! # Whether to search pull requests the user is a reviewer of, regardless of approval status
+//! check_reviewer_prs = false
+//! # Whether to search pull requests the user is a participant of
+//! check_participant_prs = false
+//! # Whether to search pull requests that mention the user in their description or comments. Pull
+//! # requests the user participates in and pull requests in the repositories listed below are searched.
+//! check_mentioned_prs = false
+//! # Repositories to search for open pull requests, as "PROJECT/repo", or "PROJECT" to search every
+//! # repository in a project
+//! repositories = []
+//! # Number of results to request per page from paged Bitbucket APIs. Defaults to the server default.
+//! # page_limit = 25
+//! # Maximum number of pages of pull requests to fetch per search. Unlimited by default. Comments,
+//! # changes and build statuses are always fetched in full so merge checks never see partial data.
+//! # max_pages = 100
+//! # Maximum number of pull requests to check at once
+//! max_concurrent_prs = 8
+//! # Maximum number of Bitbucket API requests per second, at least 0.001. Unlimited by default.
+//! # requests_per_second = 10
+//! # Async runtime to check pull requests on: "current_thread" or "multi_thread"
+//! runtime = "current_thread"
+//! # Number of worker threads for the "multi_thread" runtime. Defaults to the number of CPU cores.
+//! # worker_threads = 4
+//! # Whether to print a JSON summary of the run to stdout when done
+//! summary_json = false
+//! # Minimum level of log messages: "error", "warn", "info", "debug" or "trace"
+//! log_level = "info"
+//! # Format of log messages: "text" or "json"
+//! log_format = "text"
+//! # Seconds to wait between runs. If unset, crabby-merge runs once and exits.
+//! # poll_interval_secs = 120
+//! # Minimum number of approvals required before merging
+//! min_approvals = 0
+//! # Whether to refuse to merge pull requests that a reviewer has marked as needing work
+//! block_on_needs_work = false
+//! # Users that must all approve before merging
+//! required_reviewers = []
+//! # Groups of users that can be referenced by required_groups
+//! reviewer_groups = {}
+//! # Groups that need at least one approval from one of their members before merging
+//! required_groups = []
+//! # Whether to refuse to merge draft pull requests
+//! block_on_draft = true
+//! # Whether to refuse to merge pull requests with open tasks
+//! block_on_open_tasks = true
+//! # Whether to refuse to merge pull requests with unresolved blocker comments
+//! block_on_blocker_comments = false
+//! # Whether to require an approval from a code owner of each changed path
+//! check_codeowners = false
+//! # Path of the CODEOWNERS file on the target branch
+//! codeowners_path = "CODEOWNERS"
+//! # Whether to wait for pull requests listed in "depends-on: #123" lines to be merged first
+//! check_depends_on = true
+//! # Whether to wait for open pull requests from the target branch to be merged first. See "Stacked
+//! # pull requests" below.
+//! check_stacked_branches = false
 //! ```
 //!
 //! All fields are optional unless indicated. Values shown are the default values.
 //!
+//! For example, to require two approvals, including one from a code owner:
+//!
+//! ```toml
+//! min_approvals = 2
+//! reviewer_groups = { owners = ["alice", "bob"] }
+//! required_groups = ["owners"]
+//! ```
+//!
+//! These reviewer rules are checked by crabby-merge before it tries to merge. They are in addition to
+//! any merge checks configured on the Bitbucket server.
+//!
+//! ### CODEOWNERS
+//!
+//! When `check_codeowners` is enabled, crabby-merge reads the CODEOWNERS file from the target branch of
+//! each pull request and won't merge until every changed path has been approved by at least one of its
+//! owners. Each line of the file contains a gitignore-style pattern followed by its owners, and the last
+//! matching pattern wins. Users are written as `@username` and groups defined in `reviewer_groups` as
+//! `@@group`:
+//!
+//! ```text
+//! *           @alice
+//! /docs/      @bob
+//! *.rs        @@owners
+//! ```
+//!
+//! ### Searching repositories
+//!
+//! By default, crabby-merge only searches pull requests on the authenticated user's dashboard. To
+//! run it as a shared service, e.g. from a team bot account, list projects or repositories in
+//! `repositories` and all of their open pull requests will be searched as well. The same trigger logic
+//! applies, so comments are only searched for the trigger if they were written by the authenticated
+//! user.
+//!
+//! Pull requests found by more than one search are only checked once.
+//!
+//! ### Stacked pull requests
+//!
+//! crabby-merge never merges a pull request before the pull requests it depends on. A pull request
+//! depends on another if its description has a line like `depends-on: #123, #124` or, when
+//! `check_stacked_branches` is enabled, if it targets the source branch of another open pull request.
+//!
+//! When a stack of triggered pull requests is found, they are merged from the bottom up. After a pull
+//! request is merged, any pull requests targeting its source branch are retargeted to its target branch
+//! before they are merged.
+//!
+//! `check_stacked_branches` is disabled by default because it can't tell a stacked branch from a
+//! long-lived one. With a `develop` → `main` pull request open, every pull request targeting `develop`
+//! would wait for `develop` to be merged into `main`. Enable it if your team stacks feature branches
+//! and keeps no long-lived pull requests open.
+//!
 //! ### Environment variables
 //!
 //! Each of the TOML keys listed above can be prefixed with `CRABBY_MERGE` and provided as an
-//! environment variable. Keys are case-insensitive.
+//! environment variable. Keys are case-insensitive. List and table values can only be set in the TOML
+//! file.
 //!
 //! For example, you can pass in the bitbucket API token as `CRABBY_MERGE_API_TOKEN=<your token here>`.
 //!
-//! ## Jenkins rebuild support
+//! ## Logging, run summary and exit codes
+//!
+//! Logs are written to stderr. When `summary_json` is enabled, a JSON summary of the run is printed to
+//! stdout with the number of pull requests scanned and triggered, the pull requests that were merged or
+//! blocked (and why), the builds that were rebuilt or are waiting to be, and any errors.
+//!
+//! With `log_format = "json"`, each log message is written as a JSON object on its own line. Messages
+//! logged while checking a pull request include `pr_url`, `project`, `repo`, `pr_id` and `hash` fields,
+//! plus a `build` field while rebuilding a build, so that log aggregators can filter by pull request.
+//! In the text format, the pull request URL is included in each message instead.
+//!
+//! crabby-merge exits with one of the following codes:
+//!
+//! | Code | Meaning                                           |
+//! | ---- | ------------------------------------------------- |
+//! | 0    | Success                                           |
+//! | 1    | Unexpected fatal error                            |
+//! | 2    | The configuration could not be loaded             |
+//! | 3    | The Bitbucket server rejected the API token       |
+//! | 4    | Partial failure: some searches or checks failed   |
+//!
+//! ## Notifications
+//!
+//! crabby-merge can post to Slack and Microsoft Teams incoming webhooks, or send JSON to any other
+//! webhook, when something happens to a pull request with the merge trigger. Add a `[[notifiers]]`
+//! table to the TOML configuration file for each destination:
+//!
+//! ```toml
+//! [[notifiers]]
+//! # "slack", "teams" or "webhook"
+//! type = "slack"
+//! url = "https://hooks.slack.com/services/..."
+//! # Events to send: "merged", "blocked", "merge_failed", "rebuilt" and "error". All events are sent if
+//! # omitted.
+//! events = ["merged", "merge_failed"]
+//!
+//! # Optional message templates, overriding the defaults
+//! [notifiers.templates]
+//! merged = "crabby-merged PR #{id} into {target}: {url}"
+//! merge_failed = "merge blocked: {reason} ({url})"
+//! ```
+//!
+//! `blocked` events are sent when a merge check in crabby-merge fails, and `merge_failed` events when
+//! Bitbucket refuses the merge, e.g. because of conflicts. Templates can use the placeholders `{event}`,
+//! `{url}`, `{id}`, `{title}`, `{project}`, `{repo}`, `{author}`, `{target}`, `{reason}` and `{build}`.
+//! Generic webhooks receive a JSON object with the event's fields and the rendered `message`.
+//!
+//! A blocked pull request is only reported again when the reason it's blocked changes, or after it was
+//! merged or lost the merge trigger. The last block reported for each pull request is kept in a
+//! `notifier` directory inside crabby-merge's data directory.
+//!
+//! ### Email
+//!
+//! When built with the `email` feature, crabby-merge can email the authors of pull requests that have
+//! the merge trigger but have been blocked for a while, e.g. by conflicts or builds that keep failing.
+//! Authors are emailed at the address in their Bitbucket profile. Configure the SMTP server in an
+//! `[email]` table:
 //!
-//! There is experimental support for rebuilding failed Jenkins builds whose name matches a provided
-//! regex trigger. This is a sad workaround for flaky blocking tests. This is compile-time gated by
-//! the `jenkins` feature, which is enabled by default.
+//! ```toml
+//! [email]
+//! smtp_host = "smtp.example.com"
+//! # Defaults to 465 for "tls", 587 for "starttls" and 25 for "none"
+//! # smtp_port = 587
+//! # "tls", "starttls" or "none"
+//! tls = "starttls"
+//! # Whether to accept invalid TLS certificates, e.g. self-signed ones
+//! accept_invalid_certs = false
+//! # Optional SMTP credentials
+//! # username = ""
+//! # password = ""
+//! from = "crabby-merge <crabby-merge@example.com>"
+//! # Number of hours a pull request must be blocked before its author is emailed
+//! blocked_hours = 24
+//! # Send each author a daily digest of all their blocked pull requests instead of one email per pull
+//! # request
+//! digest = false
+//! ```
+//!
+//! Authors are emailed once per blocked pull request, or at most once a day in digest mode. The state
+//! needed for this is kept in an `email` directory inside crabby-merge's data directory.
+//!
+//! A pull request whose failed builds were retried `ci_retry_limit` times won't be merged until
+//! someone looks at the failures, so its author is emailed right away instead of after
+//! `blocked_hours`. An invalid `[email]` table is reported when the configuration is loaded.
+//!
+//! ### Desktop notifications
+//!
+//! When built with the `notify` feature, crabby-merge shows a desktop notification when one of your own
+//! pull requests is merged, or is blocked in a way that needs your attention: a failed merge check,
+//! conflicts, or failed builds that were retried `ci_retry_limit` times. Notifications include the pull
+//! request title and a link to it, and each block is only notified about once. This uses the
+//! freedesktop notification service over D-Bus. Set `desktop_notifications = false` to turn them off
+//! without rebuilding.
+//!
+//! ## Daemon mode and metrics
+//!
+//! Instead of scheduling crabby-merge externally, you can set `poll_interval_secs` to keep it running
+//! and check pull requests every `poll_interval_secs` seconds. A run that fails is logged and retried on
+//! the next poll, except that crabby-merge exits with code 3 if the Bitbucket server rejects the API
+//! token.
 //!
-//! To use it, add the following fields to your configuration file. If these fields aren't provided,
-//! the retry functionality will be disabled at runtime.
+//! When built with the `metrics` feature, crabby-merge can serve [Prometheus](https://prometheus.io)
+//! metrics at `/metrics` on the port given by `metrics_port`:
 //!
 //! ```toml
+//! metrics_port = 9898
+//! ```
+//!
+//! The following metrics are exported:
+//!
+//! | Metric                                            | Description                                              |
+//! | ------------------------------------------------- | -------------------------------------------------------- |
+//! | `crabby_merge_prs_scanned_total`                  | Pull requests checked                                    |
+//! | `crabby_merge_triggers_total`                     | Pull requests found with the merge trigger               |
+//! | `crabby_merge_merges_total`                       | Merge attempts, labeled by `result`                      |
+//! | `crabby_merge_vetoes_total`                       | Merge blockers and vetoes, labeled by `reason`           |
+//! | `crabby_merge_ci_rebuilds_total`                  | CI builds retried                                        |
+//! | `crabby_merge_errors_total`                       | Failed searches and pull request checks                  |
+//! | `crabby_merge_bitbucket_request_duration_seconds` | Bitbucket API latency by `method` and `endpoint`         |
+//! | `crabby_merge_bitbucket_request_errors_total`     | Failed Bitbucket API requests by `method` and `endpoint` |
+//!
+//! `crabby_merge_vetoes_total` counts the merge checks of crabby-merge that failed by the kind of check,
+//! e.g. `draft`, and merges that Bitbucket refused as `conflicts` or `server_veto`. Fetching a
+//! CODEOWNERS file that doesn't exist isn't counted as a failed request.
+//!
+//! `crabby_merge_ci_rebuilds_total` counts rebuilds on every CI server. It was called
+//! `crabby_merge_jenkins_rebuilds_total` before other CI servers were supported.
+//!
+//! ## CI rebuild support
+//!
+//! There is experimental support for rebuilding failed CI builds whose name matches a provided
+//! regex trigger. This is a sad workaround for flaky blocking tests. Jenkins, GitHub Actions,
+//! GitLab CI, TeamCity and Buildkite are supported, each compile-time gated by its own feature:
+//! `jenkins`, `github`, `gitlab`, `teamcity` and `buildkite`. Only the `jenkins` feature is enabled
+//! by default.
+//!
+//! To use it, add the following fields to your configuration file, along with the configuration of
+//! at least one CI server. If these fields aren't provided, the retry functionality will be
+//! disabled at runtime. `jenkins_retry_trigger` and `jenkins_retry_limit` are accepted as aliases.
+//!
+//! ```toml
+//! # Regex trigger to search against the build name
+//! ci_retry_trigger = ""
+//! # Optional. Maximum number of retries of each build of a commit. Defaults to 10.
+//! ci_retry_limit = 10
+//!
+//! # Optional. How long to wait between retries of a build. The first retry is never delayed.
+//! # Defaults to 5 minutes between later retries.
+//! [ci_backoff]
+//! # "fixed", "linear" or "exponential"
+//! strategy = "exponential"
+//! # Delay before the second retry
+//! delay_secs = 300
+//! # Linear only. Added to the delay with every retry. Defaults to 300.
+//! step_secs = 300
+//! # Exponential only. Multiplies the delay with every retry. Defaults to 2.
+//! factor = 2.0
+//! # Exponential only. Optional upper bound on the delay.
+//! max_delay_secs = 3600
+//! # Optional. Fraction of each delay to randomly add or remove, between 0 and 1. Defaults to 0.
+//! jitter = 0.1
+//!
+//! # Optional. Builds to retry on their own schedule, in addition to those matching
+//! # ci_retry_trigger. The backoff defaults to ci_backoff.
+//! [[ci_retry_rules]]
+//! trigger = "integration"
+//! backoff = { strategy = "fixed", delay_secs = 900 }
+//! ```
+//!
+//! A failed build is rebuilt on the first configured CI server that recognizes its URL, trying
+//! Jenkins last since any URL ending in a build number looks like a Jenkins build. Builds that no
+//! configured CI server recognizes are skipped with a warning.
+//!
+//! Each build of a commit has its own retry limit and backoff, so a flaky build that keeps failing
+//! doesn't hold back the retries of another. A failed build matching more than one trigger is retried
+//! on the schedule of the first rule in `ci_retry_rules` that it matches, with `ci_retry_trigger`
+//! tried last. The jitter of each delay is the same from one run to the next. Builds that are waiting
+//! for their backoff to end are logged with the time until their next rebuild, e.g. "Next rebuild of
+//! integration tests in 12m", and reported in the summary with a `state` of `waiting` and the time in
+//! `next_rebuild`. Delays are capped at a year.
+//!
+//! `ci_retry_limit` is the exact number of times each build is retried, and only the first retry is
+//! immediate. Earlier versions retried a build twice in a row without waiting and up to
+//! `ci_retry_limit + 1` times in total. Retry histories recorded by those versions count one retry
+//! fewer than were made.
+//!
+//! ### Jenkins
+//!
+//! ```toml
+//! # Optional. Restart only the failed stage of declarative pipelines. Defaults to false.
+//! jenkins_restart_failed_stage = false
+//! # Optional. Only retry builds whose failed tests all match one of these regexes, matched against
+//! # "ClassName.testName", or whose log matches one of the log regexes. By default, any failure is
+//! # retried.
+//! jenkins_flaky_tests = []
+//! jenkins_flaky_log_patterns = []
+//!
+//! # Credentials for each Jenkins server, by the URL of the server. A "*." wildcard at the start of
+//! # the host matches any subdomain.
+//! [jenkins_servers."https://jenkins-a.example.com"]
+//! username = ""
+//! password = ""
+//! [jenkins_servers."https://*.ci.example.com"]
+//! username = ""
+//! password = ""
+//! ```
+//!
+//! For a single Jenkins server, its URL and credentials can be given at the top level instead:
+//!
+//! ```toml
+//! jenkins_url = "https://jenkins.example.com"
 //! jenkins_username = ""
 //! jenkins_password = ""
-//! # Regex trigger to search against the build name
-//! jenkins_retry_trigger = ""
-//! # Optional. Defaults to 10.
-//! jenkins_retry_limit = ""
 //! ```
+//!
+//! Credentials are only sent to the Jenkins servers they're configured for. A build is matched to the
+//! server with the most specific URL: the longest path, then an exact host over a wildcard. Failed
+//! builds on other servers aren't rebuilt, and queued rebuilds that Jenkins reports on other servers
+//! aren't followed. `jenkins_username` and `jenkins_password` require `jenkins_url`, as they used to
+//! be sent to whatever server a build status pointed at.
+//!
+//! Jenkins servers with CSRF protection are supported: crabby-merge fetches a crumb from the server's
+//! crumb issuer and reuses it, along with the session cookie it belongs to, for every rebuild on that
+//! server. The crumb issuer and queue are found at the server's root URL, the part of a build URL
+//! before `/job/`. They're only used if the root URL is itself on a configured server.
+//!
+//! Pipeline builds are rebuilt with Pipeline replay, which reruns the same script at the same SCM
+//! revision with the original parameters and causes. Other builds without parameters are rebuilt with
+//! the [Rebuilder](https://plugins.jenkins.io/rebuild/) plugin if it's installed, or by triggering the
+//! job again otherwise. Builds with parameters are triggered again with the same parameters, which is
+//! also the fallback for Pipeline builds when replay isn't available. Rebuilds are refused if a
+//! parameter can't be replayed, e.g. passwords and files, whose values Jenkins doesn't expose.
+//!
+//! With `jenkins_restart_failed_stage` enabled, failed declarative Pipeline builds are restarted from
+//! the first failed stage, as reported by the workflow API (`wfapi/describe`), so that the stages
+//! before it aren't run again. If the build has no failed stage or can't be restarted from it, e.g.
+//! because the Pipeline isn't declarative, crabby-merge falls back to rebuilding it as described
+//! above.
+//!
+//! When `jenkins_flaky_tests` or `jenkins_flaky_log_patterns` is set, crabby-merge looks at why a build
+//! failed before retrying it. The build is only retried if it has failed tests in its test report and
+//! all of them are known to be flaky, or if its console log matches one of the log regexes. Otherwise,
+//! crabby-merge comments on the pull request once that the build has a genuine failure and it isn't
+//! retrying it. This is only checked once the build is due for a retry, and only the last MiB of the
+//! console log is searched.
+//!
+//! After triggering a rebuild, crabby-merge follows the new build through the Jenkins queue for up to
+//! 30 seconds to learn its URL, and records it in the retry history. Later runs report rebuilds that
+//! are still queued or running in the summary, with a `state` of `queued` or `running` instead of
+//! `triggered`, and don't trigger the build again until the rebuild has finished. Replays and Rebuilder
+//! don't say which queue item they created, so crabby-merge only follows a queued build whose cause
+//! names the failed build and the Jenkins user. If there is none, the rebuild isn't followed.
+//!
+//! ### GitHub Actions
+//!
+//! ```toml
+//! [github]
+//! # Token with permission to re-run workflows
+//! token = ""
+//! # Optional. For GitHub Enterprise Server, the URL of the instance. Defaults to https://github.com.
+//! url = "https://github.com"
+//! # Optional. Defaults to https://api.github.com for github.com and {url}/api/v3 otherwise.
+//! api_url = "https://api.github.com"
+//! ```
+//!
+//! Only the failed jobs of a workflow run are re-run, as a new attempt of the same run.
+//!
+//! ### GitLab CI
+//!
+//! ```toml
+//! [gitlab]
+//! url = "https://gitlab.example.com"
+//! # Access token with the api scope
+//! token = ""
+//! ```
+//!
+//! Failed pipelines are retried, which retries their failed jobs. Failed jobs are retried on their own.
+//!
+//! ### TeamCity
+//!
+//! ```toml
+//! [teamcity]
+//! url = "https://teamcity.example.com"
+//! # Access token of a user that can run builds
+//! token = ""
+//! ```
+//!
+//! Failed builds are rebuilt by queueing a build of the same configuration and branch, with the same
+//! parameters and at the same changes.
+//!
+//! ### Buildkite
+//!
+//! ```toml
+//! [buildkite]
+//! # API access token with the read_builds and write_builds scopes
+//! token = ""
+//! ```
+//!
+//! Failed builds are rebuilt with Buildkite's rebuild, which creates a new build of the same commit.
+//! Rebuilds on these CI servers are recorded in the retry history and reported in the summary like
+//! Jenkins rebuilds.
 
-use crabby_merge::bitbucket;
-#[cfg(feature = "jenkins")]
+use crabby_merge::bitbucket::{self, AuthError};
+#[cfg(feature = "notify")]
+use crabby_merge::desktop;
+#[cfg(feature = "email")]
+use crabby_merge::email;
+#[cfg(feature = "ci")]
 use crabby_merge::history_file;
+use crabby_merge::logging::{self, LogFormat};
+#[cfg(feature = "metrics")]
+use crabby_merge::metrics;
+use crabby_merge::notifier;
 use crabby_merge::search;
-use crabby_merge::Config;
+use crabby_merge::summary::{ExitStatus, RunSummary};
+use crabby_merge::{Config, RuntimeFlavor};
 
-use anyhow::Result;
 use cfg_if::cfg_if;
-use futures::future;
 use log::*;
-use simple_logger::SimpleLogger;
+use std::process::ExitCode;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime;
 
-#[tokio::main(flavor = "current_thread")]
 #[doc(hidden)]
-async fn main() -> Result<()> {
-    SimpleLogger::new()
-        .with_level(log::LevelFilter::Info)
-        .init()
-        .unwrap();
+fn main() -> ExitCode {
+    let config = Config::load_from_default_file();
+    let (log_level, log_format) = match &config {
+        Ok(config) => (config.log_level, config.log_format),
+        // Still log the configuration error
+        Err(_) => (LevelFilter::Info, LogFormat::Text),
+    };
+    logging::init(log_level, log_format).unwrap();
 
-    let config = Config::load_from_default_file()?;
-    let api = Arc::new(bitbucket::Client::new(
-        config.bitbucket_url.clone(),
-        &config.bitbucket_api_token,
-    ));
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{:#}", e);
+            return ExitStatus::ConfigError.into();
+        }
+    };
+    let mut runtime = match config.runtime {
+        RuntimeFlavor::CurrentThread => runtime::Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => {
+            let mut builder = runtime::Builder::new_multi_thread();
+            if let Some(worker_threads) = config.worker_threads {
+                builder.worker_threads(worker_threads);
+            }
+            builder
+        }
+    };
+    match runtime.enable_all().build() {
+        Ok(runtime) => runtime.block_on(run(config)).into(),
+        Err(e) => {
+            error!("Could not start runtime: {}", e);
+            ExitStatus::Failure.into()
+        }
+    }
+}
+
+/// Searches for PR's and checks them for the merge trigger, repeatedly if a poll interval is
+/// configured
+async fn run(config: Config) -> ExitStatus {
+    let mut api = bitbucket::Client::new(config.bitbucket_url.clone(), &config.bitbucket_api_token)
+        .with_page_options(config.page_options);
+    if let Some(requests_per_second) = config.requests_per_second {
+        api = api.with_rate_limit(requests_per_second);
+    }
+    let api = Arc::new(api);
 
     // Wrap config in an Arc to be able to pass it across async tasks
     let config = Arc::new(config);
 
-    // Return the number of PR's checked
-    let f1 = async {
-        if !config.check_own_prs {
-            return 0;
-        }
-        let api = Arc::clone(&api);
-        let config = Arc::clone(&config);
-        let n_prs = async move {
-            match search::own_prs(api, config).await {
-                Ok(n) => n,
-                Err(e) => {
-                    error!("{}", e);
-                    0
-                }
+    cfg_if! {
+        if #[cfg(feature = "metrics")] {
+            if let Some(port) = config.metrics_port {
+                tokio::spawn(async move {
+                    if let Err(e) = metrics::serve(port).await {
+                        error!("Metrics server failed: {:#}", e);
+                    }
+                });
             }
         }
-        .await;
-        info!("Own PR's checked: {}", n_prs);
-        n_prs
-    };
+    }
 
-    // Return the number of PR's checked
-    let f2 = async {
-        if !config.check_approved_prs {
-            return 0;
-        }
-        let api = Arc::clone(&api);
-        let config = Arc::clone(&config);
-        let n_prs = async move {
-            match search::approved_prs(api, config).await {
-                Ok(n) => n,
-                Err(e) => {
-                    error!("{}", e);
-                    0
+    loop {
+        let status = run_once(api.clone(), config.clone()).await;
+        let interval = match config.poll_interval_secs {
+            // A rejected API token won't fix itself
+            Some(interval) if status != ExitStatus::AuthFailure => interval,
+            _ => return status,
+        };
+        info!("Next run in {} seconds", interval);
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+/// Searches for PR's and checks them for the merge trigger once
+async fn run_once(api: Arc<bitbucket::Client>, config: Arc<Config>) -> ExitStatus {
+    let summary_json = config.summary_json;
+    let status = match search::run(api, config).await {
+        Ok(results) => {
+            cfg_if! {
+                if #[cfg(feature = "metrics")] {
+                    metrics::record_run(&results);
+                }
+            }
+            for (search, n_prs) in &results.counts {
+                info!("{} PR's checked: {}", search, n_prs);
+            }
+            let summary = RunSummary::new(&results);
+            info!(
+                "Triggered: {}, merged: {}, blocked: {}, rebuilds: {}, errors: {}",
+                summary.triggered,
+                summary.merged.len(),
+                summary.blocked.len(),
+                summary.rebuilds.len(),
+                summary.errors.len()
+            );
+            if summary_json {
+                match serde_json::to_string(&summary) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => error!("Could not serialize summary: {}", e),
                 }
             }
+            summary.exit_status()
+        }
+        Err(e) => {
+            error!("{:#}", e);
+            if e.is::<AuthError>() {
+                ExitStatus::AuthFailure
+            } else {
+                ExitStatus::Failure
+            }
         }
-        .await;
-        info!("Approved PR's checked: {}", n_prs);
-        n_prs
     };
 
-    let _ = future::join(f1, f2).await;
-
     cfg_if! {
-        if #[cfg(feature = "jenkins")] {
+        if #[cfg(feature = "ci")] {
             history_file::decruft().ok();
         }
     }
+    notifier::decruft().ok();
+    cfg_if! {
+        if #[cfg(feature = "email")] {
+            email::decruft().ok();
+        }
+    }
+    cfg_if! {
+        if #[cfg(feature = "notify")] {
+            desktop::decruft().ok();
+        }
+    }
     info!("🚢 all done");
-    Ok(())
+    status
 }
@@ -0,0 +1,381 @@
+//! Client-side checks that must pass before crabby-merge attempts to merge a pull request
+//!
+//! These are useful for repositories without equivalent server-side merge checks.
+
+use crate::bitbucket::{self, CommentSeverity, CommentState, ParticipantStatus, PullRequest};
+use crate::codeowners;
+use crate::stack;
+use crate::Config;
+
+use anyhow::Result;
+use std::fmt;
+
+/// Maximum number of characters of a blocker comment to include in its description
+const COMMENT_PREVIEW_LEN: usize = 50;
+
+/// Reviewer requirements that must be met before merging
+#[derive(Debug, Clone, Default)]
+pub struct ReviewerRules {
+    /// Minimum number of approvals
+    pub min_approvals: u32,
+    /// Whether a reviewer marking the pull request as "needs work" blocks the merge
+    pub block_on_needs_work: bool,
+    /// Users that must all approve
+    pub required_reviewers: Vec<String>,
+    /// Named groups of users. At least one member of each group must approve.
+    pub required_groups: Vec<(String, Vec<String>)>,
+}
+
+/// A reason a pull request is not allowed to be merged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Blocker {
+    TooFewApprovals {
+        required: u32,
+        actual: u32,
+    },
+    NeedsWork(Vec<String>),
+    MissingReviewer(String),
+    MissingGroupApproval(String),
+    Draft,
+    OpenTasks(u64),
+    /// Unresolved blocker comments, each described by its id and a preview of its text
+    BlockerComments(Vec<(u64, String)>),
+    /// Changed paths whose owners, according to the CODEOWNERS file, haven't approved
+    MissingCodeOwnerApproval {
+        owners: Vec<String>,
+        paths: Vec<String>,
+    },
+    /// An open pull request that must be merged first
+    OpenDependency(String),
+}
+
+impl Blocker {
+    /// Returns a short, stable name for the kind of blocker, e.g. for use as a metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Blocker::TooFewApprovals { .. } => "too_few_approvals",
+            Blocker::NeedsWork(_) => "needs_work",
+            Blocker::MissingReviewer(_) => "missing_reviewer",
+            Blocker::MissingGroupApproval(_) => "missing_group_approval",
+            Blocker::Draft => "draft",
+            Blocker::OpenTasks(_) => "open_tasks",
+            Blocker::BlockerComments(_) => "blocker_comments",
+            Blocker::MissingCodeOwnerApproval { .. } => "missing_code_owner_approval",
+            Blocker::OpenDependency(_) => "open_dependency",
+        }
+    }
+}
+
+impl fmt::Display for Blocker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Blocker::TooFewApprovals { required, actual } => {
+                write!(f, "{} of {} required approvals", actual, required)
+            }
+            Blocker::NeedsWork(users) => write!(f, "marked as needs work by {}", users.join(", ")),
+            Blocker::MissingReviewer(user) => write!(f, "missing approval from {}", user),
+            Blocker::MissingGroupApproval(group) => {
+                write!(f, "missing approval from a member of {}", group)
+            }
+            Blocker::Draft => write!(f, "pull request is a draft"),
+            Blocker::OpenTasks(n) => write!(f, "{} open task(s)", n),
+            Blocker::BlockerComments(comments) => {
+                write!(f, "unresolved blocker comment(s): ")?;
+                let descriptions: Vec<String> = comments
+                    .iter()
+                    .map(|(id, text)| format!("#{} \"{}\"", id, text))
+                    .collect();
+                write!(f, "{}", descriptions.join(", "))
+            }
+            Blocker::MissingCodeOwnerApproval { owners, paths } => {
+                write!(f, "missing approval from one of {}", owners.join(", "))?;
+                match paths.as_slice() {
+                    [path] => write!(f, " for {}", path),
+                    [path, rest @ ..] => {
+                        write!(f, " for {} and {} other path(s)", path, rest.len())
+                    }
+                    [] => Ok(()),
+                }
+            }
+            Blocker::OpenDependency(url) => write!(f, "depends on open pull request {}", url),
+        }
+    }
+}
+
+/// Evaluates `rules` against a pull request and returns everything blocking the merge
+pub fn reviewer_blockers(pr: &PullRequest, rules: &ReviewerRules) -> Vec<Blocker> {
+    let mut blockers = Vec::new();
+    let approvers = pr.users_with_status(ParticipantStatus::Approved);
+
+    let n_approvals = approvers.len() as u32;
+    if n_approvals < rules.min_approvals {
+        blockers.push(Blocker::TooFewApprovals {
+            required: rules.min_approvals,
+            actual: n_approvals,
+        });
+    }
+    if rules.block_on_needs_work {
+        let needs_work = pr.users_with_status(ParticipantStatus::NeedsWork);
+        if !needs_work.is_empty() {
+            blockers.push(Blocker::NeedsWork(
+                needs_work.into_iter().map(String::from).collect(),
+            ));
+        }
+    }
+    for reviewer in &rules.required_reviewers {
+        if !approvers.contains(reviewer.as_str()) {
+            blockers.push(Blocker::MissingReviewer(reviewer.clone()));
+        }
+    }
+    for (group, members) in &rules.required_groups {
+        if !members
+            .iter()
+            .any(|member| approvers.contains(member.as_str()))
+        {
+            blockers.push(Blocker::MissingGroupApproval(group.clone()));
+        }
+    }
+    blockers
+}
+
+/// Returns the draft and open task blockers of a pull request
+pub fn state_blockers(
+    pr: &PullRequest,
+    block_on_draft: bool,
+    block_on_open_tasks: bool,
+) -> Vec<Blocker> {
+    let mut blockers = Vec::new();
+    if block_on_draft && pr.draft {
+        blockers.push(Blocker::Draft);
+    }
+    let n_open_tasks = pr.open_task_count();
+    if block_on_open_tasks && n_open_tasks > 0 {
+        blockers.push(Blocker::OpenTasks(n_open_tasks));
+    }
+    blockers
+}
+
+/// Returns a blocker listing any unresolved blocker comments on a pull request
+async fn blocker_comments(api: &bitbucket::Client, pr: &PullRequest) -> Result<Option<Blocker>> {
+    let comments: Vec<(u64, String)> = api
+        .get_pr_comment_list(pr)
+        .await?
+        .into_iter()
+        .filter(|comment| {
+            comment.severity == CommentSeverity::Blocker && comment.state == CommentState::Open
+        })
+        .map(|comment| {
+            let first_line = comment.text.lines().next().unwrap_or_default();
+            (
+                comment.id,
+                first_line.chars().take(COMMENT_PREVIEW_LEN).collect(),
+            )
+        })
+        .collect();
+    if comments.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(Blocker::BlockerComments(comments)))
+    }
+}
+
+/// Runs all configured client-side checks against a pull request and returns everything blocking
+/// the merge
+pub async fn blockers(
+    api: &bitbucket::Client,
+    pr: &PullRequest,
+    config: &Config,
+) -> Result<Vec<Blocker>> {
+    let mut blockers = state_blockers(pr, config.block_on_draft, config.block_on_open_tasks);
+    blockers.extend(stack::dependency_blockers(api, pr, config).await?);
+    blockers.extend(reviewer_blockers(pr, &config.reviewer_rules));
+    if config.block_on_blocker_comments {
+        blockers.extend(blocker_comments(api, pr).await?);
+    }
+    if config.check_codeowners {
+        blockers.extend(
+            codeowners::blockers(api, pr, &config.codeowners_path, &config.reviewer_groups).await?,
+        );
+    }
+    Ok(blockers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+    use crate::test_server::{self, Response};
+    use serde_json::json;
+
+    fn pr_with_reviewers(reviewers: &[(&str, &str)], participants: &[(&str, &str)]) -> PullRequest {
+        let to_json = |people: &[(&str, &str)]| {
+            people
+                .iter()
+                .map(|(name, status)| json!({"user": {"name": name}, "status": status}))
+                .collect::<Vec<_>>()
+        };
+        test_fixtures::pr(
+            1,
+            json!({"reviewers": to_json(reviewers), "participants": to_json(participants)}),
+        )
+    }
+
+    #[test]
+    fn no_rules() {
+        let pr = pr_with_reviewers(&[("alice", "NEEDS_WORK")], &[]);
+        assert!(reviewer_blockers(&pr, &ReviewerRules::default()).is_empty());
+    }
+
+    #[test]
+    fn min_approvals_counts_participants() {
+        let rules = ReviewerRules {
+            min_approvals: 2,
+            ..Default::default()
+        };
+        let pr = pr_with_reviewers(&[("alice", "APPROVED")], &[("bob", "UNAPPROVED")]);
+        assert_eq!(
+            reviewer_blockers(&pr, &rules),
+            vec![Blocker::TooFewApprovals {
+                required: 2,
+                actual: 1
+            }]
+        );
+        let pr = pr_with_reviewers(&[("alice", "APPROVED")], &[("bob", "APPROVED")]);
+        assert!(reviewer_blockers(&pr, &rules).is_empty());
+    }
+
+    #[test]
+    fn needs_work() {
+        let rules = ReviewerRules {
+            block_on_needs_work: true,
+            ..Default::default()
+        };
+        let pr = pr_with_reviewers(&[("alice", "APPROVED"), ("bob", "NEEDS_WORK")], &[]);
+        assert_eq!(
+            reviewer_blockers(&pr, &rules),
+            vec![Blocker::NeedsWork(vec![String::from("bob")])]
+        );
+    }
+
+    #[test]
+    fn required_reviewers_and_groups() {
+        let rules = ReviewerRules {
+            required_reviewers: vec![String::from("alice")],
+            required_groups: vec![(
+                String::from("owners"),
+                vec![String::from("carol"), String::from("dave")],
+            )],
+            ..Default::default()
+        };
+        let pr = pr_with_reviewers(&[("alice", "UNAPPROVED"), ("dave", "APPROVED")], &[]);
+        assert_eq!(
+            reviewer_blockers(&pr, &rules),
+            vec![Blocker::MissingReviewer(String::from("alice"))]
+        );
+        let pr = pr_with_reviewers(&[("alice", "APPROVED"), ("bob", "APPROVED")], &[]);
+        assert_eq!(
+            reviewer_blockers(&pr, &rules),
+            vec![Blocker::MissingGroupApproval(String::from("owners"))]
+        );
+    }
+
+    fn pr_with_state(draft: bool, open_tasks: u64) -> PullRequest {
+        test_fixtures::pr(
+            1,
+            json!({"draft": draft, "properties": {"openTaskCount": open_tasks}}),
+        )
+    }
+
+    fn comment(
+        id: u64,
+        severity: &str,
+        state: &str,
+        replies: serde_json::Value,
+    ) -> serde_json::Value {
+        json!({
+            "id": id,
+            "author": {"name": "alice"},
+            "text": format!("comment {}\nmore details", id),
+            "severity": severity,
+            "state": state,
+            "comments": replies,
+        })
+    }
+
+    #[test]
+    fn drafts() {
+        let draft = pr_with_state(true, 0);
+        assert_eq!(state_blockers(&draft, true, true), vec![Blocker::Draft]);
+        assert!(state_blockers(&draft, false, true).is_empty());
+        assert!(state_blockers(&pr_with_state(false, 0), true, true).is_empty());
+    }
+
+    #[test]
+    fn open_tasks() {
+        let pr = pr_with_state(false, 2);
+        assert_eq!(state_blockers(&pr, true, true), vec![Blocker::OpenTasks(2)]);
+        assert!(state_blockers(&pr, true, false).is_empty());
+        assert!(state_blockers(&test_fixtures::pr(1, json!({})), true, true).is_empty());
+    }
+
+    #[tokio::test]
+    async fn unresolved_blocker_comments() {
+        let (base_url, mut requests) = test_server::serve(|request| {
+            if !request
+                .path
+                .starts_with("/rest/api/1.0/projects/PROJ/repos/app/pull-requests/1/activities")
+            {
+                return Response::status(404);
+            }
+            Response::json(json!({
+                "isLastPage": true,
+                "values": [
+                    {"action": "APPROVED"},
+                    {"action": "COMMENTED", "comment": comment(1, "BLOCKER", "RESOLVED", json!([]))},
+                    {"action": "COMMENTED", "comment": comment(2, "NORMAL", "OPEN", json!([
+                        comment(3, "BLOCKER", "OPEN", json!([])),
+                        comment(4, "NORMAL", "OPEN", json!([
+                            comment(5, "BLOCKER", "RESOLVED", json!([])),
+                        ])),
+                    ]))},
+                    {"action": "COMMENTED", "comment": comment(6, "BLOCKER", "OPEN", json!([]))},
+                ],
+            }))
+        })
+        .await;
+        let api = bitbucket::Client::new(base_url, "token");
+        let pr = pr_with_state(false, 0);
+        assert_eq!(
+            blocker_comments(&api, &pr).await.unwrap(),
+            Some(Blocker::BlockerComments(vec![
+                (3, String::from("comment 3")),
+                (6, String::from("comment 6")),
+            ]))
+        );
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.header("authorization"), Some("Bearer token"));
+    }
+
+    #[tokio::test]
+    async fn resolved_blocker_comments() {
+        let (base_url, _requests) = test_server::serve(|_| {
+            Response::json(json!({
+                "isLastPage": true,
+                "values": [
+                    {"action": "COMMENTED", "comment": comment(1, "BLOCKER", "RESOLVED", json!([
+                        comment(2, "NORMAL", "OPEN", json!([])),
+                    ]))},
+                ],
+            }))
+        })
+        .await;
+        let api = bitbucket::Client::new(base_url, "token");
+        assert_eq!(
+            blocker_comments(&api, &pr_with_state(false, 0))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,345 @@
+#![cfg(feature = "metrics")]
+//! Prometheus metrics and a minimal HTTP server that exposes them at `/metrics`
+
+use crate::ci::RebuildState;
+use crate::search::{MergeFailure, Outcome, RunResults};
+
+use anyhow::Result;
+use log::*;
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use reqwest::{Method, Response, StatusCode};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+static PRS_SCANNED: Lazy<IntCounter> = Lazy::new(|| {
+    register(IntCounter::new("crabby_merge_prs_scanned_total", "Pull requests checked").unwrap())
+});
+static TRIGGERS: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "crabby_merge_triggers_total",
+            "Pull requests found with the merge trigger",
+        )
+        .unwrap(),
+    )
+});
+static MERGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("crabby_merge_merges_total", "Merge attempts by result"),
+            &["result"],
+        )
+        .unwrap(),
+    )
+});
+static VETOES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "crabby_merge_vetoes_total",
+                "Reasons triggered pull requests were not merged",
+            ),
+            &["reason"],
+        )
+        .unwrap(),
+    )
+});
+static REBUILDS: Lazy<IntCounter> = Lazy::new(|| {
+    register(IntCounter::new("crabby_merge_ci_rebuilds_total", "CI builds retried").unwrap())
+});
+static ERRORS: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "crabby_merge_errors_total",
+            "Failed searches and pull request checks",
+        )
+        .unwrap(),
+    )
+});
+static BITBUCKET_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "crabby_merge_bitbucket_request_duration_seconds",
+                "Bitbucket API request latency",
+            ),
+            &["method", "endpoint"],
+        )
+        .unwrap(),
+    )
+});
+static BITBUCKET_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "crabby_merge_bitbucket_request_errors_total",
+                "Bitbucket API requests that failed or returned an error status",
+            ),
+            &["method", "endpoint"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Registers a metric with the registry served by [`serve`]
+fn register<T>(metric: T) -> T
+where
+    T: prometheus::core::Collector + Clone + 'static,
+{
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+/// Replaces the variable parts of a Bitbucket API path with placeholders to keep the number of
+/// distinct label values bounded
+pub fn endpoint_label(endpoint: &str) -> String {
+    let mut segments = Vec::new();
+    let mut placeholder = None;
+    for segment in endpoint.split('/') {
+        if let Some(placeholder) = placeholder.take() {
+            segments.push(placeholder);
+            continue;
+        }
+        placeholder = match segment {
+            "projects" => Some("{project}"),
+            "repos" => Some("{repo}"),
+            "pull-requests" => Some("{id}"),
+            "commits" => Some("{hash}"),
+            "raw" | "browse" => {
+                segments.push(segment);
+                segments.push("{path}");
+                break;
+            }
+            _ => None,
+        };
+        segments.push(segment);
+    }
+    segments.join("/")
+}
+
+/// Records the latency and result of a Bitbucket API request. Responses with `allowed_status`
+/// aren't counted as errors, e.g. a 404 for a file that is only read if it exists.
+pub fn observe_bitbucket_request(
+    method: &Method,
+    endpoint: &str,
+    duration: Duration,
+    response: &reqwest::Result<Response>,
+    allowed_status: Option<StatusCode>,
+) {
+    let endpoint = endpoint_label(endpoint);
+    let labels = [method.as_str(), endpoint.as_str()];
+    BITBUCKET_LATENCY
+        .with_label_values(&labels)
+        .observe(duration.as_secs_f64());
+    let failed = match response {
+        Ok(response) if Some(response.status()) == allowed_status => false,
+        Ok(response) => response.status().is_client_error() || response.status().is_server_error(),
+        Err(_) => true,
+    };
+    if failed {
+        BITBUCKET_ERRORS.with_label_values(&labels).inc();
+    }
+}
+
+/// Records the outcomes of a run
+pub fn record_run(results: &RunResults) {
+    PRS_SCANNED.inc_by(results.prs.len() as u64);
+    ERRORS.inc_by(results.errors.len() as u64);
+    for pr in &results.prs {
+        match &pr.outcome {
+            Outcome::NoTrigger | Outcome::Skipped => (),
+            Outcome::Blocked(blockers) => {
+                TRIGGERS.inc();
+                for blocker in blockers {
+                    VETOES.with_label_values(&[blocker.kind()]).inc();
+                }
+            }
+            Outcome::Merged => {
+                TRIGGERS.inc();
+                MERGES.with_label_values(&["success"]).inc();
+            }
+            Outcome::MergeFailed {
+                reason, rebuilds, ..
+            } => {
+                TRIGGERS.inc();
+                MERGES.with_label_values(&["failure"]).inc();
+                match reason {
+                    MergeFailure::Conflicts => VETOES.with_label_values(&["conflicts"]).inc(),
+                    MergeFailure::Vetoed => VETOES.with_label_values(&["server_veto"]).inc(),
+                    MergeFailure::Other => (),
+                }
+                let triggered = rebuilds
+                    .iter()
+                    .filter(|rebuild| rebuild.state == RebuildState::Triggered)
+                    .count();
+                REBUILDS.inc_by(triggered as u64);
+            }
+            Outcome::Error(_) | Outcome::Panicked(_) => ERRORS.inc(),
+        }
+    }
+}
+
+/// Returns all metrics in the Prometheus text format
+fn encode() -> String {
+    // Make sure every metric is registered, even if it hasn't been touched yet
+    Lazy::force(&PRS_SCANNED);
+    Lazy::force(&TRIGGERS);
+    Lazy::force(&MERGES);
+    Lazy::force(&VETOES);
+    Lazy::force(&REBUILDS);
+    Lazy::force(&ERRORS);
+    Lazy::force(&BITBUCKET_LATENCY);
+    Lazy::force(&BITBUCKET_ERRORS);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+/// Serves metrics at `/metrics` on `port` until an error occurs
+pub async fn serve(port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    info!("Serving metrics on port {}", port);
+    serve_listener(listener).await
+}
+
+async fn serve_listener(listener: TcpListener) -> Result<()> {
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&mut socket).await {
+                debug!("Metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Answers a single HTTP request. Only `GET /metrics` is supported.
+async fn handle_connection(socket: &mut TcpStream) -> std::io::Result<()> {
+    let mut buffer = [0; 1024];
+    let n = socket.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..n]);
+    let (status, body) = if request.starts_with("GET /metrics ") {
+        ("200 OK", encode())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitbucket;
+    use crate::merge_checks::Blocker;
+    use crate::search::PrResult;
+    use crate::test_fixtures;
+    use crate::test_server::{self, Response};
+    use serde_json::json;
+
+    #[test]
+    fn endpoint_labels() {
+        assert_eq!(
+            endpoint_label("/rest/api/1.0/dashboard/pull-requests"),
+            "/rest/api/1.0/dashboard/pull-requests"
+        );
+        assert_eq!(
+            endpoint_label("/rest/api/1.0/projects/PROJ/repos/repo/pull-requests/12/activities"),
+            "/rest/api/1.0/projects/{project}/repos/{repo}/pull-requests/{id}/activities"
+        );
+        assert_eq!(
+            endpoint_label("/rest/build-status/1.0/commits/abc123"),
+            "/rest/build-status/1.0/commits/{hash}"
+        );
+        assert_eq!(
+            endpoint_label("/rest/api/1.0/projects/PROJ/repos/repo/raw/docs/CODEOWNERS"),
+            "/rest/api/1.0/projects/{project}/repos/{repo}/raw/{path}"
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        record_run(&RunResults {
+            counts: Vec::new(),
+            prs: vec![PrResult {
+                url: String::from("a"),
+                outcome: Outcome::Blocked(vec![Blocker::Draft]),
+            }],
+            errors: Vec::new(),
+        });
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener));
+
+        let request = |path: &'static str| async move {
+            let mut socket = TcpStream::connect(address).await.unwrap();
+            socket
+                .write_all(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            socket.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = request("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("crabby_merge_vetoes_total{reason=\"draft\"}"));
+        assert!(request("/").await.starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn server_vetoes() {
+        let merge_failed = |reason| PrResult {
+            url: String::from("a"),
+            outcome: Outcome::MergeFailed {
+                error: String::from("PR not ready to merge"),
+                reason,
+                rebuilds: Vec::new(),
+                exhausted_builds: Vec::new(),
+            },
+        };
+        let vetoes = |reason| VETOES.with_label_values(&[reason]).get();
+        let (conflicts, server_vetoes) = (vetoes("conflicts"), vetoes("server_veto"));
+        record_run(&RunResults {
+            counts: Vec::new(),
+            prs: vec![
+                merge_failed(MergeFailure::Conflicts),
+                merge_failed(MergeFailure::Vetoed),
+                merge_failed(MergeFailure::Other),
+            ],
+            errors: Vec::new(),
+        });
+        assert_eq!(vetoes("conflicts"), conflicts + 1);
+        assert_eq!(vetoes("server_veto"), server_vetoes + 1);
+    }
+
+    #[tokio::test]
+    async fn missing_files_are_not_errors() {
+        let (base_url, _requests) = test_server::serve(|_| Response::status(404)).await;
+        let api = bitbucket::Client::new(base_url, "token");
+        let pr = test_fixtures::pr(1, json!({}));
+        let errors = |endpoint| BITBUCKET_ERRORS.with_label_values(&["GET", endpoint]).get();
+        let raw = "/rest/api/1.0/projects/{project}/repos/{repo}/raw/{path}";
+        let pr_endpoint = "/rest/api/1.0/projects/{project}/repos/{repo}/pull-requests/{id}";
+        let (raw_errors, pr_errors) = (errors(raw), errors(pr_endpoint));
+        assert_eq!(api.get_target_file(&pr, "CODEOWNERS").await.unwrap(), None);
+        assert!(api.get_pr_in_repo(&pr, 2).await.is_err());
+        assert_eq!(errors(raw), raw_errors);
+        assert!(errors(pr_endpoint) > pr_errors);
+    }
+}
@@ -0,0 +1,549 @@
+//! Notifications about merges, blocked merges and rebuilds, sent to chat services and webhooks
+//!
+//! The last block notified about for each pull request is stored in the data directory so that
+//! the same block isn't notified about on every run.
+
+use crate::bitbucket::PullRequest;
+use crate::ci::RebuildState;
+use crate::data_dir;
+#[cfg(feature = "notify")]
+use crate::desktop::DesktopNotifier;
+#[cfg(feature = "email")]
+use crate::email::EmailNotifier;
+use crate::search::Outcome;
+
+use anyhow::Result;
+use cfg_if::cfg_if;
+use futures::future;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+#[cfg(feature = "email")]
+use std::sync::Arc;
+use std::time::Duration;
+
+const STATE_DIR: &str = "notifier";
+/// State files that haven't been updated for this long belong to pull requests that are gone
+const STALENESS_THRESHOLD: time::Duration = time::Duration::days(5);
+
+/// Kinds of events that notifications are sent for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A pull request was merged
+    Merged,
+    /// A pull request with the merge trigger failed a merge check
+    Blocked,
+    /// Bitbucket refused to merge a pull request, e.g. because of conflicts or failed builds
+    MergeFailed,
+    /// A failed build was rebuilt
+    Rebuilt,
+    /// A pull request could not be checked
+    Error,
+}
+
+impl EventKind {
+    fn default_template(self) -> &'static str {
+        match self {
+            EventKind::Merged => "crabby-merged PR #{id} into {target}: {url}",
+            EventKind::Blocked => "merge blocked for PR #{id}: {reason} ({url})",
+            EventKind::MergeFailed => "merge blocked: {reason} ({url})",
+            EventKind::Rebuilt => "rebuilt {build} for PR #{id} ({url})",
+            EventKind::Error => "could not check PR #{id}: {reason} ({url})",
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EventKind::Merged => "merged",
+            EventKind::Blocked => "blocked",
+            EventKind::MergeFailed => "merge_failed",
+            EventKind::Rebuilt => "rebuilt",
+            EventKind::Error => "error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something that happened to a pull request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Event {
+    #[serde(rename = "event")]
+    pub kind: EventKind,
+    pub pr_url: String,
+    pub pr_id: u32,
+    pub title: String,
+    pub project: String,
+    pub repo: String,
+    pub author: Option<String>,
+    pub target_branch: Option<String>,
+    /// Why the pull request wasn't merged or couldn't be checked
+    pub reason: Option<String>,
+    /// Name of the build that was rebuilt
+    pub build: Option<String>,
+}
+
+impl Event {
+    pub fn new(kind: EventKind, pr: &PullRequest) -> Self {
+        Self {
+            kind,
+            pr_url: pr.url().unwrap_or_default().to_owned(),
+            pr_id: pr.id(),
+            title: pr.title.clone(),
+            project: pr.project_key().unwrap_or_default().to_owned(),
+            repo: pr.repo_slug().unwrap_or_default().to_owned(),
+            author: pr.author().map(String::from),
+            target_branch: pr.target_branch().map(String::from),
+            reason: None,
+            build: None,
+        }
+    }
+
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+
+    /// Returns the events for the outcome of checking a pull request
+    pub fn from_outcome(pr: &PullRequest, outcome: &Outcome) -> Vec<Self> {
+        match outcome {
+            Outcome::NoTrigger | Outcome::Skipped => Vec::new(),
+            Outcome::Blocked(blockers) => {
+                let reasons: Vec<String> = blockers.iter().map(ToString::to_string).collect();
+                vec![Self::new(EventKind::Blocked, pr).with_reason(reasons.join("; "))]
+            }
+            Outcome::Merged => vec![Self::new(EventKind::Merged, pr)],
+            Outcome::MergeFailed {
+                error, rebuilds, ..
+            } => {
+                let mut events = vec![Self::new(EventKind::MergeFailed, pr).with_reason(error)];
+                // Rebuilds from earlier runs were already notified about
+                events.extend(
+                    rebuilds
+                        .iter()
+                        .filter(|rebuild| rebuild.state == RebuildState::Triggered)
+                        .map(|rebuild| Self {
+                            build: Some(rebuild.name.clone()),
+                            ..Self::new(EventKind::Rebuilt, pr)
+                        }),
+                );
+                events
+            }
+            Outcome::Error(message) => vec![Self::new(EventKind::Error, pr).with_reason(message)],
+            Outcome::Panicked(message) => {
+                vec![Self::new(EventKind::Error, pr).with_reason(format!("panicked: {}", message))]
+            }
+        }
+    }
+
+    /// Fills in the `{placeholders}` of a message template. Values aren't expanded again, so a
+    /// title containing e.g. `{url}` is kept as is.
+    pub fn render(&self, template: &str) -> String {
+        let mut message = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            message.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let placeholder = rest
+                .find('}')
+                .and_then(|end| Some((self.placeholder(&rest[1..end])?, end)));
+            match placeholder {
+                Some((value, end)) => {
+                    message.push_str(&value);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    message.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        message.push_str(rest);
+        message
+    }
+
+    /// Returns the value of a placeholder, or `None` if there's no placeholder with that name
+    fn placeholder(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "event" => return Some(self.kind.to_string()),
+            "id" => return Some(self.pr_id.to_string()),
+            "url" => &self.pr_url,
+            "title" => &self.title,
+            "project" => &self.project,
+            "repo" => &self.repo,
+            "author" => self.author.as_deref().unwrap_or_default(),
+            "target" => self.target_branch.as_deref().unwrap_or_default(),
+            "reason" => self.reason.as_deref().unwrap_or_default(),
+            "build" => self.build.as_deref().unwrap_or_default(),
+            _ => return None,
+        };
+        Some(value.to_owned())
+    }
+}
+
+/// Services that notifications can be sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SinkKind {
+    /// A Slack incoming webhook
+    Slack,
+    /// A Microsoft Teams incoming webhook
+    Teams,
+    /// Any URL accepting a JSON POST request
+    Webhook,
+}
+
+/// Where to send notifications, and which ones
+#[derive(Debug, Clone, Deserialize)]
+pub struct SinkConfig {
+    #[serde(rename = "type")]
+    pub kind: SinkKind,
+    pub url: String,
+    /// Events to notify about. If empty, all events are sent.
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// Message templates that override the default for an event
+    #[serde(default)]
+    pub templates: HashMap<EventKind, String>,
+}
+
+impl SinkConfig {
+    fn wants(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+
+    fn message(&self, event: &Event) -> String {
+        let template = self
+            .templates
+            .get(&event.kind)
+            .map(String::as_str)
+            .unwrap_or_else(|| event.kind.default_template());
+        event.render(template)
+    }
+
+    /// Returns the body of the request to send for an event
+    fn payload(&self, event: &Event) -> serde_json::Value {
+        let message = self.message(event);
+        match self.kind {
+            SinkKind::Slack => serde_json::json!({ "text": message }),
+            SinkKind::Teams => serde_json::json!({
+                "@type": "MessageCard",
+                "@context": "https://schema.org/extensions",
+                "summary": message,
+                "text": message,
+            }),
+            SinkKind::Webhook => {
+                let mut payload = serde_json::to_value(event).unwrap();
+                payload["message"] = serde_json::Value::String(message);
+                payload
+            }
+        }
+    }
+}
+
+/// Sends notifications to the configured sinks
+pub struct Notifier {
+    http_client: reqwest::Client,
+    sinks: Vec<SinkConfig>,
+    #[cfg(feature = "email")]
+    email: Option<Arc<EmailNotifier>>,
+    #[cfg(feature = "notify")]
+    desktop: Option<DesktopNotifier>,
+}
+
+impl Notifier {
+    pub fn new(sinks: Vec<SinkConfig>) -> Self {
+        Self {
+            // Notifications are sent while checking pull requests, so a webhook that hangs
+            // mustn't hold them up
+            http_client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("Could not build HTTP client"),
+            sinks,
+            #[cfg(feature = "email")]
+            email: None,
+            #[cfg(feature = "notify")]
+            desktop: None,
+        }
+    }
+
+    /// Also email the authors of pull requests that stay blocked
+    #[cfg(feature = "email")]
+    pub fn with_email(mut self, email: Arc<EmailNotifier>) -> Self {
+        self.email = Some(email);
+        self
+    }
+
+    /// Also show desktop notifications about the user's own pull requests
+    #[cfg(feature = "notify")]
+    pub fn with_desktop(mut self, desktop: DesktopNotifier) -> Self {
+        self.desktop = Some(desktop);
+        self
+    }
+
+    /// Sends an event to every sink that wants it. Failures are logged but otherwise ignored.
+    pub async fn notify(&self, event: &Event) {
+        let sends =
+            self.sinks
+                .iter()
+                .filter(|sink| sink.wants(event.kind))
+                .map(|sink| async move {
+                    let response = self
+                        .http_client
+                        .post(&sink.url)
+                        .json(&sink.payload(event))
+                        .send()
+                        .await
+                        .and_then(reqwest::Response::error_for_status);
+                    if let Err(e) = response {
+                        warn!("Could not send {} notification: {}", event.kind, e);
+                    }
+                });
+        future::join_all(sends).await;
+    }
+
+    /// Sends the events for the outcome of checking a pull request
+    pub async fn notify_outcome(&self, pr: &PullRequest, outcome: &Outcome) {
+        cfg_if! {
+            if #[cfg(feature = "email")] {
+                if let Some(email) = &self.email {
+                    email.record(pr, outcome);
+                }
+            }
+        }
+        cfg_if! {
+            if #[cfg(feature = "notify")] {
+                if let Some(desktop) = &self.desktop {
+                    desktop.notify_outcome(pr, outcome).await;
+                }
+            }
+        }
+        if self.sinks.is_empty() {
+            return;
+        }
+        let state_path = pr.key().map(|key| data_dir::state_file(STATE_DIR, &key));
+        if let (Some(state_path), Outcome::Merged | Outcome::NoTrigger) = (&state_path, outcome) {
+            std::fs::remove_file(state_path).ok();
+        }
+        for event in Event::from_outcome(pr, outcome) {
+            if let (Some(state_path), EventKind::Blocked | EventKind::MergeFailed) =
+                (&state_path, event.kind)
+            {
+                if already_sent(state_path, &event) {
+                    debug!("Already sent {} notification", event.kind);
+                    continue;
+                }
+            }
+            self.notify(&event).await;
+        }
+    }
+
+    /// Sends any notifications that are batched until the end of a run
+    pub async fn finish(&self) {
+        cfg_if! {
+            if #[cfg(feature = "email")] {
+                if let Some(email) = &self.email {
+                    email.flush().await;
+                }
+            }
+        }
+    }
+}
+
+/// Returns whether a block is the same as the last one sent about its pull request, recording it
+/// as the last one
+fn already_sent(state_path: &Path, event: &Event) -> bool {
+    let block = format!(
+        "{}: {}",
+        event.kind,
+        event.reason.as_deref().unwrap_or_default()
+    );
+    let sent = std::fs::read_to_string(state_path).is_ok_and(|sent| sent == block);
+    // Saving on every run also keeps the file from being cleaned up as stale
+    std::fs::write(state_path, &block).ok();
+    sent
+}
+
+/// Clean out notification state that hasn't been updated for `STALENESS_THRESHOLD`
+pub fn decruft() -> Result<()> {
+    data_dir::remove_stale_files(STATE_DIR, STALENESS_THRESHOLD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge_checks::Blocker;
+    use crate::search::{MergeFailure, Rebuild};
+    use crate::test_fixtures;
+    use crate::test_server::{self, Request, Response};
+    use serde_json::json;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    fn pr() -> PullRequest {
+        test_fixtures::pr(123, json!({"title": "Fix the thing"}))
+    }
+
+    fn sink(kind: SinkKind, url: &str) -> SinkConfig {
+        SinkConfig {
+            kind,
+            url: String::from(url),
+            events: Vec::new(),
+            templates: HashMap::new(),
+        }
+    }
+
+    /// Returns the JSON body of the next request to the server
+    async fn json_body(requests: &mut UnboundedReceiver<Request>) -> serde_json::Value {
+        let request = requests.recv().await.unwrap();
+        serde_json::from_str(&request.body).unwrap()
+    }
+
+    #[test]
+    fn events_from_outcomes() {
+        let pr = pr();
+        assert!(Event::from_outcome(&pr, &Outcome::NoTrigger).is_empty());
+        let events = Event::from_outcome(
+            &pr,
+            &Outcome::MergeFailed {
+                error: String::from("conflicts"),
+                reason: MergeFailure::Conflicts,
+                rebuilds: vec![
+                    Rebuild {
+                        name: String::from("unit tests"),
+                        url: None,
+                        state: RebuildState::Triggered,
+                        next_rebuild: None,
+                    },
+                    Rebuild {
+                        name: String::from("lint"),
+                        url: Some(String::from("https://jenkins/job/lint/2/")),
+                        state: RebuildState::Running,
+                        next_rebuild: None,
+                    },
+                ],
+                exhausted_builds: Vec::new(),
+            },
+        );
+        let kinds: Vec<EventKind> = events.iter().map(|event| event.kind).collect();
+        assert_eq!(kinds, vec![EventKind::MergeFailed, EventKind::Rebuilt]);
+        assert_eq!(events[0].reason.as_deref(), Some("conflicts"));
+        assert_eq!(events[1].build.as_deref(), Some("unit tests"));
+    }
+
+    #[test]
+    fn templates() {
+        let pr = pr();
+        let event = Event::new(EventKind::Merged, &pr);
+        let mut sink = sink(SinkKind::Slack, "");
+        assert_eq!(
+            sink.message(&event),
+            "crabby-merged PR #123 into main: https://bitbucket/pr/123"
+        );
+        sink.templates.insert(
+            EventKind::Merged,
+            String::from("{author}'s \"{title}\" landed in {project}/{repo}"),
+        );
+        assert_eq!(
+            sink.message(&event),
+            "alice's \"Fix the thing\" landed in PROJ/app"
+        );
+        let event = Event::new(EventKind::MergeFailed, &pr).with_reason("conflicts");
+        assert_eq!(
+            sink.message(&event),
+            "merge blocked: conflicts (https://bitbucket/pr/123)"
+        );
+    }
+
+    #[test]
+    fn placeholders_in_values() {
+        let pr = test_fixtures::pr(123, json!({"title": "Render {reason} and {url"}));
+        let event = Event::new(EventKind::Blocked, &pr).with_reason("{title}");
+        assert_eq!(
+            event.render("{title}: {reason} {unknown} {{id}"),
+            "Render {reason} and {url: {title} {unknown} {123"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_to_matching_sinks() {
+        let (base_url, mut requests) = test_server::serve(|_| Response::status(200)).await;
+        let url = format!("{}/hook", base_url);
+        let mut slack = sink(SinkKind::Slack, &url);
+        slack.events = vec![EventKind::Merged];
+        let mut webhook = sink(SinkKind::Webhook, &url);
+        webhook.events = vec![EventKind::Blocked];
+        let notifier = Notifier::new(vec![slack, webhook]);
+
+        let pr = pr();
+        notifier.notify_outcome(&pr, &Outcome::Merged).await;
+        assert_eq!(
+            json_body(&mut requests).await,
+            json!({"text": "crabby-merged PR #123 into main: https://bitbucket/pr/123"})
+        );
+
+        notifier
+            .notify_outcome(&pr, &Outcome::Blocked(vec![Blocker::Draft]))
+            .await;
+        let body = json_body(&mut requests).await;
+        assert_eq!(body["event"], "blocked");
+        assert_eq!(body["pr_id"], 123);
+        assert_eq!(body["reason"], "pull request is a draft");
+        assert_eq!(
+            body["message"],
+            "merge blocked for PR #123: pull request is a draft (https://bitbucket/pr/123)"
+        );
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn blocks_are_sent_once() {
+        let (base_url, mut requests) = test_server::serve(|_| Response::status(200)).await;
+        let notifier = Notifier::new(vec![sink(SinkKind::Webhook, &base_url)]);
+        let pr = test_fixtures::pr(124, json!({}));
+        let conflicts = Outcome::MergeFailed {
+            error: String::from("conflicts"),
+            reason: MergeFailure::Conflicts,
+            rebuilds: Vec::new(),
+            exhausted_builds: Vec::new(),
+        };
+        for (outcome, sent) in [
+            (Outcome::Blocked(vec![Blocker::Draft]), Some("blocked")),
+            (Outcome::Blocked(vec![Blocker::Draft]), None),
+            (
+                Outcome::Blocked(vec![Blocker::OpenTasks(1)]),
+                Some("blocked"),
+            ),
+            (conflicts.clone(), Some("merge_failed")),
+            (conflicts, None),
+            (Outcome::Error(String::from("timed out")), Some("error")),
+            // Merging resets the state
+            (Outcome::Merged, Some("merged")),
+            (
+                Outcome::Blocked(vec![Blocker::OpenTasks(1)]),
+                Some("blocked"),
+            ),
+        ] {
+            notifier.notify_outcome(&pr, &outcome).await;
+            match sent {
+                Some(event) => assert_eq!(json_body(&mut requests).await["event"], event),
+                None => assert!(requests.try_recv().is_err(), "{:?}", outcome),
+            }
+        }
+    }
+
+    #[test]
+    fn teams_payload() {
+        let pr = pr();
+        let payload = sink(SinkKind::Teams, "").payload(&Event::new(EventKind::Merged, &pr));
+        assert_eq!(payload["@type"], "MessageCard");
+        assert_eq!(
+            payload["text"],
+            "crabby-merged PR #123 into main: https://bitbucket/pr/123"
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Lowest supported rate, one request every 1000 seconds. Slower rates would make the interval
+/// between requests overflow.
+pub const MIN_REQUESTS_PER_SECOND: f64 = 0.001;
+
+/// Limits the rate of requests by spacing them out evenly
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Minimum time between requests
+    interval: Duration,
+    /// Earliest time the next request is allowed
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// Allows `requests_per_second`, or `MIN_REQUESTS_PER_SECOND` if it is lower
+    pub fn new(requests_per_second: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(
+                1.0 / requests_per_second.max(MIN_REQUESTS_PER_SECOND),
+            ),
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until another request is allowed
+    pub async fn acquire(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = Instant::now().max(*next_slot);
+            *next_slot = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn spaces_out_requests() {
+        let limiter = RateLimiter::new(4.0);
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn slow_rates_are_clamped() {
+        for requests_per_second in [1e-300, 0.0, -1.0, f64::NAN] {
+            let limiter = RateLimiter::new(requests_per_second);
+            assert_eq!(limiter.interval, Duration::from_secs(1000));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_time_is_not_banked() {
+        let limiter = RateLimiter::new(1.0);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let start = Instant::now();
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+}
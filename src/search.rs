@@ -1,22 +1,72 @@
-#[cfg(feature = "jenkins")]
-use crate::backoff;
-#[cfg(feature = "jenkins")]
+#[cfg(feature = "ci")]
+use crate::backoff::{self, BackoffSchedule, NextRetry};
+#[cfg(feature = "ci")]
 use crate::bitbucket::BuildState;
-use crate::bitbucket::{self, PullRequest};
-#[cfg(feature = "jenkins")]
-use crate::jenkins;
+use crate::bitbucket::{self, AuthError, PullRequest};
+pub use crate::ci::RebuildState;
+#[cfg(feature = "ci")]
+use crate::ci::{self, CiBackend};
+#[cfg(feature = "notify")]
+use crate::desktop::DesktopNotifier;
+use crate::logging;
+use crate::merge_checks::{self, Blocker};
+use crate::notifier::{Event, EventKind, Notifier};
+use crate::stack::{self, Stack};
 use crate::Config;
-#[cfg(feature = "jenkins")]
+#[cfg(feature = "ci")]
 use crate::History;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use cfg_if::cfg_if;
-use futures::future;
-#[cfg(feature = "jenkins")]
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream::{self, StreamExt, TryStreamExt};
+#[cfg(feature = "ci")]
 use guard::guard;
 use log::*;
-use std::collections::HashMap;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
 use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::task::JoinError;
+
+/// A repository, or all repositories in a project, to search for pull requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepositoryPattern {
+    pub project_key: String,
+    /// Slug of the repository to search. If `None`, all repositories in the project are searched.
+    pub repo_slug: Option<String>,
+}
+
+impl FromStr for RepositoryPattern {
+    type Err = anyhow::Error;
+
+    /// Parses a repository pattern of the form `PROJECT/repo` or `PROJECT`
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, '/');
+        let project_key = parts.next().unwrap_or_default();
+        let repo_slug = parts.next();
+        if project_key.is_empty()
+            || repo_slug.is_some_and(|slug| slug.is_empty() || slug.contains('/'))
+        {
+            return Err(anyhow!("Invalid repository: {}", s));
+        }
+        Ok(Self {
+            project_key: project_key.to_string(),
+            repo_slug: repo_slug.map(String::from),
+        })
+    }
+}
+
+impl fmt::Display for RepositoryPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.repo_slug {
+            Some(slug) => write!(f, "{}/{}", self.project_key, slug),
+            None => write!(f, "{}", self.project_key),
+        }
+    }
+}
 
 async fn should_merge(
     api: &bitbucket::Client,
@@ -33,128 +83,878 @@ async fn should_merge(
         return true;
     }
     if config.check_comments {
-        let comments = match api.get_pr_comments(pr, Some(username)).await {
-            Ok(comments) => comments,
-            Err(e) => {
-                error!("{:#}", e);
-                Vec::new()
-            }
-        };
-        for comment in comments {
-            if config.merge_regex.is_match(&comment) {
+        // Stop fetching comments as soon as the trigger is found
+        let found = api
+            .pr_comments(pr)
+            .try_any(|comment| {
+                future::ready(
+                    comment.author.name == username && config.merge_regex.is_match(&comment.text),
+                )
+            })
+            .await;
+        match found {
+            Ok(true) => {
                 info!("Found trigger in PR comment");
                 return true;
             }
+            Ok(false) => (),
+            Err(e) => error!("{:#}", e),
         }
     }
     false
 }
 
-/// Check PR's for merge trigger and perform configured actions
-async fn check_prs(
+/// A build that was rebuilt, or will be
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rebuild {
+    /// Name of the failed build
+    pub name: String,
+    /// URL of the new build, once it has left the queue
+    pub url: Option<String>,
+    pub state: RebuildState,
+    /// When the build will be rebuilt, if it's waiting for its backoff to end
+    pub next_rebuild: Option<OffsetDateTime>,
+}
+
+/// Why Bitbucket refused to merge a PR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeFailure {
+    /// The PR conflicts with its target branch, which won't resolve itself
+    Conflicts,
+    /// Bitbucket vetoed the merge for another reason, e.g. builds that failed or are still running
+    Vetoed,
+    /// Anything else, e.g. the merge request itself failing
+    Other,
+}
+
+/// The outcome of checking a single PR
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    /// No merge trigger was found
+    NoTrigger,
+    /// The merge trigger was found, but client-side checks blocked the merge
+    Blocked(Vec<Blocker>),
+    /// The PR was merged
+    Merged,
+    /// The merge trigger was found, but merging failed
+    MergeFailed {
+        error: String,
+        reason: MergeFailure,
+        /// Builds that were rebuilt as a result, or are still being rebuilt
+        rebuilds: Vec<Rebuild>,
+        /// Names of failed builds that won't be rebuilt because they were retried as many times as
+        /// allowed
+        exhausted_builds: Vec<String>,
+    },
+    /// The PR was not checked because a PR it is stacked on was not merged
+    Skipped,
+    /// The PR could not be checked
+    Error(String),
+    /// The task checking the PR panicked
+    Panicked(String),
+}
+
+impl Outcome {
+    /// Returns why the PR wasn't merged, if the merge trigger was found but the merge was blocked
+    pub fn block_reason(&self) -> Option<String> {
+        match self {
+            Outcome::Blocked(blockers) => {
+                let reasons: Vec<String> = blockers.iter().map(ToString::to_string).collect();
+                Some(reasons.join("; "))
+            }
+            Outcome::MergeFailed {
+                error,
+                exhausted_builds,
+                ..
+            } if exhausted_builds.is_empty() => Some(error.clone()),
+            Outcome::MergeFailed {
+                error,
+                exhausted_builds,
+                ..
+            } => Some(format!(
+                "{}; retried as many times as allowed: {}",
+                error,
+                exhausted_builds.join(", ")
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of checking a PR
+#[derive(Debug, Clone)]
+pub struct PrResult {
+    pub url: String,
+    pub outcome: Outcome,
+}
+
+/// Check a PR for the merge trigger and merge it if possible
+async fn check_pr(
+    api: &bitbucket::Client,
+    pr: &PullRequest,
+    username: &str,
+    config: &Config,
+) -> Outcome {
+    debug!("Checking {}", pr.url().unwrap());
+    if !should_merge(api, pr, username, config).await {
+        debug!("No merge trigger found in {}", pr.url().unwrap());
+        return Outcome::NoTrigger;
+    }
+
+    let blockers = match merge_checks::blockers(api, pr, config).await {
+        Ok(blockers) => blockers,
+        Err(e) => {
+            error!("Could not check {}: {:#}", pr.url().unwrap(), e);
+            return Outcome::Error(format!("{:#}", e));
+        }
+    };
+    if !blockers.is_empty() {
+        let reasons: Vec<String> = blockers.iter().map(ToString::to_string).collect();
+        info!("Not merging {}: {}", pr.url().unwrap(), reasons.join("; "));
+        return Outcome::Blocked(blockers);
+    }
+
+    match api.merge_pr(pr).await {
+        Ok(()) => {
+            info!("Merged {}", pr.url().unwrap());
+            cfg_if! {
+                if #[cfg(feature = "ci")] {
+                    if let Some(hash) = pr.hash() {
+                        History::delete(hash).ok();
+                    }
+                }
+            }
+            Outcome::Merged
+        }
+        Err(e) => {
+            error!("Could not merge: {:#}", e);
+            let reason = match e.downcast_ref::<bitbucket::MergeVeto>() {
+                Some(veto) if veto.conflicted => MergeFailure::Conflicts,
+                Some(_) => MergeFailure::Vetoed,
+                None => MergeFailure::Other,
+            };
+            cfg_if! {
+                if #[cfg(feature = "ci")] {
+                    let (rebuilds, exhausted_builds) = retry_pr_builds(api, pr, config).await;
+                } else {
+                    let (rebuilds, exhausted_builds) = (Vec::new(), Vec::new());
+                }
+            }
+            Outcome::MergeFailed {
+                error: format!("{:#}", e),
+                reason,
+                rebuilds,
+                exhausted_builds,
+            }
+        }
+    }
+}
+
+/// Returns the panic message of a task, or the reason it was cancelled
+fn panic_message(e: JoinError) -> String {
+    match e.try_into_panic() {
+        Ok(payload) => payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| String::from("unknown panic")),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Marks the PR's stacked on top of `stack` as skipped
+fn skip_stack(stack: Stack, parent_url: &str, results: &mut Vec<PrResult>) {
+    for child in stack.children {
+        let url = child.pr.url().unwrap_or_default().to_owned();
+        debug!("Skipping {} until {} is merged", url, parent_url);
+        results.push(PrResult {
+            url,
+            outcome: Outcome::Skipped,
+        });
+        skip_stack(child, parent_url, results);
+    }
+}
+
+/// Check a stack of PR's for the merge trigger, starting from the bottom of the stack
+///
+/// PR's stacked on top of a PR are only checked once it has been merged, after retargeting them to
+/// the merged PR's target branch if needed. Each PR is checked in its own task so that a panic is
+/// reported against the PR that caused it.
+fn check_stack(
     api: Arc<bitbucket::Client>,
-    prs: Vec<PullRequest>,
+    stack: Stack,
     username: Arc<str>,
     config: Arc<Config>,
-) {
-    future::join_all(prs.into_iter().map(|pr| {
-        debug!("Checking {}", pr.url().unwrap());
-        let api_shared = Arc::clone(&api);
-        let username = Arc::clone(&username);
-        let config = Arc::clone(&config);
-        tokio::spawn(async move {
-            if !should_merge(&api_shared, &pr, &username, &config).await {
-                debug!("No merge trigger found in {}", pr.url().unwrap());
-                return;
-            }
-
-            match api_shared.merge_pr(&pr).await {
-                Ok(()) => {
-                    info!("Merged {}", pr.url().unwrap());
-                    cfg_if! {
-                        if #[cfg(feature = "jenkins")] {
-                            if let Some(hash) = pr.hash() {
-                                History::delete(hash).ok();
-                            }
-                        }
+    notifier: Arc<Notifier>,
+) -> BoxFuture<'static, Vec<PrResult>> {
+    async move {
+        let url = stack.pr.url().unwrap_or_default().to_owned();
+        let task = {
+            let api = Arc::clone(&api);
+            let pr = stack.pr.clone();
+            let username = Arc::clone(&username);
+            let config = Arc::clone(&config);
+            tokio::spawn(async move {
+                logging::with_pr(&pr, check_pr(&api, &pr, &username, &config)).await
+            })
+        };
+        let outcome = match task.await {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                let message = panic_message(e);
+                error!("Checking {} panicked: {}", url, message);
+                Outcome::Panicked(message)
+            }
+        };
+        logging::with_pr(&stack.pr, notifier.notify_outcome(&stack.pr, &outcome)).await;
+        let merged = matches!(outcome, Outcome::Merged);
+        let mut results = vec![PrResult {
+            url: url.clone(),
+            outcome,
+        }];
+        if !merged {
+            skip_stack(stack, &url, &mut results);
+            return results;
+        }
+
+        for mut child in stack.children {
+            if child.pr.target_ref().is_some() && child.pr.target_ref() == stack.pr.source_ref() {
+                let target_ref = stack.pr.target_ref().unwrap();
+                match api.retarget_pr(&child.pr, target_ref).await {
+                    Ok(pr) => {
+                        info!("Retargeted {} to {}", pr.url().unwrap(), target_ref);
+                        child.pr = pr;
                     }
-                }
-                Err(e) => {
-                    error!("Could not merge: {:#}", e);
-                    cfg_if! {
-                        if #[cfg(feature = "jenkins")] {
-                            retry_pr_builds(&api_shared, &pr, &config).await;
-                        }
+                    Err(e) => {
+                        error!("{:#}", e);
+                        let event =
+                            Event::new(EventKind::Error, &child.pr).with_reason(format!("{:#}", e));
+                        notifier.notify(&event).await;
+                        let child_url = child.pr.url().unwrap_or_default().to_owned();
+                        results.push(PrResult {
+                            url: child_url.clone(),
+                            outcome: Outcome::Error(format!("{:#}", e)),
+                        });
+                        skip_stack(child, &child_url, &mut results);
+                        continue;
                     }
                 }
-            };
+            }
+            results.extend(
+                check_stack(
+                    Arc::clone(&api),
+                    child,
+                    Arc::clone(&username),
+                    Arc::clone(&config),
+                    Arc::clone(&notifier),
+                )
+                .await,
+            );
+        }
+        results
+    }
+    .boxed()
+}
+
+/// Check PR's for merge trigger and perform configured actions
+async fn check_prs(
+    api: Arc<bitbucket::Client>,
+    prs: Vec<PullRequest>,
+    username: Arc<str>,
+    config: Arc<Config>,
+    notifier: Arc<Notifier>,
+) -> Vec<PrResult> {
+    let stacks = stack::build_stacks(prs, config.check_stacked_branches, config.check_depends_on);
+    let results: Vec<Vec<PrResult>> = stream::iter(stacks)
+        .map(|stack| {
+            check_stack(
+                Arc::clone(&api),
+                stack,
+                Arc::clone(&username),
+                Arc::clone(&config),
+                Arc::clone(&notifier),
+            )
         })
-    }))
-    .await;
+        .buffer_unordered(config.max_concurrent_prs)
+        .collect()
+        .await;
+    results.into_iter().flatten().collect()
 }
 
-/// Attempt to rebuild any PR builds that match the retry regex trigger
-#[cfg(feature = "jenkins")]
-async fn retry_pr_builds(api: &bitbucket::Client, pr: &PullRequest, config: &Config) {
-    guard!(
-        let (Some(jenkins_auth), Some(retry_trigger)) =
-            (config.jenkins_auth.as_ref(), config.jenkins_retry_regex.as_ref())
-        else {
-            warn!("Jenkins not configured. Skipping retry attempt.");
-            return;
-        }
-    );
+/// What became of a failed build that matched a retry rule
+#[cfg(feature = "ci")]
+enum Retry {
+    Rebuild(Rebuild),
+    /// The build was retried as many times as allowed
+    Exhausted,
+    /// The build wasn't rebuilt, e.g. because it failed for a reason that isn't known to be flaky
+    Skipped,
+}
+
+/// Attempt to rebuild any PR builds that match the retry regex trigger. Returns the builds that
+/// were rebuilt, or that were rebuilt in an earlier run and are still queued or running, and the
+/// names of the builds that were retried as many times as allowed.
+#[cfg(feature = "ci")]
+async fn retry_pr_builds(
+    api: &bitbucket::Client,
+    pr: &PullRequest,
+    config: &Config,
+) -> (Vec<Rebuild>, Vec<String>) {
+    let backends = ci::backends(config);
+    if config.retry_rules.is_empty() || backends.is_empty() {
+        warn!("CI server not configured. Skipping retry attempt.");
+        return (Vec::new(), Vec::new());
+    }
     guard!(
         let Some(hash) = pr.hash()
         else {
             error!("Could not resolve commit hash for PR {:?}", pr);
-            return;
+            return (Vec::new(), Vec::new());
         }
     );
     let builds = api.get_build_status(hash).await;
-    for build in builds.into_iter().flatten() {
-        if build.state == BuildState::Failed
-            && retry_trigger.is_match(&build.name)
-            && backoff::should_retry_now(hash, config.jenkins_retry_limit)
-        {
-            info!("Attempting rebuild for {}", build.name);
-            match jenkins::rebuild(&build.url, jenkins_auth.clone()).await {
-                Ok(_) => info!("Rebuilt {}", build.name),
-                Err(e) => error!("{:#}", e),
-            };
+    let mut rebuilds = Vec::new();
+    let mut exhausted_builds = Vec::new();
+    let failed = builds
+        .into_iter()
+        .flatten()
+        .filter(|build| build.state == BuildState::Failed)
+        .filter_map(|build| {
+            let rule = config
+                .retry_rules
+                .iter()
+                .find(|rule| rule.regex.is_match(&build.name))?;
+            Some((build, rule))
+        });
+    for (build, rule) in failed {
+        guard!(
+            let Some(backend) = ci::backend_for(&backends, &build.url)
+            else {
+                warn!("No CI server configured for {}", build.url);
+                continue;
+            }
+        );
+        let retry = logging::with_build(
+            &build.name,
+            retry_build(api, pr, hash, &build, backend, &rule.backoff, config),
+        )
+        .await;
+        match retry {
+            Retry::Rebuild(rebuild) => rebuilds.push(rebuild),
+            Retry::Exhausted => exhausted_builds.push(build.name),
+            Retry::Skipped => (),
         }
     }
+    (rebuilds, exhausted_builds)
+}
+
+/// Rebuild a failed build, unless an earlier rebuild of it is still queued or running
+#[cfg(feature = "ci")]
+async fn retry_build(
+    api: &bitbucket::Client,
+    pr: &PullRequest,
+    hash: &str,
+    build: &bitbucket::BuildStatus,
+    backend: &dyn CiBackend,
+    backoff: &BackoffSchedule,
+    config: &Config,
+) -> Retry {
+    // Bitbucket only hears about the new build once it starts, so until then the build still looks
+    // failed
+    let pending = History::load(hash)
+        .ok()
+        .flatten()
+        .and_then(|history| history.build(build))
+        .and_then(|history| history.rebuild().cloned());
+    if let Some(mut queued) = pending {
+        match backend.rebuild_state(&mut queued).await {
+            Ok(Some(state)) => {
+                info!(
+                    "Rebuild of {} is {}: {}",
+                    build.name,
+                    state,
+                    queued.url.as_deref().unwrap_or_default()
+                );
+                if let Err(e) = History::save_rebuild(hash, build, queued.clone()) {
+                    error!("Error saving retry history file for {}: {}", hash, e);
+                }
+                return Retry::Rebuild(Rebuild {
+                    name: build.name.clone(),
+                    url: queued.url,
+                    state,
+                    next_rebuild: None,
+                });
+            }
+            Ok(None) => (),
+            Err(e) => {
+                // Better to wait for the next run than to trigger the build twice
+                error!(
+                    "Could not check the last rebuild of {}: {:#}",
+                    build.name, e
+                );
+                return Retry::Skipped;
+            }
+        }
+    }
+
+    // Checking why the build failed can mean downloading its log, so only do it once the build is
+    // due to be retried
+    match backoff::next_retry(hash, build, backoff, config.retry_limit) {
+        Ok(NextRetry::Now) => (),
+        Ok(NextRetry::At(next_rebuild)) => {
+            info!(
+                "Next rebuild of {} in {}",
+                build.name,
+                backoff::format_delay(next_rebuild - OffsetDateTime::now_utc())
+            );
+            return Retry::Rebuild(Rebuild {
+                name: build.name.clone(),
+                url: None,
+                state: RebuildState::Waiting,
+                next_rebuild: Some(next_rebuild),
+            });
+        }
+        Ok(NextRetry::Never) => {
+            info!("{} was retried as many times as allowed", build.name);
+            return Retry::Exhausted;
+        }
+        Err(e) => {
+            error!("Error reading retry history file for {}: {:#}", hash, e);
+            History::delete(hash).ok();
+            return Retry::Skipped;
+        }
+    }
+
+    match backend.failed_flakily(&build.url).await {
+        Ok(true) => (),
+        Ok(false) => {
+            report_genuine_failure(api, pr, hash, build).await;
+            return Retry::Skipped;
+        }
+        Err(e) => {
+            error!("Could not check why {} failed: {:#}", build.name, e);
+            return Retry::Skipped;
+        }
+    }
+    backoff::count_retry(hash, build);
+    info!(
+        "Attempting rebuild for {} on {}",
+        build.name,
+        backend.name()
+    );
+    match backend.rebuild(&build.url).await {
+        Ok(queued) => {
+            match &queued.url {
+                Some(url) => info!("Rebuilt {} as {}", build.name, url),
+                None => info!("Rebuilt {}", build.name),
+            }
+            if let Err(e) = History::save_rebuild(hash, build, queued.clone()) {
+                error!("Error saving retry history file for {}: {}", hash, e);
+            }
+            Retry::Rebuild(Rebuild {
+                name: build.name.clone(),
+                url: queued.url,
+                state: RebuildState::Triggered,
+                next_rebuild: None,
+            })
+        }
+        Err(e) => {
+            error!("{:#}", e);
+            Retry::Skipped
+        }
+    }
+}
+
+/// Comment on a PR that a build failed for a reason that isn't known to be flaky, unless that was
+/// already done for the build
+#[cfg(feature = "ci")]
+async fn report_genuine_failure(
+    api: &bitbucket::Client,
+    pr: &PullRequest,
+    hash: &str,
+    build: &bitbucket::BuildStatus,
+) {
+    let reported = History::load(hash)
+        .ok()
+        .flatten()
+        .is_some_and(|history| history.reported_genuine_failure(&build.url));
+    if reported {
+        return;
+    }
+    info!("{} has a genuine failure, not retrying", build.name);
+    let comment = format!(
+        "🦀 {} has a genuine failure, not retrying: {}",
+        build.name, build.url
+    );
+    match api.add_pr_comment(pr, &comment).await {
+        Ok(()) => {
+            if let Err(e) = History::save_genuine_failure(hash, &build.url) {
+                error!("Error saving retry history file for {}: {}", hash, e);
+            }
+        }
+        Err(e) => error!("Could not comment on {}: {:#}", pr.url().unwrap(), e),
+    }
+}
+
+/// Returns the open PR's in a repository, or in all repositories of a project
+async fn fetch_repository_prs(
+    api: &bitbucket::Client,
+    pattern: &RepositoryPattern,
+) -> Result<Vec<PullRequest>> {
+    let repo_slugs = match &pattern.repo_slug {
+        Some(slug) => vec![slug.clone()],
+        None => api.get_repo_slugs(&pattern.project_key).await?,
+    };
+    let prs = future::try_join_all(repo_slugs.iter().map(|slug| {
+        let mut params: HashMap<&str, String> = HashMap::with_capacity(1);
+        params.insert("state", "OPEN".to_owned());
+        api.get_repo_prs(&pattern.project_key, slug, Some(params))
+    }))
+    .await?;
+    Ok(prs.into_iter().flatten().collect())
+}
+
+/// Returns the open PR's in all configured repositories
+async fn fetch_all_repository_prs(
+    api: &bitbucket::Client,
+    config: &Config,
+) -> Result<Vec<PullRequest>> {
+    let prs = future::try_join_all(
+        config
+            .repositories
+            .iter()
+            .map(|pattern| fetch_repository_prs(api, pattern)),
+    )
+    .await?;
+    Ok(prs.into_iter().flatten().collect())
+}
+
+/// Returns the open PR's on the authenticated user's dashboard in which the user has the given role
+async fn fetch_dashboard_prs(
+    api: &bitbucket::Client,
+    role: &str,
+    participant_status: Option<&str>,
+) -> Result<Vec<PullRequest>> {
+    let mut params: HashMap<&str, String> = HashMap::with_capacity(3);
+    params.insert("state", "open".to_owned());
+    params.insert("role", role.to_owned());
+    if let Some(participant_status) = participant_status {
+        params.insert("participantStatus", participant_status.to_owned());
+    }
+    api.get_prs(Some(params)).await
+}
+
+/// Returns a regex matching @-mentions of the given user
+fn mention_regex(username: &str) -> Regex {
+    // Bitbucket writes mentions as @username, or @"username" if the username contains spaces.
+    // Usernames can contain periods, so a period only ends a mention if it ends a sentence.
+    let username = regex::escape(username);
+    let regex = format!(r#"@(?:"{username}"|{username}(?:$|[^\w.-]|\.(?:$|\W)))"#);
+    Regex::new(&regex).unwrap()
+}
+
+/// Returns the PR's from `prs` that mention the given user in their description or comments
+async fn filter_mentions(
+    api: &bitbucket::Client,
+    prs: Vec<PullRequest>,
+    username: &str,
+    max_concurrent_prs: usize,
+) -> Vec<PullRequest> {
+    let regex = &mention_regex(username);
+    let is_mentioned: Vec<bool> = stream::iter(prs.iter().map(|pr| async move {
+        if regex.is_match(pr.description.as_deref().unwrap_or_default()) {
+            return true;
+        }
+        let found = api
+            .pr_comments(pr)
+            .try_any(|comment| future::ready(regex.is_match(&comment.text)))
+            .await;
+        match found {
+            Ok(found) => found,
+            Err(e) => {
+                error!("{:#}", e);
+                false
+            }
+        }
+    }))
+    .buffered(max_concurrent_prs)
+    .collect()
+    .await;
+    prs.into_iter()
+        .zip(is_mentioned)
+        .filter_map(|(pr, is_mentioned)| is_mentioned.then_some(pr))
+        .collect()
+}
+
+/// A search for PR's to check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Search {
+    /// PR's authored by the authenticated user
+    Own,
+    /// PR's approved by the authenticated user
+    Approved,
+    /// PR's the authenticated user is a reviewer of, regardless of approval status
+    Reviewer,
+    /// PR's the authenticated user is a participant of
+    Participant,
+    /// PR's that mention the authenticated user in their description or comments
+    Mentioned,
+    /// All PR's in the configured repositories
+    Repositories,
+}
+
+impl fmt::Display for Search {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Search::Own => "Own",
+            Search::Approved => "Approved",
+            Search::Reviewer => "Reviewer",
+            Search::Participant => "Participant",
+            Search::Mentioned => "Mentioned",
+            Search::Repositories => "Repository",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Search {
+    pub const ALL: [Search; 6] = [
+        Search::Own,
+        Search::Approved,
+        Search::Reviewer,
+        Search::Participant,
+        Search::Mentioned,
+        Search::Repositories,
+    ];
+
+    /// Returns true if this search is enabled by the given configuration
+    pub fn is_enabled(self, config: &Config) -> bool {
+        match self {
+            Search::Own => config.check_own_prs,
+            Search::Approved => config.check_approved_prs,
+            Search::Reviewer => config.check_reviewer_prs,
+            Search::Participant => config.check_participant_prs,
+            Search::Mentioned => config.check_mentioned_prs,
+            Search::Repositories => !config.repositories.is_empty(),
+        }
+    }
+
+    /// Returns the open PR's found by this search
+    async fn fetch(
+        self,
+        api: &bitbucket::Client,
+        config: &Config,
+        username: &str,
+    ) -> Result<Vec<PullRequest>> {
+        match self {
+            Search::Own => fetch_dashboard_prs(api, "author", None).await,
+            Search::Approved => fetch_dashboard_prs(api, "reviewer", Some("approved")).await,
+            Search::Reviewer => fetch_dashboard_prs(api, "reviewer", None).await,
+            Search::Participant => fetch_dashboard_prs(api, "participant", None).await,
+            Search::Mentioned => {
+                // Bitbucket has no search for mentions, so look through PR's the user participates
+                // in, which includes any PR they've commented on, and the configured repositories
+                let (participant_prs, repository_prs) = future::try_join(
+                    fetch_dashboard_prs(api, "participant", None),
+                    fetch_all_repository_prs(api, config),
+                )
+                .await?;
+                let candidates = dedup_prs(participant_prs.into_iter().chain(repository_prs));
+                Ok(filter_mentions(api, candidates, username, config.max_concurrent_prs).await)
+            }
+            Search::Repositories => fetch_all_repository_prs(api, config).await,
+        }
+    }
+}
+
+/// Removes duplicate PR's, keeping the first occurrence of each, and PR's whose target repository
+/// Bitbucket didn't describe, as they can't be checked
+fn dedup_prs(prs: impl IntoIterator<Item = PullRequest>) -> Vec<PullRequest> {
+    let mut seen = HashSet::new();
+    prs.into_iter()
+        .filter(|pr| {
+            let (Some(project_key), Some(repo_slug)) = (pr.project_key(), pr.repo_slug()) else {
+                warn!(
+                    "Skipping PR {} with no target repository",
+                    pr.url().unwrap_or_default()
+                );
+                return false;
+            };
+            seen.insert((project_key.to_owned(), repo_slug.to_owned(), pr.id()))
+        })
+        .collect()
+}
+
+/// The results of searching for and checking PR's
+#[derive(Debug, Clone)]
+pub struct RunResults {
+    /// Number of PR's found by each search that succeeded
+    pub counts: Vec<(Search, usize)>,
+    /// Outcome of checking each PR
+    pub prs: Vec<PrResult>,
+    /// Errors from searches that failed
+    pub errors: Vec<String>,
+}
+
+/// Runs all enabled searches and checks the PR's found for the merge trigger, checking each PR
+/// only once even if it was found by multiple searches
+pub async fn run(api: Arc<bitbucket::Client>, config: Arc<Config>) -> Result<RunResults> {
+    let searches: Vec<Search> = Search::ALL
+        .into_iter()
+        .filter(|search| search.is_enabled(&config))
+        .collect();
+    run_searches(api, config, searches).await
 }
 
 /// Search PR's authored by the authenticated user for the merge trigger and returns the number of
 /// PR's checked.
+#[deprecated(note = "use `search::run`, which runs all enabled searches")]
 pub async fn own_prs(api: Arc<bitbucket::Client>, config: Arc<Config>) -> Result<usize> {
-    let mut params: HashMap<&str, String> = HashMap::with_capacity(2);
-    params.insert("state", "open".to_owned());
-    params.insert("role", "author".to_owned());
-    info!("Fetching list of own PR's");
-    let prs = api.get_prs(Some(params)).await?;
-    let n_prs = prs.len();
-    let username = Arc::from(prs[0].author().expect("No author field"));
-    info!("Scanning {}'s PR's", username);
-    check_prs(api, prs, username, config).await;
-    Ok(n_prs)
+    let results = run_searches(api, config, vec![Search::Own]).await?;
+    match results.errors.into_iter().next() {
+        Some(error) => Err(anyhow!(error)),
+        None => Ok(results.prs.len()),
+    }
 }
 
 /// Searches PR's approved by the authenticated user for the merge trigger and returns the number
 /// of PR's checked.
+#[deprecated(note = "use `search::run`, which runs all enabled searches")]
 pub async fn approved_prs(api: Arc<bitbucket::Client>, config: Arc<Config>) -> Result<usize> {
-    let mut params: HashMap<&str, String> = HashMap::with_capacity(3);
-    params.insert("state", "open".to_owned());
-    params.insert("role", "reviewer".to_owned());
-    params.insert("participantStatus", "approved".to_owned());
-    info!("Fetching approved PR's");
-    let (prs, username) = future::join(api.get_prs(Some(params)), api.get_username()).await;
-    let prs = prs?;
-    let username = Arc::from(username?);
-
-    let n_prs = prs.len();
-    info!("Scanning PR's approved by {}", username);
-    check_prs(api, prs, username, config).await;
-    Ok(n_prs)
+    let results = run_searches(api, config, vec![Search::Approved]).await?;
+    match results.errors.into_iter().next() {
+        Some(error) => Err(anyhow!(error)),
+        None => Ok(results.prs.len()),
+    }
+}
+
+/// Runs the given searches and checks the PR's found for the merge trigger
+async fn run_searches(
+    api: Arc<bitbucket::Client>,
+    config: Arc<Config>,
+    searches: Vec<Search>,
+) -> Result<RunResults> {
+    let username = api.get_username().await?;
+    if username.is_empty() {
+        return Err(AuthError.into());
+    }
+    let username: Arc<str> = Arc::from(username);
+    info!("Fetching PR's for {}", username);
+    let results = future::join_all(
+        searches
+            .iter()
+            .map(|search| search.fetch(&api, &config, &username)),
+    )
+    .await;
+
+    let mut counts = Vec::with_capacity(searches.len());
+    let mut errors = Vec::new();
+    let mut all_prs = Vec::new();
+    for (search, result) in searches.into_iter().zip(results) {
+        match result {
+            Ok(prs) => {
+                counts.push((search, prs.len()));
+                all_prs.extend(prs);
+            }
+            Err(e) => {
+                error!("{} PR search failed: {:#}", search, e);
+                errors.push(format!("{} PR search failed: {:#}", search, e));
+            }
+        }
+    }
+    let prs = dedup_prs(all_prs);
+    info!("Scanning {} PR's", prs.len());
+    let notifier = Notifier::new(config.notifiers.clone());
+    cfg_if! {
+        if #[cfg(feature = "email")] {
+            let notifier = match &config.email {
+                Some(email) => notifier.with_email(Arc::clone(email)),
+                None => notifier,
+            };
+        }
+    }
+    cfg_if! {
+        if #[cfg(feature = "notify")] {
+            let notifier = if config.desktop_notifications {
+                notifier.with_desktop(DesktopNotifier::new(&username))
+            } else {
+                notifier
+            };
+        }
+    }
+    let notifier = Arc::new(notifier);
+    let prs = check_prs(api, prs, username, config, Arc::clone(&notifier)).await;
+    notifier.finish().await;
+    Ok(RunResults {
+        counts,
+        prs,
+        errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+    use serde_json::json;
+
+    #[test]
+    fn repository_patterns() {
+        assert_eq!(
+            "PROJ/repo".parse::<RepositoryPattern>().unwrap(),
+            RepositoryPattern {
+                project_key: String::from("PROJ"),
+                repo_slug: Some(String::from("repo")),
+            }
+        );
+        assert_eq!(
+            "~user".parse::<RepositoryPattern>().unwrap(),
+            RepositoryPattern {
+                project_key: String::from("~user"),
+                repo_slug: None,
+            }
+        );
+        assert!("".parse::<RepositoryPattern>().is_err());
+        assert!("PROJ/".parse::<RepositoryPattern>().is_err());
+        assert!("PROJ/repo/extra".parse::<RepositoryPattern>().is_err());
+    }
+
+    #[tokio::test]
+    async fn panic_messages() {
+        let task = tokio::spawn(async { panic!("oh no") });
+        assert_eq!(panic_message(task.await.unwrap_err()), "oh no");
+        let task = tokio::spawn(async { panic!("oh no {}", 2) });
+        assert_eq!(panic_message(task.await.unwrap_err()), "oh no 2");
+    }
+
+    #[test]
+    fn mentions() {
+        let mentions = |text, username| mention_regex(username).is_match(text);
+        assert!(mentions("cc @alice", "alice"));
+        assert!(mentions("@alice, can you look?", "alice"));
+        assert!(mentions("ping @\"alice smith\"", "alice smith"));
+        assert!(mentions("thanks @alice.", "alice"));
+        assert!(mentions("@alice.smith please merge", "alice.smith"));
+        assert!(!mentions("cc @alice2", "alice"));
+        assert!(!mentions("cc @alice.smith", "alice"));
+        assert!(!mentions("alice@example.com", "example"));
+        assert!(!mentions("no mention of alice", "alice"));
+    }
+
+    #[test]
+    fn dedup() {
+        let prs = vec![
+            test_fixtures::pr(1, json!({})),
+            test_fixtures::pr(2, json!({"toRef": {}})),
+            test_fixtures::pr(
+                1,
+                json!({"toRef": test_fixtures::to_ref("lib", "refs/heads/main")}),
+            ),
+            test_fixtures::pr(1, json!({"title": "Duplicate"})),
+        ];
+        let prs = dedup_prs(prs);
+        let ids: Vec<(Option<&str>, u32)> =
+            prs.iter().map(|pr| (pr.repo_slug(), pr.id())).collect();
+        assert_eq!(ids, vec![(Some("app"), 1), (Some("lib"), 1)]);
+    }
 }
@@ -0,0 +1,270 @@
+//! Support for stacked pull requests
+//!
+//! A pull request depends on another open pull request if it targets the other pull request's
+//! source branch or if its description contains a line like `depends-on: #123`. Dependent pull
+//! requests are never merged before the pull requests they depend on.
+
+use crate::bitbucket::{self, PullRequest, PullRequestState};
+use crate::merge_checks::Blocker;
+use crate::Config;
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// A pull request and the pull requests stacked on top of it
+#[derive(Debug)]
+pub struct Stack {
+    pub pr: PullRequest,
+    pub children: Vec<Stack>,
+}
+
+/// Returns the ids of the pull requests listed in `depends-on` lines of a pull request description
+pub fn depends_on(description: &str) -> Vec<u32> {
+    static DEPENDS_ON_REGEX: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(?im)^\s*depends-on:(.*)$").unwrap());
+    static ID_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"#(\d+)").unwrap());
+
+    DEPENDS_ON_REGEX
+        .captures_iter(description)
+        .flat_map(|captures| {
+            ID_REGEX
+                .captures_iter(captures.get(1).unwrap().as_str())
+                .filter_map(|id| id[1].parse().ok())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Returns true if `child` depends on `parent`, considering only information available locally
+fn is_parent(
+    parent: &PullRequest,
+    child: &PullRequest,
+    check_stacked_branches: bool,
+    check_depends_on: bool,
+) -> bool {
+    if !parent.same_repo(child) {
+        return false;
+    }
+    let stacked_branch = check_stacked_branches
+        && parent.source_ref().is_some()
+        && parent.source_ref() == child.target_ref();
+    let explicit = check_depends_on
+        && child
+            .description
+            .as_deref()
+            .is_some_and(|description| depends_on(description).contains(&parent.id()));
+    stacked_branch || explicit
+}
+
+/// Organizes pull requests into stacks such that every pull request comes after the pull request
+/// it depends on, if that pull request is also in `prs`
+pub fn build_stacks(
+    prs: Vec<PullRequest>,
+    check_stacked_branches: bool,
+    check_depends_on: bool,
+) -> Vec<Stack> {
+    let mut parents: Vec<Option<usize>> = prs
+        .iter()
+        .enumerate()
+        .map(|(i, child)| {
+            (0..prs.len()).find(|&j| {
+                j != i && is_parent(&prs[j], child, check_stacked_branches, check_depends_on)
+            })
+        })
+        .collect();
+    // Break any dependency cycles by making the pull request that closes the cycle a root. Its
+    // dependency check will block it from being merged regardless.
+    for i in 0..parents.len() {
+        let mut visited = vec![i];
+        let mut current = i;
+        while let Some(parent) = parents[current] {
+            if visited.contains(&parent) {
+                parents[current] = None;
+                break;
+            }
+            visited.push(parent);
+            current = parent;
+        }
+    }
+
+    let mut prs: Vec<Option<PullRequest>> = prs.into_iter().map(Some).collect();
+    fn take_stack(
+        i: usize,
+        prs: &mut Vec<Option<PullRequest>>,
+        parents: &[Option<usize>],
+    ) -> Stack {
+        let children = (0..parents.len())
+            .filter(|&child| parents[child] == Some(i))
+            .map(|child| take_stack(child, prs, parents))
+            .collect();
+        Stack {
+            pr: prs[i].take().unwrap(),
+            children,
+        }
+    }
+    (0..parents.len())
+        .filter(|&i| parents[i].is_none())
+        .map(|i| take_stack(i, &mut prs, &parents))
+        .collect()
+}
+
+/// Returns blockers for any open pull requests that `pr` depends on
+///
+/// The state of each dependency is fetched from the server, so this also catches dependencies that
+/// were not returned by the pull request search.
+pub async fn dependency_blockers(
+    api: &bitbucket::Client,
+    pr: &PullRequest,
+    config: &Config,
+) -> Result<Vec<Blocker>> {
+    let mut dependencies = Vec::new();
+    if config.check_depends_on {
+        for id in depends_on(pr.description.as_deref().unwrap_or_default()) {
+            let dependency = api.get_pr_in_repo(pr, id).await?;
+            if dependency.state == PullRequestState::Open {
+                dependencies.push(dependency);
+            }
+        }
+    }
+    if config.check_stacked_branches {
+        if let Some(target_ref) = pr.target_ref() {
+            dependencies.extend(api.get_open_prs_from_ref(pr, target_ref).await?);
+        }
+    }
+    Ok(dependencies
+        .into_iter()
+        .map(|dependency| {
+            Blocker::OpenDependency(
+                dependency
+                    .url()
+                    .map(String::from)
+                    .unwrap_or_else(|| format!("#{}", dependency.id())),
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+    use serde_json::json;
+
+    fn pr(id: u32, source: &str, target: &str, description: &str) -> PullRequest {
+        pr_in_repo("app", id, source, target, description)
+    }
+
+    fn pr_in_repo(
+        repo: &str,
+        id: u32,
+        source: &str,
+        target: &str,
+        description: &str,
+    ) -> PullRequest {
+        test_fixtures::pr(
+            id,
+            json!({
+                "description": description,
+                "fromRef": {"id": source},
+                "toRef": test_fixtures::to_ref(repo, target),
+            }),
+        )
+    }
+
+    /// Returns the ids of the pull requests in each stack, parents before children
+    fn ids(stacks: &[Stack]) -> Vec<(u32, Vec<u32>)> {
+        fn flatten(stack: &Stack, ids: &mut Vec<u32>) {
+            ids.push(stack.pr.id());
+            for child in &stack.children {
+                flatten(child, ids);
+            }
+        }
+        stacks
+            .iter()
+            .map(|stack| {
+                let mut ids = Vec::new();
+                flatten(stack, &mut ids);
+                (stack.pr.id(), ids)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn depends_on_lines() {
+        assert_eq!(depends_on("Fixes a bug"), Vec::<u32>::new());
+        assert_eq!(depends_on("Fixes a bug\n\ndepends-on: #123"), vec![123]);
+        assert_eq!(
+            depends_on("Depends-On: #1, #22\ndepends-on: #3"),
+            vec![1, 22, 3]
+        );
+        assert_eq!(
+            depends_on("This is not a depends-on: #4 line"),
+            Vec::<u32>::new()
+        );
+    }
+
+    #[test]
+    fn stacked_branches() {
+        // Given from the top of the stack down
+        let prs = vec![
+            pr(3, "refs/heads/c", "refs/heads/b", ""),
+            pr(2, "refs/heads/b", "refs/heads/a", ""),
+            pr(1, "refs/heads/a", "refs/heads/main", ""),
+            pr(4, "refs/heads/d", "refs/heads/main", ""),
+        ];
+        assert_eq!(
+            ids(&build_stacks(prs.clone(), true, true)),
+            vec![(1, vec![1, 2, 3]), (4, vec![4])]
+        );
+        assert_eq!(
+            ids(&build_stacks(prs, false, true)),
+            vec![(3, vec![3]), (2, vec![2]), (1, vec![1]), (4, vec![4])]
+        );
+    }
+
+    #[test]
+    fn explicit_dependencies() {
+        let prs = vec![
+            pr(5, "refs/heads/e", "refs/heads/main", "depends-on: #6"),
+            pr(6, "refs/heads/f", "refs/heads/main", ""),
+            pr(7, "refs/heads/g", "refs/heads/main", "depends-on: #8"),
+        ];
+        // #8 isn't among the pull requests, so #7 is checked on its own
+        assert_eq!(
+            ids(&build_stacks(prs.clone(), true, true)),
+            vec![(6, vec![6, 5]), (7, vec![7])]
+        );
+        assert_eq!(
+            ids(&build_stacks(prs, true, false)),
+            vec![(5, vec![5]), (6, vec![6]), (7, vec![7])]
+        );
+    }
+
+    #[test]
+    fn other_repositories() {
+        let prs = vec![
+            pr(1, "refs/heads/a", "refs/heads/main", ""),
+            pr_in_repo("lib", 2, "refs/heads/b", "refs/heads/a", "depends-on: #1"),
+        ];
+        assert_eq!(
+            ids(&build_stacks(prs, true, true)),
+            vec![(1, vec![1]), (2, vec![2])]
+        );
+    }
+
+    #[test]
+    fn cycles_are_broken() {
+        let prs = vec![
+            pr(1, "refs/heads/a", "refs/heads/main", "depends-on: #3"),
+            pr(2, "refs/heads/b", "refs/heads/main", "depends-on: #1"),
+            pr(3, "refs/heads/c", "refs/heads/main", "depends-on: #2"),
+        ];
+        let stacks = build_stacks(prs, true, true);
+        // Every pull request is still checked exactly once
+        assert_eq!(stacks.len(), 1);
+        let (_, mut all) = ids(&stacks).remove(0);
+        all.sort_unstable();
+        assert_eq!(all, vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,263 @@
+//! A machine-readable summary of a run and the process exit status derived from it
+
+use crate::ci::RebuildState;
+use crate::search::{Outcome, RunResults};
+
+use serde::Serialize;
+use std::process::ExitCode;
+use time::OffsetDateTime;
+
+/// Process exit status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// Everything was checked without errors
+    Success = 0,
+    /// An unexpected fatal error occurred
+    Failure = 1,
+    /// The configuration could not be loaded
+    ConfigError = 2,
+    /// The Bitbucket server rejected the API token
+    AuthFailure = 3,
+    /// Some searches or PR checks failed
+    PartialFailure = 4,
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
+
+/// A PR that had the merge trigger but wasn't merged
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockedPr {
+    pub url: String,
+    pub reasons: Vec<String>,
+}
+
+/// A build that was rebuilt, or will be
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rebuild {
+    /// URL of the PR the build belongs to
+    pub url: String,
+    pub build: String,
+    /// URL of the new build, once it has left the queue
+    pub build_url: Option<String>,
+    pub state: RebuildState,
+    /// When the build will be rebuilt, if it's waiting for its backoff to end
+    #[serde(
+        serialize_with = "time::serde::rfc3339::option::serialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub next_rebuild: Option<OffsetDateTime>,
+}
+
+/// An error encountered during the run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RunError {
+    /// URL of the PR being checked, if any
+    pub url: Option<String>,
+    pub message: String,
+}
+
+/// Summary of a single run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RunSummary {
+    /// Number of PR's found
+    pub prs_scanned: usize,
+    /// Number of PR's with the merge trigger
+    pub triggered: usize,
+    /// URLs of merged PR's
+    pub merged: Vec<String>,
+    pub blocked: Vec<BlockedPr>,
+    pub rebuilds: Vec<Rebuild>,
+    pub errors: Vec<RunError>,
+}
+
+impl RunSummary {
+    pub fn new(results: &RunResults) -> Self {
+        let mut summary = Self {
+            prs_scanned: results.prs.len(),
+            errors: results
+                .errors
+                .iter()
+                .map(|message| RunError {
+                    url: None,
+                    message: message.clone(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        for pr in &results.prs {
+            let url = pr.url.clone();
+            match &pr.outcome {
+                Outcome::NoTrigger | Outcome::Skipped => (),
+                Outcome::Blocked(blockers) => {
+                    summary.triggered += 1;
+                    summary.blocked.push(BlockedPr {
+                        url,
+                        reasons: blockers.iter().map(ToString::to_string).collect(),
+                    });
+                }
+                Outcome::Merged => {
+                    summary.triggered += 1;
+                    summary.merged.push(url);
+                }
+                Outcome::MergeFailed {
+                    error, rebuilds, ..
+                } => {
+                    summary.triggered += 1;
+                    summary
+                        .rebuilds
+                        .extend(rebuilds.iter().map(|rebuild| Rebuild {
+                            url: url.clone(),
+                            build: rebuild.name.clone(),
+                            build_url: rebuild.url.clone(),
+                            state: rebuild.state,
+                            next_rebuild: rebuild.next_rebuild,
+                        }));
+                    summary.blocked.push(BlockedPr {
+                        url,
+                        reasons: vec![error.clone()],
+                    });
+                }
+                Outcome::Error(message) => summary.errors.push(RunError {
+                    url: Some(url),
+                    message: message.clone(),
+                }),
+                Outcome::Panicked(message) => summary.errors.push(RunError {
+                    url: Some(url),
+                    message: format!("panicked: {}", message),
+                }),
+            }
+        }
+        summary
+    }
+
+    /// Returns the exit status for a run with this summary
+    pub fn exit_status(&self) -> ExitStatus {
+        if self.errors.is_empty() {
+            ExitStatus::Success
+        } else {
+            ExitStatus::PartialFailure
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge_checks::Blocker;
+    use crate::search::{self, MergeFailure, PrResult};
+
+    fn result(url: &str, outcome: Outcome) -> PrResult {
+        PrResult {
+            url: String::from(url),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn summarize() {
+        let results = RunResults {
+            counts: Vec::new(),
+            prs: vec![
+                result("a", Outcome::NoTrigger),
+                result("b", Outcome::Merged),
+                result("c", Outcome::Blocked(vec![Blocker::Draft])),
+                result(
+                    "d",
+                    Outcome::MergeFailed {
+                        error: String::from("build failed"),
+                        reason: MergeFailure::Vetoed,
+                        rebuilds: vec![search::Rebuild {
+                            name: String::from("unit tests"),
+                            url: Some(String::from("https://jenkins/job/unit/2/")),
+                            state: RebuildState::Queued,
+                            next_rebuild: None,
+                        }],
+                        exhausted_builds: Vec::new(),
+                    },
+                ),
+                result("e", Outcome::Skipped),
+            ],
+            errors: Vec::new(),
+        };
+        let summary = RunSummary::new(&results);
+        assert_eq!(
+            summary,
+            RunSummary {
+                prs_scanned: 5,
+                triggered: 3,
+                merged: vec![String::from("b")],
+                blocked: vec![
+                    BlockedPr {
+                        url: String::from("c"),
+                        reasons: vec![String::from("pull request is a draft")],
+                    },
+                    BlockedPr {
+                        url: String::from("d"),
+                        reasons: vec![String::from("build failed")],
+                    },
+                ],
+                rebuilds: vec![Rebuild {
+                    url: String::from("d"),
+                    build: String::from("unit tests"),
+                    build_url: Some(String::from("https://jenkins/job/unit/2/")),
+                    state: RebuildState::Queued,
+                    next_rebuild: None,
+                }],
+                errors: Vec::new(),
+            }
+        );
+        assert_eq!(summary.exit_status(), ExitStatus::Success);
+    }
+
+    #[test]
+    fn waiting_rebuilds() {
+        let rebuild = Rebuild {
+            url: String::from("a"),
+            build: String::from("unit tests"),
+            build_url: None,
+            state: RebuildState::Waiting,
+            next_rebuild: Some(OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap()),
+        };
+        assert_eq!(
+            serde_json::to_value(&rebuild).unwrap(),
+            serde_json::json!({
+                "url": "a",
+                "build": "unit tests",
+                "build_url": null,
+                "state": "waiting",
+                "next_rebuild": "2023-11-14T22:13:20Z",
+            })
+        );
+    }
+
+    #[test]
+    fn errors_are_partial_failures() {
+        let results = RunResults {
+            counts: Vec::new(),
+            prs: vec![
+                result("a", Outcome::Merged),
+                result("b", Outcome::Panicked(String::from("oh no"))),
+            ],
+            errors: vec![String::from("Own PR search failed")],
+        };
+        let summary = RunSummary::new(&results);
+        assert_eq!(
+            summary.errors,
+            vec![
+                RunError {
+                    url: None,
+                    message: String::from("Own PR search failed"),
+                },
+                RunError {
+                    url: Some(String::from("b")),
+                    message: String::from("panicked: oh no"),
+                },
+            ]
+        );
+        assert_eq!(summary.exit_status(), ExitStatus::PartialFailure);
+    }
+}
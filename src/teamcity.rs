@@ -0,0 +1,227 @@
+#![cfg(feature = "teamcity")]
+//! Re-running failed TeamCity builds
+
+use crate::ci::{self, CiBackend, QueuedBuild, RebuildState, HTTP_CLIENT};
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::header::ACCEPT;
+use reqwest::{RequestBuilder, StatusCode};
+use serde::Deserialize;
+
+/// TeamCity server and credentials
+#[derive(Debug, Clone, Deserialize)]
+pub struct TeamcityConfig {
+    /// URL of the TeamCity server, e.g. `https://teamcity.example.com`
+    pub url: String,
+    /// Access token of a user that can run builds
+    pub token: String,
+}
+
+/// A build, as returned by the API
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Build {
+    state: String,
+    web_url: String,
+}
+
+/// TeamCity as a CI server to rebuild builds on
+#[derive(Debug)]
+pub struct Teamcity {
+    url: String,
+    token: String,
+}
+
+impl Teamcity {
+    pub fn new(config: TeamcityConfig) -> Self {
+        Self {
+            url: config.url.trim_end_matches('/').to_owned(),
+            token: config.token,
+        }
+    }
+
+    /// Returns the id of the build a URL points at. Queued builds keep their id when they start.
+    fn parse_url(&self, build_url: &str) -> Option<u64> {
+        static PATH_REGEX: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(
+                r"^/(?:buildConfiguration/[^/]+/(\d+)(?:[/?#].*)?|view(?:Log|Queued)\.html\?(?:.*&)?(?:buildId|itemId)=(\d+)(?:[&#].*)?)$",
+            )
+            .unwrap()
+        });
+
+        let path = build_url.strip_prefix(&self.url)?;
+        let captures = PATH_REGEX.captures(path)?;
+        captures.get(1).or(captures.get(2))?.as_str().parse().ok()
+    }
+
+    fn build_api_url(&self, id: u64) -> String {
+        format!("{}/app/rest/builds/id:{}", self.url, id)
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        request
+            .bearer_auth(&self.token)
+            .header(ACCEPT, "application/json")
+    }
+}
+
+impl CiBackend for Teamcity {
+    fn name(&self) -> &'static str {
+        "TeamCity"
+    }
+
+    fn recognizes(&self, build_url: &str) -> bool {
+        self.parse_url(build_url).is_some()
+    }
+
+    fn rebuild_state<'a>(
+        &'a self,
+        rebuild: &'a mut QueuedBuild,
+    ) -> BoxFuture<'a, Result<Option<RebuildState>>> {
+        Box::pin(async move {
+            let Some(id) = rebuild.url.as_deref().and_then(|url| self.parse_url(url)) else {
+                return Ok(None);
+            };
+            let url = format!("{}?fields=state,webUrl", self.build_api_url(id));
+            let response = self.authorize(HTTP_CLIENT.get(url)).send().await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let build: Build = ci::json_response(response, "Fetching build").await?;
+            rebuild.url = Some(build.web_url);
+            Ok(match build.state.as_str() {
+                "queued" => Some(RebuildState::Queued),
+                "running" => Some(RebuildState::Running),
+                _ => None,
+            })
+        })
+    }
+
+    fn rebuild<'a>(&'a self, build_url: &'a str) -> BoxFuture<'a, Result<QueuedBuild>> {
+        Box::pin(async move {
+            let id = self
+                .parse_url(build_url)
+                .ok_or_else(|| anyhow!("Invalid URL: {}", build_url))?;
+            // Queue a build of the same configuration and branch with the same parameters, pinned
+            // to the same changes
+            let url = format!(
+                "{}?fields=buildType(id),branchName,properties(property(name,value)),lastChanges(change(id))",
+                self.build_api_url(id)
+            );
+            let response = self.authorize(HTTP_CLIENT.get(url)).send().await?;
+            let mut original: serde_json::Map<String, serde_json::Value> =
+                ci::json_response(response, "Fetching build").await?;
+            original.retain(|_, value| !value.is_null());
+
+            let url = format!("{}/app/rest/buildQueue", self.url);
+            let request = self.authorize(HTTP_CLIENT.post(url)).json(&original);
+            let queued: Build = ci::json_response(request.send().await?, "Queueing build").await?;
+            Ok(QueuedBuild {
+                queue_url: None,
+                url: Some(queued.web_url),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Response};
+    use serde_json::json;
+
+    fn backend(url: &str) -> Teamcity {
+        Teamcity::new(TeamcityConfig {
+            url: String::from(url),
+            token: String::from("secret"),
+        })
+    }
+
+    #[test]
+    fn parses_urls() {
+        let teamcity = backend("https://tc.example.com/");
+        for (url, id) in [
+            (
+                "https://tc.example.com/buildConfiguration/App_Test/123",
+                123,
+            ),
+            (
+                "https://tc.example.com/buildConfiguration/App_Test/123?buildTab=tests",
+                123,
+            ),
+            (
+                "https://tc.example.com/viewLog.html?buildTypeId=App_Test&buildId=45",
+                45,
+            ),
+            ("https://tc.example.com/viewQueued.html?itemId=67", 67),
+        ] {
+            assert_eq!(teamcity.parse_url(url), Some(id), "{}", url);
+        }
+        assert!(!teamcity.recognizes("https://tc.example.com/project/App"));
+        assert!(!teamcity.recognizes("https://other.example.com/buildConfiguration/App/1"));
+    }
+
+    #[tokio::test]
+    async fn queues_builds() {
+        let (base_url, mut requests) = test_server::serve(|request| {
+            let host = request.header("host").unwrap_or_default();
+            if request.header("authorization") != Some("Bearer secret") {
+                return Response::status(401);
+            }
+            match (
+                request.method.as_str(),
+                request.path.split('?').next().unwrap(),
+            ) {
+                ("GET", "/app/rest/builds/id:123") => Response::json(json!({
+                    "buildType": {"id": "App_Test"},
+                    "branchName": "feature",
+                    "properties": {"property": [{"name": "env.FOO", "value": "bar"}]},
+                    "lastChanges": {"change": [{"id": 9}]},
+                    "comment": null,
+                })),
+                ("POST", "/app/rest/buildQueue") => Response::json(json!({
+                    "state": "queued",
+                    "webUrl": format!("http://{}/viewQueued.html?itemId=124", host),
+                })),
+                ("GET", "/app/rest/builds/id:124") => Response::json(json!({
+                    "state": "running",
+                    "webUrl": format!("http://{}/buildConfiguration/App_Test/124", host),
+                })),
+                _ => Response::status(404),
+            }
+        })
+        .await;
+        let teamcity = backend(&base_url);
+        let build_url = format!("{}/buildConfiguration/App_Test/123", base_url);
+        let mut rebuild = teamcity.rebuild(&build_url).await.unwrap();
+        assert_eq!(
+            rebuild.url,
+            Some(format!("{}/viewQueued.html?itemId=124", base_url))
+        );
+
+        requests.recv().await.unwrap();
+        let queued = requests.recv().await.unwrap();
+        let body: serde_json::Value = serde_json::from_str(&queued.body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "buildType": {"id": "App_Test"},
+                "branchName": "feature",
+                "properties": {"property": [{"name": "env.FOO", "value": "bar"}]},
+                "lastChanges": {"change": [{"id": 9}]},
+            })
+        );
+
+        assert_eq!(
+            teamcity.rebuild_state(&mut rebuild).await.unwrap(),
+            Some(RebuildState::Running)
+        );
+        assert_eq!(
+            rebuild.url,
+            Some(format!("{}/buildConfiguration/App_Test/124", base_url))
+        );
+    }
+}
@@ -0,0 +1,36 @@
+//! Pull requests for tests, in the form the Bitbucket API returns them
+
+use crate::bitbucket::PullRequest;
+use serde_json::json;
+
+/// Returns pull request `id` by alice from `refs/heads/fix` into `main` of `PROJ/app`, with the
+/// top-level fields in `overrides` replaced
+pub fn pr(id: u32, overrides: serde_json::Value) -> PullRequest {
+    let mut pr = json!({
+        "id": id,
+        "title": format!("Change {}", id),
+        "description": null,
+        "version": 0,
+        "author": {"user": {"name": "alice"}},
+        "fromRef": {"id": "refs/heads/fix", "latestCommit": "abc123"},
+        "toRef": to_ref("app", "refs/heads/main"),
+        "links": {"self": [{"href": format!("https://bitbucket/pr/{}", id)}]},
+        "reviewers": [],
+        "participants": [],
+    });
+    if let serde_json::Value::Object(overrides) = overrides {
+        for (field, value) in overrides {
+            pr[field] = value;
+        }
+    }
+    serde_json::from_value(pr).unwrap()
+}
+
+/// Returns the `toRef` of a pull request into `branch`, a fully-qualified ref, of `PROJ/<repo>`
+pub fn to_ref(repo: &str, branch: &str) -> serde_json::Value {
+    json!({
+        "id": branch,
+        "displayId": branch.trim_start_matches("refs/heads/"),
+        "repository": {"slug": repo, "project": {"key": "PROJ"}},
+    })
+}
@@ -0,0 +1,136 @@
+//! A minimal HTTP server for testing API clients against canned responses
+
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// A request received by the server
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Path including the query string
+    pub path: String,
+    /// Headers, with lowercase names
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A response for the server to send
+#[derive(Debug, Clone)]
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    pub fn status(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    pub fn json(value: serde_json::Value) -> Self {
+        Self::text(&value.to_string()).header("Content-Type", "application/json")
+    }
+
+    pub fn text(body: &str) -> Self {
+        Self {
+            body: body.to_owned(),
+            ..Self::status(200)
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+}
+
+/// Starts a server that answers requests with `handler`. Returns the server's base URL and a
+/// channel of the requests it received.
+pub async fn serve<F>(handler: F) -> (String, mpsc::UnboundedReceiver<Request>)
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let handler = Arc::new(handler);
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let handler = Arc::clone(&handler);
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let mut buffer = Vec::new();
+                let mut chunk = [0; 4096];
+                let request = loop {
+                    let n = socket.read(&mut chunk).await.unwrap();
+                    if n == 0 {
+                        return;
+                    }
+                    buffer.extend_from_slice(&chunk[..n]);
+                    if let Some(request) = parse(&buffer) {
+                        break request;
+                    }
+                };
+                let response = handler(&request);
+                sender.send(request).ok();
+                let mut head = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    response.status,
+                    response.body.len()
+                );
+                for (name, value) in &response.headers {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                head.push_str("\r\n");
+                socket.write_all(head.as_bytes()).await.ok();
+                socket.write_all(response.body.as_bytes()).await.ok();
+                socket.shutdown().await.ok();
+            });
+        }
+    });
+    (base_url, receiver)
+}
+
+/// Parses a complete request, or returns `None` if more data is needed
+fn parse(buffer: &[u8]) -> Option<Request> {
+    let text = String::from_utf8_lossy(buffer);
+    let (head, body) = text.split_once("\r\n\r\n")?;
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_owned();
+    let path = request_line.next()?.to_owned();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+        .collect();
+    let length: usize = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    if body.len() < length {
+        return None;
+    }
+    Some(Request {
+        method,
+        path,
+        headers,
+        body: body.to_owned(),
+    })
+}